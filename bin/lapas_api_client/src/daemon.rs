@@ -1,14 +1,30 @@
-use std::{time::Duration, path::PathBuf, sync::Arc, ops::Deref, os::unix::prelude::PermissionsExt};
+use std::{net::IpAddr, time::Duration, path::{Path, PathBuf}, sync::Arc, ops::Deref, os::unix::prelude::PermissionsExt, collections::{HashMap, VecDeque}};
 
 use anyhow::{anyhow, Result, Context};
-use lapas_api_proto::{LapasProtocol, ProtoSerde, LapasUserShadow, ApiAuth, LapasUserPasswd};
+use lapas_api_proto::{LapasProtocol, ProtoSerde, LapasUserShadow, ApiAuth, LapasUserPasswd, UserId, GuestInfo, GuestBootMode, GuestAction, GuestControlId, AnnouncementUrgency, EventCursor, ChatMessage, ChatMessageId, GameServerId, RootGeneration};
 use sd_notify::NotifyState;
-use tokio::{time, process::Command, fs, net::{UnixListener, TcpStream, UnixStream}, sync::Mutex};
+use tokio::{time, process::Command, fs, net::{UnixListener, UnixStream}, sync::{Mutex, broadcast, mpsc, oneshot}, io::WriteHalf, task::JoinSet};
 
-use crate::{CliArgs, lapas_connect, args_to_auth};
+use crate::{CliArgs, ApiConnection, lapas_connect, args_to_auth};
 
-const LAPAS_AUTH_RUNDIR: &'static str = "/run/lapas";
-const LAPAS_AUTH_SOCKET_NAME: &'static str = "auth_serv.socket";
+const LAPAS_AUTH_RUNDIR: &str = "/run/lapas";
+const LAPAS_AUTH_SOCKET_NAME: &str = "auth_serv.socket";
+/// Marker file created during boot when the guest was booted in user mode
+const LAPAS_USER_MODE_MARKER: &str = "/.lapasUser";
+/// File within the rundir holding the generation of the guest root that was last remounted.
+/// The rundir does not survive a reboot, which mounts the current generation anyway.
+const LAPAS_ROOT_GENERATION_FILE: &str = "root_generation";
+/// Time to wait before reconnecting after the api server announced that it shuts down
+const SERVER_SHUTDOWN_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Time a local client waits for the response to a request forwarded to the lapas api server
+/// (e.g. a login waiting for the user's home lease)
+const FORWARDED_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Amount of chat messages buffered for local chat listeners that are slow to read them
+const LOCAL_CHAT_BUFFER: usize = 64;
+/// Script unmounting the home of a user after their last session (see mountHome.sh)
+const LAPAS_UNMOUNT_HOME_SCRIPT: &str = "/lapas/unmountHome.sh";
+/// Interval in which unmounting homes that were still busy after their user's last session is retried
+const HOME_UNMOUNT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 
 struct UserCache {
     user_cache: Mutex<Option<Vec<LapasUserShadow>>>
@@ -40,7 +56,226 @@ impl UserCache {
 }
 type UserCacheState = Arc<UserCache>;
 
-async fn handle_local_auth_client(auth: ApiAuth, user_cache: UserCacheState, mut stream: UnixStream) -> Result<()> {
+/// Forward a request to the lapas api server and extract the result of the expected response
+macro_rules! forward_request {
+    ($requests:expr, $response_pkt:ident = $req_pkt:expr) => {
+        match $requests.forward($req_pkt).await {
+            Ok(LapasProtocol::$response_pkt { result }) => result,
+            Ok(_) => Err("Received unexpected response".to_string()),
+            Err(e) => Err(e),
+        }
+    };
+}
+
+/// Request of a local client to forward to the lapas api server, with the channel to report its response on
+struct ForwardedRequest {
+    packet: LapasProtocol,
+    response_tx: oneshot::Sender<LapasProtocol>,
+}
+
+/// Request that was sent to the lapas api server, and is waiting for its response.
+/// Leases of users that were already logged in when the connection was (re-)established have no
+/// one waiting for their result.
+struct PendingResponse {
+    request: LapasProtocol,
+    response_tx: Option<oneshot::Sender<LapasProtocol>>,
+}
+impl PendingResponse {
+    /// Whether the given packet is the response to this request
+    fn is_answered_by(&self, response: &LapasProtocol) -> bool {
+        matches!((&self.request, response),
+            (LapasProtocol::SessionBegin { .. }, LapasProtocol::SessionBeginResponse { .. })
+            | (LapasProtocol::SessionEnd { .. }, LapasProtocol::SessionEndResponse { .. })
+            | (LapasProtocol::ChatSend { .. }, LapasProtocol::ChatSendResponse { .. })
+            | (LapasProtocol::ChatHistory { .. }, LapasProtocol::ChatHistoryResponse { .. })
+            | (LapasProtocol::GameServerRegister { .. }, LapasProtocol::GameServerRegisterResponse { .. })
+            | (LapasProtocol::GameServerHeartbeat { .. }, LapasProtocol::GameServerHeartbeatResponse { .. })
+            | (LapasProtocol::GameServerUnregister { .. }, LapasProtocol::GameServerUnregisterResponse { .. })
+            | (LapasProtocol::GameServerList { .. }, LapasProtocol::GameServerListResponse { .. }))
+    }
+}
+
+/// Forwards requests of local clients through the daemon's connection to the lapas api server
+#[derive(Clone)]
+struct ServerRequests {
+    requests: mpsc::UnboundedSender<ForwardedRequest>,
+}
+impl ServerRequests {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<ForwardedRequest>) {
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        (Self { requests }, requests_rx)
+    }
+
+    pub async fn forward(&self, packet: LapasProtocol) -> Result<LapasProtocol, String> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.requests.send(ForwardedRequest { packet, response_tx })
+            .map_err(|_| "Lapas daemon is not connected".to_string())?;
+        match time::timeout(FORWARDED_REQUEST_TIMEOUT, response_rx).await {
+            Ok(Ok(response)) => Ok(response),
+            _ => Err("Lapas api server is not reachable".to_string()),
+        }
+    }
+}
+
+/// Keeps track of the users logged in on this guest.
+/// The server grants the exclusive home lease for the first session of a user. After the user's
+/// last session, the home is unmounted before the lease is released. Homes that are still busy
+/// keep their lease (the user stays tracked without sessions), and are unmounted later on.
+struct SessionTracker {
+    /// Number of open sessions per user holding a home lease
+    sessions: Mutex<HashMap<String, usize>>,
+    requests: ServerRequests,
+}
+impl SessionTracker {
+    pub fn new(requests: ServerRequests) -> Self {
+        Self { sessions: Mutex::new(HashMap::new()), requests }
+    }
+
+    pub async fn begin(&self, auth: &ApiAuth, username: String) -> Result<(), String> {
+        // session changes are serialized, so a lease is never released while its user logs in again
+        let mut sessions = self.sessions.lock().await;
+        let response = self.requests.forward(LapasProtocol::SessionBegin { auth: auth.clone(), username: username.clone(), resume: false }).await;
+        match response {
+            Ok(LapasProtocol::SessionBeginResponse { result }) => result?,
+            Ok(_) => return Err("Received unexpected response".to_string()),
+            Err(e) => {
+                // the server might have granted the lease without us hearing back. The session is not
+                // counted, so release it again - unless the user's home is still in use on this guest.
+                if !sessions.contains_key(&username) {
+                    let (auth, requests) = (auth.clone(), self.requests.clone());
+                    tokio::spawn(async move {
+                        let _ = requests.forward(LapasProtocol::SessionEnd { auth, username }).await;
+                    });
+                }
+                return Err(e);
+            }
+        }
+        *sessions.entry(username).or_default() += 1;
+        Ok(())
+    }
+
+    pub async fn end(&self, auth: &ApiAuth, username: String) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get_mut(&username) {
+            Some(session_cnt) if *session_cnt > 0 => *session_cnt -= 1,
+            _ => return Err(format!("User {} has no open session", username)),
+        }
+        if sessions[&username] > 0 {
+            return Ok(());
+        }
+        Self::release_home(&mut sessions, &self.requests, auth, username).await
+    }
+
+    /// Unmount the home of a user without sessions, and release its lease afterwards.
+    /// The lease is kept if unmounting failed or the server could not be reached, to try again later.
+    async fn release_home(sessions: &mut HashMap<String, usize>, requests: &ServerRequests, auth: &ApiAuth, username: String) -> Result<(), String> {
+        if let Err(e) = run_command(LAPAS_UNMOUNT_HOME_SCRIPT, &[&username]).await {
+            println!("Keeping the home lease of {} until its home is unmounted: {}", username, e);
+            return Ok(());
+        }
+        match requests.forward(LapasProtocol::SessionEnd { auth: auth.clone(), username: username.clone() }).await? {
+            LapasProtocol::SessionEndResponse { result } => {
+                sessions.remove(&username);
+                result
+            },
+            _ => Err("Received unexpected response".to_string()),
+        }
+    }
+
+    /// Retry releasing the leases of users without sessions, whose home was still busy
+    pub async fn release_unused_homes(&self, auth: &ApiAuth) {
+        let mut sessions = self.sessions.lock().await;
+        let unused: Vec<_> = sessions.iter()
+            .filter(|(_, session_cnt)| **session_cnt == 0)
+            .map(|(username, _)| username.clone())
+            .collect();
+        for username in unused {
+            if let Err(e) = Self::release_home(&mut sessions, &self.requests, auth, username.clone()).await {
+                eprintln!("Releasing the home lease of {} failed: {}", username, e);
+            }
+        }
+    }
+
+    /// Users holding a home lease on this guest
+    pub async fn users(&self) -> Vec<String> {
+        self.sessions.lock().await.keys().cloned().collect()
+    }
+}
+type SessionTrackerState = Arc<SessionTracker>;
+
+/// Relays chat messages between the lapas api server and the chat apps of the users on this guest
+struct ChatRelay {
+    requests: ServerRequests,
+    /// Messages received from the lapas api server
+    messages: broadcast::Sender<ChatMessage>,
+}
+impl ChatRelay {
+    pub fn new(requests: ServerRequests) -> Self {
+        Self { requests, messages: broadcast::Sender::new(LOCAL_CHAT_BUFFER) }
+    }
+
+    pub async fn send(&self, auth: &ApiAuth, from: String, to: Option<String>, text: String) -> Result<ChatMessageId, String> {
+        forward_request!(self.requests, ChatSendResponse = LapasProtocol::ChatSend { auth: auth.clone(), from, to, text })
+    }
+
+    pub async fn history(&self, auth: &ApiAuth, username: String) -> Result<Vec<ChatMessage>, String> {
+        forward_request!(self.requests, ChatHistoryResponse = LapasProtocol::ChatHistory { auth: auth.clone(), username })
+    }
+
+    /// Stream all chat messages the given user may see to a local client, until it disconnects
+    async fn stream_to(&self, username: &str, stream: &mut UnixStream) -> Result<()> {
+        let mut messages = self.messages.subscribe();
+        loop {
+            match messages.recv().await {
+                Ok(message) if message.is_visible_to(username) => {
+                    LapasProtocol::NotifyChatMessage { message }.encode(stream).await?;
+                },
+                Ok(_) => {},
+                // the client has to re-read the history to catch up
+                Err(broadcast::error::RecvError::Lagged(_)) => LapasProtocol::NotifyEventsLost.encode(stream).await?,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+type ChatRelayState = Arc<ChatRelay>;
+
+/// Game servers registered through a local client connection.
+/// They are unregistered once the connection is closed, e.g. because the hosting process exited.
+struct HostedGameServers {
+    auth: ApiAuth,
+    requests: ServerRequests,
+    ids: Vec<GameServerId>,
+}
+impl Drop for HostedGameServers {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            let auth = self.auth.clone();
+            let requests = self.requests.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forward_request!(requests, GameServerUnregisterResponse = LapasProtocol::GameServerUnregister { auth, id }) {
+                    eprintln!("AuthServ: Unregistering game server {} failed: {}", id, e);
+                }
+            });
+        }
+    }
+}
+
+/// Name of the lapas user with the given uid
+async fn local_username(user_cache: &UserCache, uid: u32) -> Option<String> {
+    user_cache.get().await.into_iter()
+        .find(|user| user.id == UserId::from(uid))
+        .map(|user| user.name)
+}
+
+async fn handle_local_auth_client(
+    auth: ApiAuth, user_cache: UserCacheState, session_tracker: SessionTrackerState,
+    chat_relay: ChatRelayState, server_requests: ServerRequests, mut stream: UnixStream
+) -> Result<()> {
+    // sessions may only be managed by root (pam), everyone else only gets to read user lists and chat
+    let uid = stream.peer_cred()?.uid();
+    let is_root = uid == 0;
+
     if let LapasProtocol::ControlHandshake { version } = LapasProtocol::decode(&mut stream).await? {
         if version != lapas_api_proto::VERSION {
             LapasProtocol::ControlHandshakeResponse { result: Err("AuthServ: Incompatible Protocol Version".to_string()) }
@@ -52,32 +287,108 @@ async fn handle_local_auth_client(auth: ApiAuth, user_cache: UserCacheState, mut
     }
     LapasProtocol::ControlHandshakeResponse { result: Ok(()) }.encode(&mut stream).await?;
 
-    let pkt = LapasProtocol::decode(&mut stream).await?;
-    match pkt {
-        LapasProtocol::PasswdGetList => {
-            println!("AuthServ: Got Passwd request");
-            let user_list = user_cache.get().await
-                .into_iter()
-                .map(|u| LapasUserPasswd { id: u.id, name: u.name })
-                .collect();
-            LapasProtocol::PasswdGetListResponse { result: Ok(user_list) }.encode(&mut stream).await?;
-        },
-        LapasProtocol::ShadowGetList { auth: is_auth } => {
-            println!("AuthServ: Got Shadow request");
-            match (is_auth, auth) {
-                (ApiAuth::Password(auth_is), ApiAuth::Password(auth_should)) if auth_is == auth_should => {
-                    let user_list = user_cache.get().await;
-                    LapasProtocol::ShadowGetListResponse { result: Ok(user_list) }.encode(&mut stream).await?;
-                },
-                _ => eprintln!("AuthServ: API Authentication failed!")
-            }
-        },
-        _ => {}
+    let mut hosted_game_servers = HostedGameServers { auth: auth.clone(), requests: server_requests.clone(), ids: vec![] };
+    // clients may send multiple requests, until they close the connection
+    while let Ok(pkt) = LapasProtocol::decode(&mut stream).await {
+        match pkt {
+            LapasProtocol::PasswdGetList => {
+                println!("AuthServ: Got Passwd request");
+                let user_list = user_cache.get().await
+                    .into_iter()
+                    .map(|u| LapasUserPasswd { id: u.id, name: u.name })
+                    .collect();
+                LapasProtocol::PasswdGetListResponse { result: Ok(user_list) }.encode(&mut stream).await?;
+            },
+            LapasProtocol::ShadowGetList { auth: is_auth } => {
+                println!("AuthServ: Got Shadow request");
+                match (is_auth, &auth) {
+                    (ApiAuth::Password(auth_is), ApiAuth::Password(auth_should)) if auth_is == *auth_should => {
+                        let user_list = user_cache.get().await;
+                        LapasProtocol::ShadowGetListResponse { result: Ok(user_list) }.encode(&mut stream).await?;
+                    },
+                    _ => eprintln!("AuthServ: API Authentication failed!")
+                }
+            },
+            LapasProtocol::SessionBegin { username, .. } => {
+                println!("AuthServ: Got session begin request for: {}", username);
+                let result = match is_root {
+                    true => session_tracker.begin(&auth, username).await,
+                    false => Err("Only root may start sessions".to_string()),
+                };
+                LapasProtocol::SessionBeginResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::SessionEnd { username, .. } => {
+                println!("AuthServ: Got session end request for: {}", username);
+                let result = match is_root {
+                    true => session_tracker.end(&auth, username).await,
+                    false => Err("Only root may end sessions".to_string()),
+                };
+                LapasProtocol::SessionEndResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::ChatSend { to, text, .. } => {
+                // messages are always sent in the name of the user running the chat app
+                let result = match local_username(&user_cache, uid).await {
+                    Some(from) => chat_relay.send(&auth, from, to, text).await,
+                    None => Err("Only lapas users can chat".to_string()),
+                };
+                LapasProtocol::ChatSendResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::ChatHistory { .. } => {
+                let result = match local_username(&user_cache, uid).await {
+                    Some(username) => chat_relay.history(&auth, username).await,
+                    None => Err("Only lapas users can chat".to_string()),
+                };
+                LapasProtocol::ChatHistoryResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::GameServerRegister { info, .. } => {
+                println!("AuthServ: Got game server register request for: {} on port {}", info.game, info.port);
+                // game servers hosted by players are announced in their name
+                let owner = local_username(&user_cache, uid).await;
+                let result = forward_request!(server_requests,
+                    GameServerRegisterResponse = LapasProtocol::GameServerRegister { auth: auth.clone(), owner, info });
+                if let Ok(id) = result {
+                    hosted_game_servers.ids.push(id);
+                }
+                LapasProtocol::GameServerRegisterResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::GameServerHeartbeat { id, player_count, .. } => {
+                let result = match hosted_game_servers.ids.contains(&id) {
+                    true => forward_request!(server_requests,
+                        GameServerHeartbeatResponse = LapasProtocol::GameServerHeartbeat { auth: auth.clone(), id, player_count }),
+                    false => Err(format!("Game server {} was not registered through this connection", id)),
+                };
+                LapasProtocol::GameServerHeartbeatResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::GameServerUnregister { id, .. } => {
+                let result = match hosted_game_servers.ids.contains(&id) {
+                    true => forward_request!(server_requests,
+                        GameServerUnregisterResponse = LapasProtocol::GameServerUnregister { auth: auth.clone(), id }),
+                    false => Err(format!("Game server {} was not registered through this connection", id)),
+                };
+                hosted_game_servers.ids.retain(|hosted_id| *hosted_id != id);
+                LapasProtocol::GameServerUnregisterResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::GameServerList { .. } => {
+                let result = forward_request!(server_requests,
+                    GameServerListResponse = LapasProtocol::GameServerList { auth: auth.clone() });
+                LapasProtocol::GameServerListResponse { result }.encode(&mut stream).await?;
+            },
+            LapasProtocol::ControlListenEvents { .. } => {
+                // the connection is turned into a stream of chat messages
+                let username = local_username(&user_cache, uid).await
+                    .ok_or_else(|| anyhow!("AuthServ: Only lapas users can listen for chat messages"))?;
+                return chat_relay.stream_to(&username, &mut stream).await;
+            },
+            _ => {}
+        }
     }
     Ok(())
 }
 
-async fn run_local_auth_server(auth: ApiAuth, user_cache: UserCacheState) -> Result<()> {
+async fn run_local_auth_server(
+    auth: ApiAuth, user_cache: UserCacheState, session_tracker: SessionTrackerState,
+    chat_relay: ChatRelayState, server_requests: ServerRequests
+) -> Result<()> {
     fs::create_dir_all(LAPAS_AUTH_RUNDIR).await?;
     let mut auth_socket_path = PathBuf::from(LAPAS_AUTH_RUNDIR);
     auth_socket_path.push(LAPAS_AUTH_SOCKET_NAME);
@@ -97,7 +408,9 @@ async fn run_local_auth_server(auth: ApiAuth, user_cache: UserCacheState) -> Res
     loop {
         match listener.accept().await {
             Ok((stream, _addr)) => {
-                tokio::spawn(handle_local_auth_client(auth.clone(), user_cache.clone(), stream));
+                tokio::spawn(handle_local_auth_client(
+                    auth.clone(), user_cache.clone(), session_tracker.clone(), chat_relay.clone(), server_requests.clone(), stream
+                ));
             },
             Err(e) => {
                 eprintln!("AuthServ: Connection attempt failed:\n{}", e);
@@ -110,13 +423,20 @@ pub(crate) async fn run(args: &CliArgs) -> Result<()> {
     let auth = args_to_auth(args)?;
 
     let auth_cache = UserCacheState::new(UserCache::new());
+    let (server_requests, mut forwarded_requests) = ServerRequests::new();
+    let session_tracker = SessionTrackerState::new(SessionTracker::new(server_requests.clone()));
+    let chat_relay = ChatRelayState::new(ChatRelay::new(server_requests.clone()));
     tokio::spawn({
         let auth = auth.clone();
         let auth_cache = auth_cache.clone();
+        let session_tracker = session_tracker.clone();
+        let chat_relay = chat_relay.clone();
         async move {
         loop {
             println!("AuthServ: Starting...");
-            let result = run_local_auth_server(auth.clone(), auth_cache.clone()).await;
+            let result = run_local_auth_server(
+                auth.clone(), auth_cache.clone(), session_tracker.clone(), chat_relay.clone(), server_requests.clone()
+            ).await;
             if let Err(e) = result {
                 eprintln!("AuthServ: Crashed: {}", e);
             }
@@ -124,35 +444,227 @@ pub(crate) async fn run(args: &CliArgs) -> Result<()> {
         }
     }});
 
+    tokio::spawn({
+        let auth = auth.clone();
+        let session_tracker = session_tracker.clone();
+        async move {
+            let mut retry_interval = time::interval(HOME_UNMOUNT_RETRY_INTERVAL);
+            loop {
+                retry_interval.tick().await;
+                session_tracker.release_unused_homes(&auth).await;
+            }
+        }
+    });
+
+    // position in the server's event stream, to resume from after reconnecting
+    let mut event_cursor = None;
     loop {
         println!("Connecting to lapas api server");
-        if let Err(e) = run_daemon(args, auth.clone(), auth_cache.clone()).await {
-            println!("Lost connection to lapas api server: {}", e);
+        match run_daemon(args, auth.clone(), auth_cache.clone(), session_tracker.clone(), chat_relay.clone(), &mut forwarded_requests, &mut event_cursor).await {
+            Ok(()) => {
+                println!("Lapas api server is shutting down");
+                tokio::time::sleep(SERVER_SHUTDOWN_RECONNECT_DELAY).await;
+            },
+            Err(e) => println!("Lost connection to lapas api server: {}", e),
         }
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
 
-async fn run_daemon(args: &CliArgs, auth: ApiAuth, auth_cache: UserCacheState) -> Result<()> {
-    let mut connection = lapas_connect(&args).await?;
+/// MAC address of the first network interface that is up.
+/// Guests boot from the lapas network, which is usually their only connected interface.
+async fn primary_interface() -> Result<PathBuf> {
+    let mut interfaces = fs::read_dir("/sys/class/net").await?;
+    let mut interface_names = vec![];
+    while let Some(interface) = interfaces.next_entry().await? {
+        interface_names.push(interface.path());
+    }
+    interface_names.sort();
+    for interface in interface_names {
+        if interface.ends_with("lo") {
+            continue;
+        }
+        let operstate = fs::read_to_string(interface.join("operstate")).await.unwrap_or_default();
+        if operstate.trim() == "up" {
+            return Ok(interface);
+        }
+    }
+    Err(anyhow!("No network interface is up"))
+}
+
+/// Global IPv4 and IPv6 addresses of the given network interface
+async fn interface_addresses(interface: &Path) -> Result<Vec<IpAddr>> {
+    let interface_name = interface.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Invalid interface {:?}", interface))?;
+    let output = Command::new("/usr/sbin/ip")
+        .args(["-oneline", "address", "show", "dev", interface_name, "scope", "global"])
+        .output().await?;
+    if !output.status.success() {
+        return Err(anyhow!("Listing addresses of {} failed", interface_name));
+    }
+    // e.g.: 2: eth0    inet6 fd00::2/64 scope global dynamic \       valid_lft ...
+    let addresses = String::from_utf8_lossy(&output.stdout).lines()
+        .filter(|line| !line.contains(" deprecated"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(2);
+            match fields.next()? {
+                "inet" | "inet6" => fields.next()?.split('/').next()?.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect();
+    Ok(addresses)
+}
+
+async fn collect_guest_info() -> GuestInfo {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").await
+        .map(|hostname| hostname.trim().to_owned())
+        .unwrap_or_default();
+    let (mac, addresses) = match primary_interface().await {
+        Ok(interface) => {
+            let mac = fs::read_to_string(interface.join("address")).await.unwrap_or_else(|e| {
+                eprintln!("Failed to determine MAC address: {}", e);
+                String::new()
+            });
+            let addresses = interface_addresses(&interface).await.unwrap_or_else(|e| {
+                eprintln!("Failed to determine addresses: {}", e);
+                vec![]
+            });
+            (mac.trim().to_owned(), addresses)
+        },
+        Err(e) => {
+            eprintln!("Failed to determine network interface: {}", e);
+            (String::new(), vec![])
+        },
+    };
+    let boot_mode = match Path::new(LAPAS_USER_MODE_MARKER).exists() {
+        true => GuestBootMode::User,
+        false => GuestBootMode::Admin,
+    };
+    let root_generation = mounted_root_generation().await;
+    let boot_id = fs::read_to_string("/proc/sys/kernel/random/boot_id").await
+        .map(|boot_id| boot_id.trim().to_owned())
+        .unwrap_or_default();
+    GuestInfo { mac, hostname, daemon_version: env!("CARGO_PKG_VERSION").to_owned(), boot_mode, addresses, root_generation, boot_id }
+}
+
+/// Generation of the guest root this machine remounted last, None if it did not remount since booting
+async fn mounted_root_generation() -> Option<RootGeneration> {
+    let generation_file = Path::new(LAPAS_AUTH_RUNDIR).join(LAPAS_ROOT_GENERATION_FILE);
+    fs::read_to_string(generation_file).await.ok()?.trim().parse().ok()
+}
+
+async fn run_daemon(
+    args: &CliArgs, auth: ApiAuth, auth_cache: UserCacheState,
+    session_tracker: SessionTrackerState, chat_relay: ChatRelayState,
+    forwarded_requests: &mut mpsc::UnboundedReceiver<ForwardedRequest>,
+    event_cursor: &mut Option<EventCursor>
+) -> Result<()> {
+    let (mut connection_rx, mut connection) = tokio::io::split(lapas_connect(args).await?);
+    // decoding is not cancellation safe, so it can not be one of the branches in the select below.
+    // Packets are decoded in a separate task instead, which is aborted with the connection.
+    let (packets_tx, mut packets) = mpsc::unbounded_channel();
+    let mut connection_reader = JoinSet::new();
+    connection_reader.spawn(async move {
+        loop {
+            let pkt = LapasProtocol::decode(&mut connection_rx).await;
+            let failed = pkt.is_err();
+            if packets_tx.send(pkt).is_err() || failed {
+                break;
+            }
+        }
+    });
     println!("Connected to lapas api server");
-    LapasProtocol::ControlListenEvents.encode(&mut connection).await
+    // register as guest first, so events targeted at this guest are delivered to our listener
+    LapasProtocol::GuestRegister { auth: auth.clone(), info: collect_guest_info().await }.encode(&mut connection).await
+        .context("Registering with the guest inventory")?;
+    LapasProtocol::ControlListenEvents { resume_from: *event_cursor }.encode(&mut connection).await
         .context("Registering for server events")?;
     LapasProtocol::ShadowGetList { auth: auth.clone() }.encode(&mut connection).await
         .context("Request shadow list for initial cache warming")?;
 
+    // the server responds to forwarded requests in order
+    let mut pending_responses = VecDeque::new();
+    for username in session_tracker.users().await {
+        let request = LapasProtocol::SessionBegin { auth: auth.clone(), username, resume: true };
+        request.encode(&mut connection).await
+            .context("Restoring home leases of open sessions")?;
+        pending_responses.push_back(PendingResponse { request, response_tx: None });
+    }
+    // results of guest control actions and root remounts, which run in the background
+    let (control_results_tx, mut control_results) = mpsc::unbounded_channel::<LapasProtocol>();
+    // the pings renew our home leases on the server, so they must not be delayed by incoming packets
+    let mut ping_interval = time::interval(Duration::from_millis(1000));
+    ping_interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
     loop {
         tokio::select! {
-            _ = time::sleep(Duration::from_millis(1000)) => {
+            _ = ping_interval.tick() => {
                 LapasProtocol::ControlPing.encode(&mut connection).await?;
             },
-            pkt = LapasProtocol::decode(&mut connection) => {
-                let pkt = pkt?;
+            Some(request) = forwarded_requests.recv() => {
+                // the local client already gave up on this request
+                if request.response_tx.is_closed() {
+                    continue;
+                }
+                request.packet.encode(&mut connection).await?;
+                pending_responses.push_back(PendingResponse { request: request.packet, response_tx: Some(request.response_tx) });
+            },
+            Some(control_result) = control_results.recv() => {
+                control_result.encode(&mut connection).await?;
+            },
+            pkt = packets.recv() => {
+                let pkt = pkt.ok_or_else(|| anyhow!("Connection closed"))??;
                 match pkt {
                     LapasProtocol::ControlPing => {},
-                    LapasProtocol::NotifyRootChanged{} => handle_root_changed().await,
-                    LapasProtocol::NotifyUsersChanged {} => handle_users_changed(&mut connection, &auth).await?,
-                    LapasProtocol::NotifyDnsMappingsChanged{} => handle_dns_mappings_changed().await,
+                    LapasProtocol::ControlEventSeq { cursor } => *event_cursor = Some(cursor),
+                    LapasProtocol::NotifyEventsLost => handle_events_lost(&mut connection, &auth).await?,
+                    LapasProtocol::NotifyRootChanged { generation } => {
+                        tokio::spawn(handle_root_changed(generation, control_results_tx.clone()));
+                    },
+                    LapasProtocol::NotifyUsersChanged => handle_users_changed(&mut connection, &auth).await?,
+                    LapasProtocol::NotifyDnsMappingsChanged => handle_dns_mappings_changed().await,
+                    LapasProtocol::NotifyServerShutdown => return Ok(()),
+                    LapasProtocol::NotifyMaintenance { maintenance } => match maintenance {
+                        Some(maintenance) => println!("[Event] Maintenance mode on, refusing new player logins: {}", maintenance.message),
+                        None => println!("[Event] Maintenance mode off"),
+                    },
+                    LapasProtocol::NotifyAnnouncement { title, body, urgency } => {
+                        println!("[Event] Announcement: {}", title);
+                        tokio::spawn(async move { show_announcement(&title, &body, urgency).await });
+                    },
+                    // the server only delivers control requests to the targeted guests. The ticket
+                    // holds the guest id of the connection it was issued on, which changes on reconnects.
+                    LapasProtocol::NotifyGuestControl { ticket, action, countdown_secs } => {
+                        tokio::spawn(handle_guest_control(ticket.id, action, countdown_secs,
+                            session_tracker.clone(), control_results_tx.clone()));
+                    },
+                    LapasProtocol::GuestRegisterResponse { result } => {
+                        match result {
+                            Ok(id) => println!("Registered with guest inventory [id: {}]", id),
+                            Err(e) => eprintln!("Registering with guest inventory failed: {}", e),
+                        }
+                    },
+                    LapasProtocol::NotifyChatMessage { message } => {
+                        // nobody might be listening, which is fine
+                        let _ = chat_relay.messages.send(message);
+                    },
+                    response @ (LapasProtocol::SessionBeginResponse { .. } | LapasProtocol::SessionEndResponse { .. }
+                        | LapasProtocol::ChatSendResponse { .. } | LapasProtocol::ChatHistoryResponse { .. }
+                        | LapasProtocol::GameServerRegisterResponse { .. } | LapasProtocol::GameServerHeartbeatResponse { .. }
+                        | LapasProtocol::GameServerUnregisterResponse { .. } | LapasProtocol::GameServerListResponse { .. }) => {
+                        // handing a response to the wrong request could e.g. report a refused login
+                        // as successful, so the connection can not be trusted anymore
+                        let pending = pending_responses.pop_front()
+                            .filter(|pending| pending.is_answered_by(&response))
+                            .ok_or_else(|| anyhow!("Received response that does not match the pending request"))?;
+                        match pending.response_tx {
+                            Some(response_tx) => { let _ = response_tx.send(response); },
+                            None => if let LapasProtocol::SessionBeginResponse { result: Err(e) } = response {
+                                eprintln!("Restoring home lease failed: {}", e);
+                            },
+                        }
+                    },
                     LapasProtocol::ShadowGetListResponse { result } => {
                         match result {
                             Ok(user_list) => auth_cache.set(user_list).await, // update cache
@@ -168,26 +680,28 @@ async fn run_daemon(args: &CliArgs, auth: ApiAuth, auth_cache: UserCacheState) -
     }
 }
 
-async fn handle_root_changed() {
-    println!("[Event] Root filesystem changed");
+async fn handle_root_changed(generation: RootGeneration, control_results_tx: mpsc::UnboundedSender<LapasProtocol>) {
+    println!("[Event] Root filesystem changed [generation: {}]", generation);
+    // replayed events of a generation that was already mounted
+    if mounted_root_generation().await.is_some_and(|mounted| mounted >= generation) {
+        let _ = control_results_tx.send(LapasProtocol::GuestRootRemountResult { generation, result: Ok(()) });
+        return;
+    }
     tokio::time::sleep(Duration::from_secs(2)).await;
     println!("Remounting root filesystem...");
-    let child = Command::new("/usr/bin/mount")
-        .args(&["-o", "remount", "/"])
-        .spawn();
-    match child {
-        Ok(mut child) => {
-            match child.wait().await {
-                Ok(result) if result.success() => {
-                    println!("Successfully remounted root filesystem");
-                },
-                _ => println!("Failed to remount root filesystem")
-            }
-        },
-        Err(e) => {
-            println!("Failed to remount root filesystem: {}", e);
-        },
+    let result = remount_root(generation).await.map_err(|e| e.to_string());
+    match &result {
+        Ok(()) => println!("Successfully remounted root filesystem"),
+        Err(e) => println!("Failed to remount root filesystem: {}", e),
     }
+    let _ = control_results_tx.send(LapasProtocol::GuestRootRemountResult { generation, result });
+}
+
+async fn remount_root(generation: RootGeneration) -> Result<()> {
+    run_command("/usr/bin/mount", &["-o", "remount", "/"]).await?;
+    let generation_file = Path::new(LAPAS_AUTH_RUNDIR).join(LAPAS_ROOT_GENERATION_FILE);
+    fs::write(generation_file, generation.to_string()).await
+        .context("Remembering the mounted root generation")
 }
 
 async fn handle_dns_mappings_changed() {
@@ -211,9 +725,122 @@ async fn handle_dns_mappings_changed() {
     }
 }
 
-async fn handle_users_changed(connection: &mut TcpStream, auth: &ApiAuth) -> Result<()> {
+async fn handle_events_lost(connection: &mut WriteHalf<ApiConnection>, auth: &ApiAuth) -> Result<()> {
+    println!("[Event] Missed events from lapas api server, refreshing everything");
+    handle_users_changed(connection, auth).await?;
+    handle_dns_mappings_changed().await;
+    Ok(())
+}
+
+async fn handle_users_changed(connection: &mut WriteHalf<ApiConnection>, auth: &ApiAuth) -> Result<()> {
     println!("[Event] Registered users changed");
     // request user listing to refresh local cache
     LapasProtocol::ShadowGetList { auth: auth.clone() }.encode(connection).await?;
     Ok(())
 }
+
+async fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program).args(args).status().await
+        .with_context(|| format!("Running {}", program))?;
+    if !status.success() {
+        return Err(anyhow!("{} {} failed: {}", program, args.join(" "), status));
+    }
+    Ok(())
+}
+
+/// Names of all users with an active graphical (X11 / Wayland) session on this machine
+async fn graphical_session_users() -> Result<Vec<String>> {
+    let sessions = Command::new("/usr/bin/loginctl").args(["list-sessions", "--no-legend"]).output().await?;
+    let mut users = vec![];
+    for session_id in String::from_utf8_lossy(&sessions.stdout).lines().filter_map(|l| l.split_whitespace().next()) {
+        let properties = Command::new("/usr/bin/loginctl")
+            .args(["show-session", session_id, "--property=Type", "--property=Name"])
+            .output().await?;
+        let properties = String::from_utf8_lossy(&properties.stdout);
+        let (mut session_type, mut username) = (None, None);
+        for property in properties.lines() {
+            match property.split_once('=') {
+                Some(("Type", value)) => session_type = Some(value),
+                Some(("Name", value)) => username = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        if let (Some("x11" | "wayland"), Some(username)) = (session_type, username) {
+            if !users.contains(&username) {
+                users.push(username);
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// Show a notification in the given user's graphical session, using the freedesktop notification interface
+async fn show_desktop_notification(username: &str, title: &str, body: &str, urgency: AnnouncementUrgency) -> Result<()> {
+    let urgency = match urgency {
+        AnnouncementUrgency::Low => "0",
+        AnnouncementUrgency::Normal => "1",
+        AnnouncementUrgency::Critical => "2",
+    };
+    // Notify(app_name, replaces_id, app_icon, summary, body, actions, hints, expire_timeout)
+    run_command("/usr/bin/busctl", &[
+        "--user", &format!("--machine={}@.host", username), "call",
+        "org.freedesktop.Notifications", "/org/freedesktop/Notifications", "org.freedesktop.Notifications",
+        "Notify", "susssasa{sv}i", "LAPAS", "0", "", title, body, "0", "1", "urgency", "y", urgency, "-1"
+    ]).await
+}
+
+/// Show an announcement to all users with a graphical session, and log it
+async fn show_announcement(title: &str, body: &str, urgency: AnnouncementUrgency) {
+    println!("Announcement [{:?}]: {}\n{}", urgency, title, body);
+    let users = graphical_session_users().await.unwrap_or_else(|e| {
+        println!("Failed to list graphical sessions: {}", e);
+        vec![]
+    });
+    if users.is_empty() {
+        println!("No graphical session active, announcement was only logged");
+    }
+    for username in users {
+        if let Err(e) = show_desktop_notification(&username, title, body, urgency).await {
+            println!("Failed to show announcement to {}: {}", username, e);
+        }
+    }
+}
+
+async fn run_guest_action(action: GuestAction, session_tracker: &SessionTracker) -> Result<()> {
+    match action {
+        GuestAction::Logout => {
+            for username in session_tracker.users().await {
+                run_command("/usr/bin/loginctl", &["terminate-user", &username]).await?;
+            }
+            Ok(())
+        },
+        GuestAction::Reboot => run_command("/usr/bin/systemctl", &["reboot"]).await,
+        GuestAction::PowerOff => run_command("/usr/bin/systemctl", &["poweroff"]).await,
+    }
+}
+
+async fn handle_guest_control(
+    control_id: GuestControlId, action: GuestAction, countdown_secs: u32,
+    session_tracker: SessionTrackerState, control_results_tx: mpsc::UnboundedSender<LapasProtocol>
+) {
+    println!("[Event] Guest control: {:?} in {}s", action, countdown_secs);
+    if countdown_secs > 0 {
+        let announcement = match action {
+            GuestAction::Logout => "All users will be logged out",
+            GuestAction::Reboot => "This machine will reboot",
+            GuestAction::PowerOff => "This machine will power off",
+        };
+        let message = format!("LAPAS: {} in {} seconds. Please save your progress!", announcement, countdown_secs);
+        if let Err(e) = run_command("/usr/bin/wall", &[&message]).await {
+            println!("Failed to warn users: {}", e);
+        }
+        show_announcement("LAPAS", &message, AnnouncementUrgency::Critical).await;
+        time::sleep(Duration::from_secs(countdown_secs.into())).await;
+    }
+    let result = run_guest_action(action, &session_tracker).await.map_err(|e| e.to_string());
+    match &result {
+        Ok(()) => println!("Guest control: {:?} succeeded", action),
+        Err(e) => println!("Guest control: {:?} failed: {}", action, e),
+    }
+    let _ = control_results_tx.send(LapasProtocol::GuestControlResult { control_id, result });
+}
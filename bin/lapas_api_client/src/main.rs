@@ -1,9 +1,15 @@
 mod daemon;
 
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
 use anyhow::{anyhow, Result, Context};
-use tokio::{net::TcpStream, io::AsyncWriteExt};
-use lapas_api_proto::{LapasProtocol, ProtoSerde, ApiAuth};
-use clap::{Parser, Subcommand};
+use tokio::{net::{TcpStream, UnixStream}, io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, time};
+use lapas_api_proto::{LapasProtocol, ProtoSerde, ApiAuth, GuestBootMode, GuestAction, GuestControlTicket, GuestId, GuestTarget, AnnouncementUrgency, ScheduledEventId, ChatMessage, GameServerInfo, DnsRecord, DnsRecordTarget, KnownGuest, BootProfile, LapasGuest, MaintenanceMode, RootRollout, RootRolloutState, UserHomeUsage};
+use clap::{Args, Parser, Subcommand};
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, Utc};
+
+/// Interval in which hosted game servers are kept alive with the lapas api server
+const GAME_SERVER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 
 macro_rules! perform_request {
     ($connection:expr, $response_pkt:ident = $req_pkt:expr) => {
@@ -37,10 +43,106 @@ struct CliArgs {
     #[arg(long = "port", default_value_t = 1337)]
     api_port: u16,
 
+    /// Connect to the local unix socket of the LAPAS api server instead of --host / --port.
+    /// When running as root on the server, no administration password is required.
+    #[arg(long = "socket", value_name = "PATH")]
+    api_socket: Option<PathBuf>,
+
     #[command(subcommand)]
     command: ClientCommand
 }
 
+/// Guests to run a guest control action on
+#[derive(Debug, Args)]
+struct GuestControlArgs {
+    /// Ids of the guests to control (as shown by guests)
+    guests: Vec<GuestId>,
+    /// Control all guests with a connected daemon
+    #[arg(long, conflicts_with = "guests")]
+    all: bool,
+    /// Control the guest the given user is logged in on
+    #[arg(long, conflicts_with_all = ["guests", "all"])]
+    user: Option<String>,
+    /// Seconds users are given to save their progress before the action runs
+    #[arg(long, default_value_t = 60)]
+    countdown: u32,
+}
+
+#[derive(Debug, Subcommand)]
+enum ScheduleCommand {
+    /// Add a timed event, which is announced on all guests ahead of time
+    Add {
+        title: String,
+        /// Local time of the event, either HH:MM (next occurrence) or "YYYY-MM-DD HH:MM"
+        time: String,
+        /// Text shown in the announcements of the event
+        #[arg(long, default_value = "")]
+        description: String,
+        /// Minutes before the event at which it is announced
+        #[arg(long, value_delimiter = ',', default_value = "15,5,0")]
+        remind: Vec<u32>
+    },
+    /// Display a list of all upcoming events
+    List,
+    /// Remove an event from the schedule
+    Remove {
+        id: ScheduledEventId
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum DnsRecordCommand {
+    /// Add a custom dns record, or replace the record with the same name.
+    /// Names without a dot are placed within the lapas network's domain.
+    Add {
+        name: String,
+        /// Address the record resolves to
+        #[arg(long, required_unless_present = "user", conflicts_with = "user")]
+        ip: Option<IpAddr>,
+        /// Resolve to the machine the given user is logged in on (alias of the user's dns mapping)
+        #[arg(long)]
+        user: Option<String>
+    },
+    /// Display a list of all custom dns records
+    List,
+    /// Remove a custom dns record
+    Remove {
+        name: String
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum BootProfileCommand {
+    /// Select what the given machine boots into from the network, starting with its next boot
+    Set {
+        /// Hostname or MAC address of the machine (as shown by known-guests)
+        host: String,
+        /// default: show the boot menu, user / admin: boot the newest kernel in that mode right away,
+        /// maintenance: boot the newest kernel in admin mode into the rescue target
+        #[arg(value_parser = ["default", "user", "admin", "maintenance"])]
+        profile: String
+    },
+    /// Display all machines with a boot profile other than the default boot menu
+    List
+}
+
+#[derive(Debug, Subcommand)]
+enum MaintenanceCommand {
+    /// Turn the maintenance mode on, refusing new player logins
+    On {
+        /// Message shown to players whose login is refused
+        #[arg(long, default_value = "")]
+        message: String,
+        /// Warn players that are already logged in, and log them out after the given amount of seconds
+        #[arg(long, value_name = "SECS")]
+        logout_after: Option<u32>
+    },
+    /// Turn the maintenance mode off, allowing player logins again
+    Off,
+    /// Display whether the maintenance mode is on
+    Status
+}
+
 #[derive(Debug, Subcommand)]
 enum ClientCommand {
     /// Start a lapas api client daemon.
@@ -54,25 +156,162 @@ enum ClientCommand {
     AddDnsMapping {
         username: String
     },
+    /// Display the dns mappings of all logged in users
+    DnsMappings,
+    /// Manage custom dns records, in addition to the automatic user mappings
+    DnsRecords {
+        #[command(subcommand)]
+        command: DnsRecordCommand
+    },
     /// Add a new player user with the given credentials (username & password).
     AddUser {
         username: String,
         password: String
     },
     /// Display a list of all registered players
-    ListUsers
+    #[command(visible_alias = "users")]
+    ListUsers {
+        /// Also display the size and disk usage of the players' home images
+        #[arg(long)]
+        usage: bool
+    },
+    /// Set the home quota of a player, resizing the player's home image to it (e.g. 2G or 512M).
+    /// The player must not be logged in while the home image is resized.
+    HomeQuota {
+        username: String,
+        #[arg(value_parser = parse_size, required_unless_present = "clear")]
+        size: Option<u64>,
+        /// Forget the player's quota, leaving the home image as it is
+        #[arg(long, conflicts_with = "size")]
+        clear: bool
+    },
+    /// Display a list of all guests with a connected daemon
+    Guests,
+    /// Display a list of all machines seen in the lapas network, including powered off ones
+    KnownGuests {
+        /// Keep running and report machines that are booting from the lapas network
+        #[arg(long)]
+        watch: bool
+    },
+    /// Manage what single machines boot into from the network
+    BootProfile {
+        #[command(subcommand)]
+        command: BootProfileCommand
+    },
+    /// Manage the maintenance mode, during which players can not log in (e.g. while updating the guest root)
+    Maintenance {
+        #[command(subcommand)]
+        command: MaintenanceCommand
+    },
+    /// Tell the guests that the guest root image was changed, so they remount it.
+    /// The remount is rolled out in waves, see root-status for its progress.
+    RootChanged {
+        /// Keep running and report the progress of the rollout until it finished or was paused
+        #[arg(long)]
+        watch: bool
+    },
+    /// Display the current generation of the guest root, the guests that did not remount it yet
+    /// and the progress of the rollout
+    RootStatus,
+    /// Continue a rollout that was paused because too many guests failed to remount, retrying them
+    RootResume,
+    /// Power up known guests using Wake-on-LAN
+    Wake {
+        /// Hostnames or MAC addresses of the guests to wake (as shown by known-guests)
+        #[arg(required_unless_present = "all")]
+        hosts: Vec<String>,
+        /// Wake all known guests
+        #[arg(long, conflicts_with = "hosts")]
+        all: bool
+    },
+    /// Show an announcement on the desktops of all guests, or only on the given ones
+    Announce {
+        title: String,
+        body: String,
+        /// Urgency of the announcement
+        #[arg(long, default_value = "normal", value_parser = ["low", "normal", "critical"])]
+        urgency: String,
+        /// Only show the announcement on the guest with the given id (can be repeated)
+        #[arg(long = "guest", value_name = "GUEST_ID")]
+        guests: Vec<GuestId>,
+        /// Only show the announcement on the guest the given user is logged in on
+        #[arg(long, conflicts_with = "guests")]
+        user: Option<String>
+    },
+    /// Manage the schedule of timed events
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand
+    },
+    /// Log out all users on the given guests
+    Logout {
+        #[command(flatten)]
+        control: GuestControlArgs
+    },
+    /// Reboot the given guests
+    Reboot {
+        #[command(flatten)]
+        control: GuestControlArgs
+    },
+    /// Power off the given guests
+    Poweroff {
+        #[command(flatten)]
+        control: GuestControlArgs
+    },
+    /// Chat with the other players. Lines are sent to the global room, "/msg USER TEXT" sends
+    /// a direct message. Players connect through the local lapas daemon (--socket), which sends
+    /// the messages in the name of the logged in user.
+    Chat {
+        /// Name to chat as, when connected to the api server directly
+        #[arg(long, default_value = "admin")]
+        name: String
+    },
+    /// Display a list of all game servers hosted on the guests
+    GameServers,
+    /// Announce a game server hosted on this machine through the local lapas daemon (--socket).
+    /// The game server stays listed until this command is terminated.
+    HostGame {
+        /// Name of the game
+        game: String,
+        /// Port the game server is listening on
+        port: u16,
+        /// Number of players currently on the game server
+        #[arg(long, default_value_t = 0)]
+        players: u32,
+        /// Joining the game server requires a password
+        #[arg(long)]
+        password_protected: bool
+    },
+    /// Tell the local lapas daemon (--socket) that the given user logs in on this machine.
+    /// This acquires the user's exclusive home lease, and fails while the user is logged in elsewhere.
+    SessionBegin {
+        username: String
+    },
+    /// Tell the local lapas daemon (--socket) that the given user logged out of this machine.
+    SessionEnd {
+        username: String
+    }
 }
 
 fn args_to_auth(args: &CliArgs) -> Result<ApiAuth> {
     // use one of the supplied authentication mechanisms
-    match &args.api_password {
-        Some(api_password) => Ok(ApiAuth::Password(api_password.clone())),
+    match (&args.api_password, &args.api_socket) {
+        (Some(api_password), _) => Ok(ApiAuth::Password(api_password.clone())),
+        (None, Some(_)) => Ok(ApiAuth::PeerCredentials),
         _ => Err(anyhow!("This action requires authentication"))
     }
 }
 
-async fn lapas_connect(args: &CliArgs) -> Result<TcpStream> {
-    let mut stream = TcpStream::connect(format!("{}:{}", args.api_host, args.api_port)).await?;
+pub(crate) trait ApiStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ApiStream for T {}
+/// Connection to the LAPAS api server, either via tcp or via its local unix socket
+pub(crate) type ApiConnection = Box<dyn ApiStream>;
+
+async fn lapas_connect(args: &CliArgs) -> Result<ApiConnection> {
+    let mut stream: ApiConnection = match &args.api_socket {
+        Some(api_socket) => Box::new(UnixStream::connect(api_socket).await?),
+        None => Box::new(TcpStream::connect((args.api_host.as_str(), args.api_port)).await?),
+    };
     LapasProtocol::ControlHandshake { version: lapas_api_proto::VERSION }.encode(&mut stream).await?;
 
     let response = LapasProtocol::decode(&mut stream).await?;
@@ -88,7 +327,7 @@ async fn lapas_connect(args: &CliArgs) -> Result<TcpStream> {
 }
 
 
-async fn cmd_check_auth(args: &CliArgs, connection: &mut TcpStream) -> Result<()> {
+async fn cmd_check_auth(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
     let result = perform_request!(connection, ControlCheckAuthResponse = LapasProtocol::ControlCheckAuth { auth: args_to_auth(args)? });
     result
         .map_err(|e| anyhow!(e))
@@ -97,7 +336,7 @@ async fn cmd_check_auth(args: &CliArgs, connection: &mut TcpStream) -> Result<()
     Ok(())
 }
 
-async fn cmd_add_dns_mapping(args: &CliArgs, connection: &mut TcpStream, username: &str) -> Result<()> {
+async fn cmd_add_dns_mapping(args: &CliArgs, connection: &mut ApiConnection, username: &str) -> Result<()> {
     let auth = args_to_auth(args)?;
     let result = perform_request!(connection,
         UserDnsMappingResponse = LapasProtocol::UserDnsMapping { auth, username: username.to_owned() });
@@ -108,7 +347,7 @@ async fn cmd_add_dns_mapping(args: &CliArgs, connection: &mut TcpStream, usernam
     Ok(())
 }
 
-async fn cmd_add_user(args: &CliArgs, connection: &mut TcpStream, username: &str, password: &str) -> Result<()> {
+async fn cmd_add_user(args: &CliArgs, connection: &mut ApiConnection, username: &str, password: &str) -> Result<()> {
     let auth = args_to_auth(args)?;
     let (new_username, new_password) = (username.to_owned(), password.to_owned());
     let result = perform_request!(connection,
@@ -120,7 +359,53 @@ async fn cmd_add_user(args: &CliArgs, connection: &mut TcpStream, username: &str
     Ok(())
 }
 
-async fn cmd_list_users(connection: &mut TcpStream) -> Result<()> {
+/// Parse a size in bytes, optionally with a binary unit suffix (K, M, G or T)
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, factor) = match size.char_indices().last() {
+        Some((idx, unit)) if unit.is_ascii_alphabetic() => {
+            let exponent = match unit.to_ascii_uppercase() {
+                'K' => 1, 'M' => 2, 'G' => 3, 'T' => 4,
+                _ => return Err(anyhow!("Unknown size unit: {} (expected K, M, G or T)", unit)),
+            };
+            (&size[..idx], 1024u64.pow(exponent))
+        },
+        _ => (size, 1),
+    };
+    let number: u64 = number.parse().with_context(|| format!("Invalid size: {}", size))?;
+    number.checked_mul(factor).ok_or_else(|| anyhow!("Size {} is too large", size))
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", size),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+fn print_home_usage(usage: &UserHomeUsage) {
+    let image_size = usage.image_size.map(format_size).unwrap_or_else(|| "no image".to_owned());
+    let quota = usage.quota.map(format_size).unwrap_or_else(|| "none".to_owned());
+    println!("{}: {} used of {} [quota: {}]", usage.username, format_size(usage.used), image_size, quota);
+}
+
+async fn cmd_list_users(args: &CliArgs, connection: &mut ApiConnection, usage: bool) -> Result<()> {
+    if usage {
+        let auth = args_to_auth(args)?;
+        let usages = perform_request!(connection, UserHomeUsageListResponse = LapasProtocol::UserHomeUsageList { auth })
+            .map_err(|e| anyhow!(e))
+            .context("Acquiring home usage of registered users")?;
+        for usage in &usages {
+            print_home_usage(usage);
+        }
+        return Ok(());
+    }
     let mut users = perform_request!(connection, PasswdGetListResponse = LapasProtocol::PasswdGetList)
         .map_err(|e| anyhow!(e))
         .context("Acquiring list of registered users")?;
@@ -131,11 +416,574 @@ async fn cmd_list_users(connection: &mut TcpStream) -> Result<()> {
     Ok(())
 }
 
+async fn cmd_home_quota(args: &CliArgs, connection: &mut ApiConnection, username: &str, quota: Option<u64>) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let usage = perform_request!(connection, UserHomeQuotaSetResponse = LapasProtocol::UserHomeQuotaSet {
+        auth, username: username.to_owned(), quota
+    })
+        .map_err(|e| anyhow!(e))
+        .context("Setting home quota")?;
+    print_home_usage(&usage);
+    Ok(())
+}
+
+async fn cmd_guests(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let guests = perform_request!(connection, GuestListResponse = LapasProtocol::GuestList { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring list of guests")?;
+    let now = chrono::Utc::now();
+    for guest in guests {
+        let boot_mode = match guest.info.boot_mode {
+            GuestBootMode::Admin => "admin",
+            GuestBootMode::User => "user",
+        };
+        println!("{}: {} [ip: {}, mac: {}, mode: {}, root: {}, daemon: v{}, connected since: {}, last ping: {}s ago]",
+            guest.id, guest.info.hostname, join_addresses(&guest.addresses()), guest.info.mac, boot_mode,
+            root_generation_name(&guest), guest.info.daemon_version,
+            guest.connect_ts.with_timezone(&chrono::Local).format("%H:%M:%S"),
+            (now - guest.last_ping_ts).num_seconds());
+    }
+    Ok(())
+}
+
+fn root_generation_name(guest: &LapasGuest) -> String {
+    match guest.info.root_generation {
+        Some(generation) => format!("generation {}", generation),
+        None => "unknown".to_owned(),
+    }
+}
+
+fn print_maintenance(maintenance: &Option<MaintenanceMode>) {
+    match maintenance {
+        Some(maintenance) => println!("Maintenance mode is on since {} [message: {}]",
+            maintenance.since_ts.with_timezone(&Local).format("%Y-%m-%d %H:%M"), maintenance.message),
+        None => println!("Maintenance mode is off"),
+    }
+}
+
+async fn cmd_maintenance(args: &CliArgs, connection: &mut ApiConnection, command: &MaintenanceCommand) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let maintenance = match command {
+        MaintenanceCommand::On { message, logout_after } => {
+            perform_request!(connection, MaintenanceSetResponse = LapasProtocol::MaintenanceSet {
+                auth, message: Some(message.clone()), logout_countdown_secs: *logout_after
+            })
+                .map_err(|e| anyhow!(e))
+                .context("Turning maintenance mode on")?
+        },
+        MaintenanceCommand::Off => {
+            perform_request!(connection, MaintenanceSetResponse = LapasProtocol::MaintenanceSet {
+                auth, message: None, logout_countdown_secs: None
+            })
+                .map_err(|e| anyhow!(e))
+                .context("Turning maintenance mode off")?
+        },
+        MaintenanceCommand::Status => {
+            perform_request!(connection, MaintenanceGetResponse = LapasProtocol::MaintenanceGet { auth })
+                .map_err(|e| anyhow!(e))
+                .context("Acquiring maintenance mode")?
+        },
+    };
+    print_maintenance(&maintenance);
+    Ok(())
+}
+
+fn print_root_rollout(rollout: &RootRollout) {
+    let state = match rollout.state {
+        RootRolloutState::Running => "running",
+        RootRolloutState::Paused => "paused (resume with root-resume)",
+        RootRolloutState::Finished => "finished",
+    };
+    println!("Rollout of generation {}: {} [remounted: {}, failed: {}, waiting for: {:?}]",
+        rollout.generation, state, rollout.remounted.len(), rollout.failures.len(), rollout.pending);
+    for failure in &rollout.failures {
+        println!("  {}: {} failed: {}", failure.guest_id, failure.hostname, failure.error);
+    }
+}
+
+async fn cmd_root_changed(args: &CliArgs, connection: &mut ApiConnection, watch: bool) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    if !watch {
+        let generation = perform_request!(connection, RootChangedResponse = LapasProtocol::RootChanged { auth })
+            .map_err(|e| anyhow!(e))
+            .context("Announcing guest root change")?;
+        println!("Guest root is now at generation {}, guests are remounting", generation);
+        return Ok(());
+    }
+    // progress is reported through events, which might even arrive before the response
+    LapasProtocol::ControlListenEvents { resume_from: None }.encode(connection).await?;
+    LapasProtocol::RootChanged { auth }.encode(connection).await?;
+    let mut generation = None;
+    let mut early_progress: Vec<RootRollout> = vec![];
+    loop {
+        let rollout = match LapasProtocol::decode(connection).await? {
+            LapasProtocol::RootChangedResponse { result } => {
+                let new_generation = result
+                    .map_err(|e| anyhow!(e))
+                    .context("Announcing guest root change")?;
+                println!("Guest root is now at generation {}, guests are remounting", new_generation);
+                generation = Some(new_generation);
+                match early_progress.iter().rev().find(|rollout| rollout.generation == new_generation) {
+                    Some(rollout) => rollout.clone(),
+                    None => continue,
+                }
+            },
+            LapasProtocol::NotifyRootRollout { rollout } if generation.is_none() => {
+                early_progress.push(rollout);
+                continue;
+            },
+            LapasProtocol::NotifyRootRollout { rollout } if generation == Some(rollout.generation) => rollout,
+            LapasProtocol::NotifyServerShutdown => return Err(anyhow!("Lapas api server is shutting down")),
+            _ => continue,
+        };
+        print!("[{}] ", Local::now().format("%H:%M:%S"));
+        print_root_rollout(&rollout);
+        if rollout.state != RootRolloutState::Running {
+            return Ok(());
+        }
+    }
+}
+
+async fn cmd_root_resume(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let rollout = perform_request!(connection, RootRolloutResumeResponse = LapasProtocol::RootRolloutResume { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Resuming guest root rollout")?;
+    print_root_rollout(&rollout);
+    Ok(())
+}
+
+async fn cmd_root_status(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let status = perform_request!(connection, RootStatusResponse = LapasProtocol::RootStatus { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring guest root status")?;
+    println!("Guest root generation: {}", status.generation);
+    if status.stragglers.is_empty() {
+        println!("All guests mounted the current generation");
+    }
+    for guest in status.stragglers {
+        println!("{}: {} [ip: {}, root: {}]", guest.id, guest.info.hostname, join_addresses(&guest.addresses()), root_generation_name(&guest));
+    }
+    if let Some(rollout) = &status.rollout {
+        print_root_rollout(rollout);
+    }
+    Ok(())
+}
+
+fn join_addresses(addresses: &[IpAddr]) -> String {
+    addresses.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Hostname of a known guest, machines only seen through a dhcp lease might not have sent one
+fn known_guest_name(guest: &KnownGuest) -> &str {
+    match guest.hostname.is_empty() {
+        true => "<unnamed>",
+        false => &guest.hostname,
+    }
+}
+
+async fn cmd_known_guests(args: &CliArgs, connection: &mut ApiConnection, watch: bool) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let guests = perform_request!(connection, KnownGuestListResponse = LapasProtocol::KnownGuestList { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring list of known guests")?;
+    for guest in guests {
+        let ip = guest.ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_owned());
+        let daemon = guest.guest_id.map(|id| format!("guest {}", id)).unwrap_or_else(|| "not connected".to_owned());
+        println!("{} {} [ip: {}, daemon: {}, dhcp lease: {}, last seen: {}]", guest.mac, known_guest_name(&guest), ip,
+            daemon, if guest.has_lease { "yes" } else { "no" },
+            guest.last_seen_ts.with_timezone(&Local).format("%Y-%m-%d %H:%M"));
+    }
+    if !watch {
+        return Ok(());
+    }
+    LapasProtocol::ControlListenEvents { resume_from: None }.encode(connection).await?;
+    loop {
+        match LapasProtocol::decode(connection).await? {
+            LapasProtocol::NotifyGuestBooting { guest } => {
+                let ip = guest.ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_owned());
+                println!("[{}] {} {} is booting [ip: {}]", Local::now().format("%H:%M:%S"), guest.mac, known_guest_name(&guest), ip);
+            },
+            LapasProtocol::NotifyServerShutdown => return Err(anyhow!("Lapas api server is shutting down")),
+            _ => {},
+        }
+    }
+}
+
+async fn cmd_wake(args: &CliArgs, connection: &mut ApiConnection, hosts: &[String], all: bool) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let hosts = match all {
+        true => None,
+        false => Some(hosts.to_vec()),
+    };
+    let guests = perform_request!(connection, GuestWakeResponse = LapasProtocol::GuestWake { auth, hosts })
+        .map_err(|e| anyhow!(e))
+        .context("Waking guests")?;
+    for guest in guests {
+        println!("Sent magic packet to {} ({})", known_guest_name(&guest), guest.mac);
+    }
+    Ok(())
+}
+
+fn boot_profile_name(profile: BootProfile) -> &'static str {
+    match profile {
+        BootProfile::Default => "default",
+        BootProfile::User => "user",
+        BootProfile::Admin => "admin",
+        BootProfile::Maintenance => "maintenance",
+    }
+}
+
+async fn cmd_boot_profile(args: &CliArgs, connection: &mut ApiConnection, command: &BootProfileCommand) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    match command {
+        BootProfileCommand::Set { host, profile } => {
+            let profile = match profile.as_str() {
+                "user" => BootProfile::User,
+                "admin" => BootProfile::Admin,
+                "maintenance" => BootProfile::Maintenance,
+                _ => BootProfile::Default,
+            };
+            let boot_profile = perform_request!(connection, BootProfileSetResponse = LapasProtocol::BootProfileSet {
+                auth, host: host.clone(), profile
+            })
+                .map_err(|e| anyhow!(e))
+                .context("Setting boot profile")?;
+            println!("{} boots into: {} (on its next boot)", boot_profile.mac, boot_profile_name(boot_profile.profile));
+        },
+        BootProfileCommand::List => {
+            let boot_profiles = perform_request!(connection, BootProfileListResponse = LapasProtocol::BootProfileList { auth })
+                .map_err(|e| anyhow!(e))
+                .context("Acquiring boot profiles")?;
+            for boot_profile in boot_profiles {
+                let hostname = boot_profile.hostname.filter(|hostname| !hostname.is_empty()).unwrap_or_else(|| "<unnamed>".to_owned());
+                println!("{} {}: {}", boot_profile.mac, hostname, boot_profile_name(boot_profile.profile));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Guests selected on the command line, either by id or by the user logged in on them
+fn guest_target(guests: &[GuestId], user: &Option<String>) -> Option<GuestTarget> {
+    match (guests.is_empty(), user) {
+        (_, Some(user)) => Some(GuestTarget::User(user.clone())),
+        (false, None) => Some(GuestTarget::Guests(guests.to_vec())),
+        (true, None) => None,
+    }
+}
+
+async fn cmd_announce(args: &CliArgs, connection: &mut ApiConnection, title: &str, body: &str, urgency: &str, target: GuestTarget) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let urgency = match urgency {
+        "low" => AnnouncementUrgency::Low,
+        "critical" => AnnouncementUrgency::Critical,
+        _ => AnnouncementUrgency::Normal,
+    };
+    let result = perform_request!(connection,
+        AnnounceResponse = LapasProtocol::Announce { auth, target, title: title.to_owned(), body: body.to_owned(), urgency });
+    result
+        .map_err(|e| anyhow!(e))
+        .context("Sending announcement")?;
+    println!("Announcement sent");
+    Ok(())
+}
+
+async fn cmd_dns_mappings(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let mappings = perform_request!(connection, DnsMappingListResponse = LapasProtocol::DnsMappingList { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring dns mappings")?;
+    for mapping in mappings {
+        println!("{} -> {} [user: {}]", mapping.name, join_addresses(&mapping.addresses), mapping.username);
+    }
+    Ok(())
+}
+
+async fn cmd_dns_records(args: &CliArgs, connection: &mut ApiConnection, command: &DnsRecordCommand) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    match command {
+        DnsRecordCommand::Add { name, ip, user } => {
+            let target = match (ip, user) {
+                (Some(ip), _) => DnsRecordTarget::Address(*ip),
+                (None, Some(user)) => DnsRecordTarget::User(user.clone()),
+                (None, None) => return Err(anyhow!("Either specify --ip or --user")),
+            };
+            let record = perform_request!(connection, DnsRecordAddResponse = LapasProtocol::DnsRecordAdd {
+                auth, record: DnsRecord { name: name.clone(), target }
+            })
+                .map_err(|e| anyhow!(e))
+                .context("Adding dns record")?;
+            println!("Dns record {} added", record.name);
+        },
+        DnsRecordCommand::List => {
+            let records = perform_request!(connection, DnsRecordListResponse = LapasProtocol::DnsRecordList { auth })
+                .map_err(|e| anyhow!(e))
+                .context("Acquiring dns records")?;
+            for record in records {
+                match record.target {
+                    DnsRecordTarget::Address(ip) => println!("{} -> {}", record.name, ip),
+                    DnsRecordTarget::User(username) => println!("{} -> user {}", record.name, username),
+                }
+            }
+        },
+        DnsRecordCommand::Remove { name } => {
+            perform_request!(connection, DnsRecordRemoveResponse = LapasProtocol::DnsRecordRemove { auth, name: name.clone() })
+                .map_err(|e| anyhow!(e))
+                .context("Removing dns record")?;
+            println!("Dns record {} removed", name);
+        },
+    }
+    Ok(())
+}
+
+/// Parse a local time given as HH:MM (next occurrence) or as YYYY-MM-DD HH:MM
+fn parse_local_time(time: &str) -> Result<DateTime<Utc>> {
+    let ts = match NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+        Ok(ts) => ts,
+        Err(_) => {
+            let time_of_day = NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("Invalid time: {} (expected HH:MM or YYYY-MM-DD HH:MM)", time))?;
+            let now = Local::now();
+            let mut date = now.date_naive();
+            if time_of_day <= now.time() {
+                date = date.succ_opt().ok_or_else(|| anyhow!("Invalid date"))?;
+            }
+            date.and_time(time_of_day)
+        }
+    };
+    ts.and_local_timezone(Local).single()
+        .map(|ts| ts.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("Time {} is ambiguous or does not exist in the local timezone", time))
+}
+
+async fn cmd_schedule(args: &CliArgs, connection: &mut ApiConnection, command: &ScheduleCommand) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    match command {
+        ScheduleCommand::Add { title, time, description, remind } => {
+            let ts = parse_local_time(time)?;
+            let id = perform_request!(connection, ScheduleAddResponse = LapasProtocol::ScheduleAdd {
+                auth, title: title.clone(), description: description.clone(), ts, reminder_offsets_mins: remind.clone()
+            })
+                .map_err(|e| anyhow!(e))
+                .context("Adding event to schedule")?;
+            println!("Event {} scheduled at {}", id, ts.with_timezone(&Local).format("%Y-%m-%d %H:%M"));
+        },
+        ScheduleCommand::List => {
+            let events = perform_request!(connection, ScheduleListResponse = LapasProtocol::ScheduleList { auth })
+                .map_err(|e| anyhow!(e))
+                .context("Acquiring schedule")?;
+            for event in events {
+                let reminders: Vec<_> = event.reminder_offsets_mins.iter().map(|offset| offset.to_string()).collect();
+                println!("{}: {} - {} [reminders: {} min before]",
+                    event.id, event.ts.with_timezone(&Local).format("%Y-%m-%d %H:%M"), event.title, reminders.join(","));
+                if !event.description.is_empty() {
+                    println!("    {}", event.description);
+                }
+            }
+        },
+        ScheduleCommand::Remove { id } => {
+            perform_request!(connection, ScheduleRemoveResponse = LapasProtocol::ScheduleRemove { auth, id: *id })
+                .map_err(|e| anyhow!(e))
+                .context("Removing event from schedule")?;
+            println!("Event {} removed from schedule", id);
+        },
+    }
+    Ok(())
+}
+
+async fn cmd_guest_control(args: &CliArgs, connection: &mut ApiConnection, action: GuestAction, control: &GuestControlArgs) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let target = guest_target(&control.guests, &control.user)
+        .or(control.all.then_some(GuestTarget::All))
+        .ok_or_else(|| anyhow!("Either specify the guests to control, --user or --all"))?;
+    // results are reported through events, which might even arrive before the response
+    LapasProtocol::ControlListenEvents { resume_from: None }.encode(connection).await?;
+    LapasProtocol::GuestControl { auth, target, action, countdown_secs: control.countdown }.encode(connection).await?;
+
+    let deadline = time::Instant::now() + Duration::from_secs(control.countdown as u64 + 30);
+    let mut ticket: Option<GuestControlTicket> = None;
+    let mut results = vec![];
+    loop {
+        if let Some(ticket) = &ticket {
+            let all_reported = ticket.guests.iter()
+                .all(|guest_id| results.iter().any(|(control_id, id, _)| *control_id == ticket.id && id == guest_id));
+            if all_reported {
+                break;
+            }
+        }
+        let pkt = match time::timeout_at(deadline, LapasProtocol::decode(connection)).await {
+            Ok(pkt) => pkt?,
+            Err(_) => break,
+        };
+        match pkt {
+            LapasProtocol::GuestControlResponse { result } => {
+                let new_ticket = result
+                    .map_err(|e| anyhow!(e))
+                    .context("Requesting guest control")?;
+                println!("Requested {:?} on {} guest(s), waiting for results...", action, new_ticket.guests.len());
+                ticket = Some(new_ticket);
+            },
+            LapasProtocol::NotifyGuestControlResult { control_id, guest_id, result } => {
+                results.push((control_id, guest_id, result));
+            },
+            _ => {}
+        }
+    }
+
+    let ticket = ticket.ok_or_else(|| anyhow!("Api server did not respond to guest control request"))?;
+    let mut all_succeeded = true;
+    for guest_id in &ticket.guests {
+        let result = results.iter()
+            .find(|(control_id, id, _)| *control_id == ticket.id && id == guest_id)
+            .map(|(_, _, result)| result);
+        match result {
+            Some(Ok(())) => println!("{}: ok", guest_id),
+            Some(Err(e)) => println!("{}: failed: {}", guest_id, e),
+            None => println!("{}: no response", guest_id),
+        }
+        all_succeeded &= matches!(result, Some(Ok(())));
+    }
+    match all_succeeded {
+        true => Ok(()),
+        false => Err(anyhow!("Guest control did not succeed on all guests")),
+    }
+}
+
+async fn cmd_game_servers(args: &CliArgs, connection: &mut ApiConnection) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let game_servers = perform_request!(connection, GameServerListResponse = LapasProtocol::GameServerList { auth })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring list of game servers")?;
+    for game_server in game_servers {
+        let info = &game_server.info;
+        let password = match info.password_protected {
+            true => ", password protected",
+            false => "",
+        };
+        println!("{}: {} on {} [{}:{}, players: {}{}, hosted by: {}, since: {}]",
+            game_server.id, info.game, game_server.hostname, game_server.ip, info.port, info.player_count, password,
+            game_server.owner.as_deref().unwrap_or("-"),
+            game_server.registered_ts.with_timezone(&Local).format("%H:%M"));
+    }
+    Ok(())
+}
+
+async fn cmd_host_game(args: &CliArgs, connection: &mut ApiConnection, game: &str, port: u16, players: u32, password_protected: bool) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let info = GameServerInfo { game: game.to_owned(), port, player_count: players, password_protected };
+    let mut id = perform_request!(connection, GameServerRegisterResponse = LapasProtocol::GameServerRegister {
+        auth: auth.clone(), owner: None, info: info.clone()
+    })
+        .map_err(|e| anyhow!(e))
+        .context("Registering game server")?;
+    println!("Game server {} registered, keeping it listed until terminated", id);
+    loop {
+        time::sleep(GAME_SERVER_HEARTBEAT_INTERVAL).await;
+        let result = perform_request!(connection, GameServerHeartbeatResponse = LapasProtocol::GameServerHeartbeat {
+            auth: auth.clone(), id, player_count: players
+        });
+        // the registration is lost when the lapas daemon reconnected in the meantime
+        if let Err(e) = result {
+            eprintln!("Game server heartbeat failed, registering again: {}", e);
+            let result = perform_request!(connection, GameServerRegisterResponse = LapasProtocol::GameServerRegister {
+                auth: auth.clone(), owner: None, info: info.clone()
+            });
+            match result {
+                Ok(new_id) => id = new_id,
+                Err(e) => eprintln!("Registering game server failed: {}", e),
+            }
+        }
+    }
+}
+
+fn print_chat_message(message: &ChatMessage) {
+    let ts = message.ts.with_timezone(&Local).format("%H:%M");
+    match &message.to {
+        None => println!("[{}] {}: {}", ts, message.from, message.text),
+        Some(to) => println!("[{}] {} -> {}: {}", ts, message.from, to, message.text),
+    }
+}
+
+async fn cmd_chat(args: &CliArgs, connection: &mut ApiConnection, name: &str) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    // messages are received on a separate connection, that is turned into an event stream
+    let mut events = lapas_connect(args).await?;
+    LapasProtocol::ControlListenEvents { resume_from: None }.encode(&mut events).await?;
+    let history = perform_request!(connection, ChatHistoryResponse = LapasProtocol::ChatHistory { auth: auth.clone(), username: name.to_owned() })
+        .map_err(|e| anyhow!(e))
+        .context("Acquiring chat history")?;
+    for message in &history {
+        print_chat_message(message);
+    }
+    // the server (or the local daemon) only delivers the messages we may see
+    let receiver = tokio::spawn(async move {
+        loop {
+            match LapasProtocol::decode(&mut events).await {
+                Ok(LapasProtocol::NotifyChatMessage { message }) => print_chat_message(&message),
+                Ok(LapasProtocol::NotifyEventsLost) => println!("Some messages were missed, restart the chat to see them"),
+                Ok(_) => {},
+                Err(e) => {
+                    eprintln!("Connection lost: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let (to, text) = match line.strip_prefix("/msg ") {
+            Some(dm) => match dm.trim_start().split_once(' ') {
+                Some((to, text)) => (Some(to.to_owned()), text.to_owned()),
+                None => {
+                    eprintln!("Usage: /msg USER TEXT");
+                    continue;
+                }
+            },
+            None => (None, line),
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        let result = perform_request!(connection, ChatSendResponse = LapasProtocol::ChatSend { auth: auth.clone(), from: name.to_owned(), to, text });
+        if let Err(e) = result {
+            eprintln!("Sending message failed: {}", e);
+        }
+    }
+    receiver.abort();
+    Ok(())
+}
+
+async fn cmd_session_begin(args: &CliArgs, connection: &mut ApiConnection, username: &str) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let result = perform_request!(connection,
+        SessionBeginResponse = LapasProtocol::SessionBegin { auth, username: username.to_owned(), resume: false });
+    result
+        .map_err(|e| anyhow!(e))
+        .context("Starting session")?;
+    println!("Session for user: {} started", username);
+    Ok(())
+}
+
+async fn cmd_session_end(args: &CliArgs, connection: &mut ApiConnection, username: &str) -> Result<()> {
+    let auth = args_to_auth(args)?;
+    let result = perform_request!(connection,
+        SessionEndResponse = LapasProtocol::SessionEnd { auth, username: username.to_owned() });
+    result
+        .map_err(|e| anyhow!(e))
+        .context("Ending session")?;
+    println!("Session for user: {} ended", username);
+    Ok(())
+}
+
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let args = CliArgs::parse();
-    if args.api_password.is_none() {
+    if args.api_password.is_none() && args.api_socket.is_none() {
         return Err(anyhow!("Authentication option required"));
     }
 
@@ -146,8 +994,34 @@ async fn main() -> Result<()> {
         let result = match &args.command {
             ClientCommand::CheckAuth => cmd_check_auth(&args, &mut connection).await,
             ClientCommand::AddDnsMapping { username } => cmd_add_dns_mapping(&args, &mut connection, username).await,
+            ClientCommand::DnsMappings => cmd_dns_mappings(&args, &mut connection).await,
+            ClientCommand::DnsRecords { command } => cmd_dns_records(&args, &mut connection, command).await,
+            ClientCommand::BootProfile { command } => cmd_boot_profile(&args, &mut connection, command).await,
             ClientCommand::AddUser { username, password } => cmd_add_user(&args, &mut connection, username, password).await,
-            ClientCommand::ListUsers => cmd_list_users(&mut connection).await,
+            ClientCommand::ListUsers { usage } => cmd_list_users(&args, &mut connection, *usage).await,
+            ClientCommand::HomeQuota { username, size, clear: _ } => cmd_home_quota(&args, &mut connection, username, *size).await,
+            ClientCommand::Guests => cmd_guests(&args, &mut connection).await,
+            ClientCommand::KnownGuests { watch } => cmd_known_guests(&args, &mut connection, *watch).await,
+            ClientCommand::Maintenance { command } => cmd_maintenance(&args, &mut connection, command).await,
+            ClientCommand::RootChanged { watch } => cmd_root_changed(&args, &mut connection, *watch).await,
+            ClientCommand::RootStatus => cmd_root_status(&args, &mut connection).await,
+            ClientCommand::RootResume => cmd_root_resume(&args, &mut connection).await,
+            ClientCommand::Wake { hosts, all } => cmd_wake(&args, &mut connection, hosts, *all).await,
+            ClientCommand::Announce { title, body, urgency, guests, user } => {
+                let target = guest_target(guests, user).unwrap_or(GuestTarget::All);
+                cmd_announce(&args, &mut connection, title, body, urgency, target).await
+            },
+            ClientCommand::Schedule { command } => cmd_schedule(&args, &mut connection, command).await,
+            ClientCommand::Logout { control } => cmd_guest_control(&args, &mut connection, GuestAction::Logout, control).await,
+            ClientCommand::Reboot { control } => cmd_guest_control(&args, &mut connection, GuestAction::Reboot, control).await,
+            ClientCommand::Poweroff { control } => cmd_guest_control(&args, &mut connection, GuestAction::PowerOff, control).await,
+            ClientCommand::GameServers => cmd_game_servers(&args, &mut connection).await,
+            ClientCommand::HostGame { game, port, players, password_protected } => {
+                cmd_host_game(&args, &mut connection, game, *port, *players, *password_protected).await
+            },
+            ClientCommand::Chat { name } => cmd_chat(&args, &mut connection, name).await,
+            ClientCommand::SessionBegin { username } => cmd_session_begin(&args, &mut connection, username).await,
+            ClientCommand::SessionEnd { username } => cmd_session_end(&args, &mut connection, username).await,
             _ => unreachable!()
         };
         let _ = connection.shutdown().await;
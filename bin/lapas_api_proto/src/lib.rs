@@ -4,8 +4,10 @@ pub mod models;
 pub use proto::*;
 pub use models::*;
 
+use chrono::{DateTime, Utc};
+
 pub type Version = u32;
-pub const VERSION: Version = 6;
+pub const VERSION: Version = 28;
 
 
 define_protocol!(proto LapasProtocol {
@@ -17,8 +19,13 @@ define_protocol!(proto LapasProtocol {
     // For long-running connections, this is used to notice early on when the tcp
     // connection crashed (doesn't have a response, both client and server send this regularly)
     ControlPing,
-    // Register for server notifications
-    ControlListenEvents,
+    // Register for server notifications. When resuming from the cursor of a previous connection,
+    // all events that were missed in the meantime are replayed first.
+    // Guests have to GuestRegister beforehand to also receive the events targeted at them.
+    ControlListenEvents { resume_from: Option<EventCursor> },
+    // Sent after every event, marking the position in the event stream up to which all
+    // events were delivered (doesn't have a response)
+    ControlEventSeq { cursor: EventCursor },
 
     // Ask server to test the supplied authentication
     ControlCheckAuth { auth: ApiAuth },
@@ -36,7 +43,8 @@ define_protocol!(proto LapasProtocol {
     UserRegisterResponse { result: Result<(), String> },
 
     // Tell the server that he should now create a dns mapping for the given user
-    // to the ip address from which this requests is comming
+    // to the ip address from which this requests is comming.
+    // Mappings are created automatically with SessionBegin, and removed when the session ends.
     // - Requires auth
     UserDnsMapping {
         auth: ApiAuth,
@@ -44,6 +52,50 @@ define_protocol!(proto LapasProtocol {
     },
     UserDnsMappingResponse { result: Result<(), String> },
 
+    // List of the dns mappings of all users
+    // - Requires auth
+    DnsMappingList { auth: ApiAuth },
+    DnsMappingListResponse { result: Result<Vec<DnsMapping>, String> },
+
+    // Add a custom dns record, or replace the record with the same name.
+    // Names without a dot are placed within the lapas network's domain.
+    // - Requires auth
+    DnsRecordAdd {
+        auth: ApiAuth,
+        record: DnsRecord
+    },
+    DnsRecordAddResponse { result: Result<DnsRecord, String> },
+
+    // List of all custom dns records
+    // - Requires auth
+    DnsRecordList { auth: ApiAuth },
+    DnsRecordListResponse { result: Result<Vec<DnsRecord>, String> },
+
+    // Remove the custom dns record with the given name
+    // - Requires auth
+    DnsRecordRemove {
+        auth: ApiAuth,
+        name: String
+    },
+    DnsRecordRemoveResponse { result: Result<(), String> },
+
+    // Home image size and disk usage of all users
+    // - Requires auth
+    UserHomeUsageList { auth: ApiAuth },
+    UserHomeUsageListResponse { result: Result<Vec<UserHomeUsage>, String> },
+
+    // Set the home quota of the given user (in bytes) and resize the user's home image to it offline,
+    // growing or shrinking its filesystem. Users without a home image get one of that size.
+    // quota = None forgets the quota, leaving the home image as it is.
+    // Fails while the user is logged in.
+    // - Requires auth
+    UserHomeQuotaSet {
+        auth: ApiAuth,
+        username: String,
+        quota: Option<u64>
+    },
+    UserHomeQuotaSetResponse { result: Result<UserHomeUsage, String> },
+
     // Passwd get listing
     PasswdGetList,
     PasswdGetListResponse { result: Result<Vec<LapasUserPasswd>, String> },
@@ -52,15 +104,280 @@ define_protocol!(proto LapasProtocol {
     ShadowGetList { auth: ApiAuth },
     ShadowGetListResponse { result: Result<Vec<LapasUserShadow>, String> },
 
+    // # Guest Packets
+    // ####################
+    // Register the sending daemon's connection with the guest inventory
+    // - Requires auth
+    GuestRegister {
+        auth: ApiAuth,
+        info: GuestInfo
+    },
+    GuestRegisterResponse { result: Result<GuestId, String> },
+
+    // List of all guests with a connected daemon
+    // - Requires auth
+    GuestList { auth: ApiAuth },
+    GuestListResponse { result: Result<Vec<LapasGuest>, String> },
+
+    // Ask the targeted guests to run the given action after warning their users for countdown_secs.
+    // Guests report back with GuestControlResult, which is forwarded as NotifyGuestControlResult.
+    // - Requires auth
+    GuestControl {
+        auth: ApiAuth,
+        target: GuestTarget,
+        action: GuestAction,
+        countdown_secs: u32
+    },
+    GuestControlResponse { result: Result<GuestControlTicket, String> },
+
+    // Sent by a guest daemon once it ran the action of a NotifyGuestControl (doesn't have a response)
+    // - Requires the connection to be registered as guest (GuestRegister)
+    GuestControlResult {
+        control_id: GuestControlId,
+        result: Result<(), String>
+    },
+
+    // Show an announcement on the desktops of the targeted guests (sent as NotifyAnnouncement)
+    // - Requires auth
+    Announce {
+        auth: ApiAuth,
+        target: GuestTarget,
+        title: String,
+        body: String,
+        urgency: AnnouncementUrgency
+    },
+    AnnounceResponse { result: Result<(), String> },
+
+    // List of all machines the server has seen so far, also those that are powered off
+    // - Requires auth
+    KnownGuestList { auth: ApiAuth },
+    KnownGuestListResponse { result: Result<Vec<KnownGuest>, String> },
+
+    // Power up known guests by sending Wake-on-LAN magic packets into the lapas network.
+    // hosts are hostnames or MAC addresses, None wakes all known guests.
+    // Responds with the guests that magic packets were sent to.
+    // - Requires auth
+    GuestWake {
+        auth: ApiAuth,
+        hosts: Option<Vec<String>>
+    },
+    GuestWakeResponse { result: Result<Vec<KnownGuest>, String> },
+
+    // Select what the machine with the given hostname or MAC address boots into from the network.
+    // Takes effect on the machine's next boot.
+    // - Requires auth
+    BootProfileSet {
+        auth: ApiAuth,
+        host: String,
+        profile: BootProfile
+    },
+    BootProfileSetResponse { result: Result<GuestBootProfile, String> },
+
+    // List of all machines with a boot profile other than the default boot menu
+    // - Requires auth
+    BootProfileList { auth: ApiAuth },
+    BootProfileListResponse { result: Result<Vec<GuestBootProfile>, String> },
+
+    // # Root Packets
+    // ####################
+    // Tell the server that the guest root image was changed. Increases the root generation
+    // and rolls the remount out to the guests in waves (sent as NotifyRootChanged), replacing
+    // the rollout of a previous change. Progress is reported with NotifyRootRollout.
+    // Responds with the new root generation.
+    // - Requires auth
+    RootChanged { auth: ApiAuth },
+    RootChangedResponse { result: Result<RootGeneration, String> },
+
+    // Current root generation, and the connected guests that did not mount it yet
+    // - Requires auth
+    RootStatus { auth: ApiAuth },
+    RootStatusResponse { result: Result<RootStatus, String> },
+
+    // Continue a rollout that was paused because too many guests failed to remount,
+    // retrying the failed guests
+    // - Requires auth
+    RootRolloutResume { auth: ApiAuth },
+    RootRolloutResumeResponse { result: Result<RootRollout, String> },
+
+    // Sent by a guest daemon once it remounted its root after a NotifyRootChanged (doesn't have a response)
+    // - Requires the connection to be registered as guest (GuestRegister)
+    GuestRootRemountResult {
+        generation: RootGeneration,
+        result: Result<(), String>
+    },
+
+    // # Maintenance Packets
+    // ####################
+    // Turn the maintenance mode on (with the message shown to players whose login is refused),
+    // or off (message = None). Sent to everyone as NotifyMaintenance.
+    // Players that are already logged in are warned and logged out after logout_countdown_secs, if given.
+    // - Requires auth
+    MaintenanceSet {
+        auth: ApiAuth,
+        message: Option<String>,
+        logout_countdown_secs: Option<u32>
+    },
+    MaintenanceSetResponse { result: Result<Option<MaintenanceMode>, String> },
+
+    // Current maintenance mode, None if it is off
+    // - Requires auth
+    MaintenanceGet { auth: ApiAuth },
+    MaintenanceGetResponse { result: Result<Option<MaintenanceMode>, String> },
+
+    // # Schedule Packets
+    // ####################
+    // Add a timed event to the schedule, which is announced on the guests automatically
+    // - Requires auth
+    ScheduleAdd {
+        auth: ApiAuth,
+        title: String,
+        description: String,
+        ts: DateTime<Utc>,
+        reminder_offsets_mins: Vec<u32>
+    },
+    ScheduleAddResponse { result: Result<ScheduledEventId, String> },
+
+    // List of all upcoming events on the schedule
+    // - Requires auth
+    ScheduleList { auth: ApiAuth },
+    ScheduleListResponse { result: Result<Vec<ScheduledEvent>, String> },
+
+    // Remove an event from the schedule
+    // - Requires auth
+    ScheduleRemove {
+        auth: ApiAuth,
+        id: ScheduledEventId
+    },
+    ScheduleRemoveResponse { result: Result<(), String> },
+
+    // # Session Packets
+    // ####################
+    // Acquire the exclusive home lease for the given user on the sending guest, and map the
+    // user's dns name to the guest. Fails while the user's home is leased to another guest.
+    // Leases are renewed by the guest daemon's ControlPing and time out when they are not renewed.
+    // The dns mapping is removed with the session, or when the guest disconnects.
+    // New sessions are refused during maintenance, sessions that were already open on the guest
+    // before its daemon reconnected are restored with resume = true.
+    // - Requires auth
+    // - Requires the connection to be registered as guest (GuestRegister)
+    SessionBegin {
+        auth: ApiAuth,
+        username: String,
+        resume: bool
+    },
+    SessionBeginResponse { result: Result<(), String> },
+
+    // Release the home lease of the given user held by the sending guest
+    // - Requires auth
+    // - Requires the connection to be registered as guest (GuestRegister)
+    SessionEnd {
+        auth: ApiAuth,
+        username: String
+    },
+    SessionEndResponse { result: Result<(), String> },
+
+    // # Chat Packets
+    // ####################
+    // Send a message to the global room (to = None), or directly to the given user.
+    // Messages are delivered as NotifyChatMessage to everyone (global room), or to the guests
+    // the sender and receiver are logged in on (direct messages).
+    // - Requires auth
+    ChatSend {
+        auth: ApiAuth,
+        from: String,
+        to: Option<String>,
+        text: String
+    },
+    ChatSendResponse { result: Result<ChatMessageId, String> },
+
+    // Recent messages of the global room and the direct messages of the given user
+    // - Requires auth
+    ChatHistory {
+        auth: ApiAuth,
+        username: String
+    },
+    ChatHistoryResponse { result: Result<Vec<ChatMessage>, String> },
+
+    // # Game Server Packets
+    // ####################
+    // Announce a game server hosted on the sending guest. It has to be kept alive with
+    // GameServerHeartbeat, and is dropped when the guest disconnects.
+    // - Requires auth
+    // - Requires the connection to be registered as guest (GuestRegister)
+    GameServerRegister {
+        auth: ApiAuth,
+        owner: Option<String>,
+        info: GameServerInfo
+    },
+    GameServerRegisterResponse { result: Result<GameServerId, String> },
+
+    // Keep a game server alive, updating its current player count
+    // - Requires auth
+    // - Requires the connection to be registered as guest (GuestRegister)
+    GameServerHeartbeat {
+        auth: ApiAuth,
+        id: GameServerId,
+        player_count: u32
+    },
+    GameServerHeartbeatResponse { result: Result<(), String> },
+
+    // Remove a game server that was shut down
+    // - Requires auth
+    // - Requires the connection to be registered as guest (GuestRegister)
+    GameServerUnregister {
+        auth: ApiAuth,
+        id: GameServerId
+    },
+    GameServerUnregisterResponse { result: Result<(), String> },
+
+    // List of all game servers hosted on the guests
+    // - Requires auth
+    GameServerList { auth: ApiAuth },
+    GameServerListResponse { result: Result<Vec<GameServer>, String> },
+
 
     // # Event Packets
     // ####################
     // Packet notifying guests that they should remount their root filesystem because
-    // some files have changed (takes a remount to avoid stale file handle errors with overlayfs)
-    NotifyRootChanged,
+    // some files have changed (takes a remount to avoid stale file handle errors with overlayfs).
+    // Guests report back with GuestRootRemountResult.
+    NotifyRootChanged { generation: RootGeneration },
     // Packet notifying guests that they should now clear their dns cache because some
     // mappings have changed
     NotifyDnsMappingsChanged,
     // Packet notifying guests that the list of registered users has changed
-    NotifyUsersChanged
+    NotifyUsersChanged,
+    // Packet notifying guests that the server is shutting down and will close the connection
+    NotifyServerShutdown,
+    // Packet notifying a listener that events it should have received can not be replayed
+    // (e.g. because the server restarted), so it has to refresh all of its state
+    NotifyEventsLost,
+    // Packet asking the guests in the ticket to run the given action after the countdown.
+    // Only delivered to the targeted guests, also when their daemon reconnects in the meantime.
+    NotifyGuestControl {
+        ticket: GuestControlTicket,
+        action: GuestAction,
+        countdown_secs: u32
+    },
+    // Packet asking guests to show an announcement to their logged-in users
+    NotifyAnnouncement {
+        title: String,
+        body: String,
+        urgency: AnnouncementUrgency
+    },
+    // Packet notifying about the result of a guest control action on one of the targeted guests
+    NotifyGuestControlResult {
+        control_id: GuestControlId,
+        guest_id: GuestId,
+        result: Result<(), String>
+    },
+    // Packet delivering a chat message
+    NotifyChatMessage { message: ChatMessage },
+    // Packet notifying that a machine without a connected daemon obtained a dhcp lease,
+    // which usually means that it is booting from the lapas network
+    NotifyGuestBooting { guest: KnownGuest },
+    // Packet notifying about the progress of the rollout of a guest root change
+    NotifyRootRollout { rollout: RootRollout },
+    // Packet notifying that the maintenance mode was turned on, or off (None)
+    NotifyMaintenance { maintenance: Option<MaintenanceMode> }
 });
\ No newline at end of file
@@ -1,12 +1,17 @@
+use std::net::IpAddr;
+
 use chrono::{Utc, DateTime};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::{ProtoSerde, LapasProtocolError};
+use crate::{ProtoSerde, LapasProtocolError, impl_protoserde_for_struct, impl_protoserde_for_enum};
 
 #[derive(Debug, Clone)]
 pub enum ApiAuth {
     /// Plaintext LAPAS administration password
     Password(String),
+    /// Authenticate using the peer credentials of the connection.
+    /// (Only valid on the api server's local unix socket, where root is trusted)
+    PeerCredentials,
 }
 #[async_trait::async_trait]
 impl ProtoSerde for ApiAuth {
@@ -14,6 +19,7 @@ impl ProtoSerde for ApiAuth {
         let tag = reader.read_u8().await?;
         match tag {
             0 => Ok(ApiAuth::Password( String::decode(reader).await? )),
+            1 => Ok(ApiAuth::PeerCredentials),
             _ => Err(LapasProtocolError::ProtocolError("Error while deserializing ApiPassword. Invalid Tag".to_owned()))
         }
     }
@@ -23,6 +29,7 @@ impl ProtoSerde for ApiAuth {
                 writer.write_u8(0).await?;
                 password.encode(writer).await?;
             }
+            ApiAuth::PeerCredentials => writer.write_u8(1).await?,
         }
         Ok(())
     }
@@ -75,4 +82,366 @@ impl ProtoSerde for LapasUserShadow {
         self.last_update_ts.encode(writer).await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Home image of a user, and how much of it is in use
+#[derive(Clone, Debug)]
+pub struct UserHomeUsage {
+    pub username: String,
+    /// Size the user's home image is kept at in bytes, None if the user has no quota
+    pub quota: Option<u64>,
+    /// Size of the user's home image in bytes (the capacity of its filesystem),
+    /// None if the user never logged in and has no home image yet
+    pub image_size: Option<u64>,
+    /// Bytes of the (sparse) home image actually allocated on disk
+    pub used: u64,
+}
+impl_protoserde_for_struct!(UserHomeUsage { username, quota, image_size, used });
+
+
+pub type GuestId = u64;
+
+/// Mode the guest operating system was booted in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestBootMode {
+    /// Guest root is mounted writable, only the base user may log in
+    Admin,
+    /// Guest root is mounted immutable, this is the mode for players
+    User,
+}
+impl_protoserde_for_enum!(GuestBootMode { Admin, User });
+
+/// What a machine boots into from the network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootProfile {
+    /// Show the boot menu, with all kernels and modes
+    Default,
+    /// Boot the newest kernel in user mode right away
+    User,
+    /// Boot the newest kernel in admin mode right away
+    Admin,
+    /// Boot the newest kernel in admin mode into the rescue target, without a graphical session
+    Maintenance,
+}
+impl_protoserde_for_enum!(BootProfile { Default, User, Admin, Maintenance });
+
+/// Boot profile of a machine
+#[derive(Clone, Debug)]
+pub struct GuestBootProfile {
+    /// MAC address in lowercase, colon separated notation
+    pub mac: String,
+    /// Hostname of the machine, if it is a known guest
+    pub hostname: Option<String>,
+    pub profile: BootProfile,
+}
+impl_protoserde_for_struct!(GuestBootProfile { mac, hostname, profile });
+
+/// Server-wide maintenance mode (e.g. while the guest root is updated), during which players can not log in
+#[derive(Clone, Debug)]
+pub struct MaintenanceMode {
+    /// Shown to players whose login is refused
+    pub message: String,
+    pub since_ts: DateTime<Utc>,
+}
+impl_protoserde_for_struct!(MaintenanceMode { message, since_ts });
+
+/// Generation of the guest root image, increased every time the administrator changed it
+pub type RootGeneration = u64;
+
+/// Information a guest daemon reports about the machine it is running on
+#[derive(Clone, Debug)]
+pub struct GuestInfo {
+    pub mac: String,
+    pub hostname: String,
+    pub daemon_version: String,
+    pub boot_mode: GuestBootMode,
+    /// Global IPv4 and IPv6 addresses of the guest's network interface
+    pub addresses: Vec<IpAddr>,
+    /// Generation of the guest root the machine has mounted.
+    /// Unknown to the daemon until it remounted the root once, the server then assumes the current one.
+    pub root_generation: Option<RootGeneration>,
+    /// Random id of the machine's current boot (`/proc/sys/kernel/random/boot_id`), empty if unknown
+    pub boot_id: String,
+}
+impl_protoserde_for_struct!(GuestInfo { mac, hostname, daemon_version, boot_mode, addresses, root_generation, boot_id });
+
+/// Guest with a connected daemon, as tracked by the server
+#[derive(Clone, Debug)]
+pub struct LapasGuest {
+    pub id: GuestId,
+    pub ip: IpAddr,
+    pub info: GuestInfo,
+    pub connect_ts: DateTime<Utc>,
+    pub last_ping_ts: DateTime<Utc>,
+}
+impl_protoserde_for_struct!(LapasGuest { id, ip, info, connect_ts, last_ping_ts });
+impl LapasGuest {
+    /// All addresses of the guest: the one its daemon connected from, followed by the ones it reported
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![self.ip];
+        for address in &self.info.addresses {
+            if !addresses.contains(address) {
+                addresses.push(*address);
+            }
+        }
+        addresses
+    }
+}
+
+/// State of the rollout of a guest root change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootRolloutState {
+    /// Guests are asked to remount their root, wave by wave
+    Running,
+    /// Too many guests failed to remount, the rollout waits for the administrator to resume it
+    Paused,
+    /// All guests were asked to remount their root
+    Finished,
+}
+impl_protoserde_for_enum!(RootRolloutState { Running, Paused, Finished });
+
+/// Guest that failed to remount its root during a rollout
+#[derive(Clone, Debug)]
+pub struct RootRemountFailure {
+    pub guest_id: GuestId,
+    pub hostname: String,
+    pub error: String,
+}
+impl_protoserde_for_struct!(RootRemountFailure { guest_id, hostname, error });
+
+/// Progress of the rollout of a guest root change.
+/// Guests are asked to remount their root in waves, so they don't hit the storage all at once.
+#[derive(Clone, Debug)]
+pub struct RootRollout {
+    pub generation: RootGeneration,
+    pub state: RootRolloutState,
+    /// Guests of the current wave that did not report back yet
+    pub pending: Vec<GuestId>,
+    /// Guests that remounted their root
+    pub remounted: Vec<GuestId>,
+    pub failures: Vec<RootRemountFailure>,
+}
+impl_protoserde_for_struct!(RootRollout { generation, state, pending, remounted, failures });
+
+/// Current generation of the guest root, and the guests that did not mount it yet
+#[derive(Clone, Debug)]
+pub struct RootStatus {
+    pub generation: RootGeneration,
+    pub stragglers: Vec<LapasGuest>,
+    /// Rollout of the latest root change since the server started
+    pub rollout: Option<RootRollout>,
+}
+impl_protoserde_for_struct!(RootStatus { generation, stragglers, rollout });
+
+/// Machine the server has seen in the lapas network, either through its daemon or its dhcp lease
+#[derive(Clone, Debug)]
+pub struct KnownGuest {
+    /// MAC address in lowercase, colon separated notation
+    pub mac: String,
+    pub hostname: String,
+    /// Last address the machine was seen with
+    pub ip: Option<IpAddr>,
+    pub last_seen_ts: DateTime<Utc>,
+    /// Id of the guest, if its daemon is currently connected
+    pub guest_id: Option<GuestId>,
+    /// Whether the machine currently holds a lease of dnsmasq's dhcp server
+    pub has_lease: bool,
+}
+impl_protoserde_for_struct!(KnownGuest { mac, hostname, ip, last_seen_ts, guest_id, has_lease });
+
+/// Guests a request is meant for
+#[derive(Clone, Debug)]
+pub enum GuestTarget {
+    /// All guests with a connected daemon
+    All,
+    /// The guests with the given ids
+    Guests(Vec<GuestId>),
+    /// The guest the user with the given name is currently logged in on
+    User(String),
+}
+#[async_trait::async_trait]
+impl ProtoSerde for GuestTarget {
+    async fn decode<R: AsyncReadExt + Send + Unpin>(reader: &mut R) -> Result<Self, LapasProtocolError> {
+        let tag = reader.read_u8().await?;
+        match tag {
+            0 => Ok(GuestTarget::All),
+            1 => Ok(GuestTarget::Guests( Vec::<GuestId>::decode(reader).await? )),
+            2 => Ok(GuestTarget::User( String::decode(reader).await? )),
+            _ => Err(LapasProtocolError::ProtocolError("Error while deserializing GuestTarget. Invalid Tag".to_owned()))
+        }
+    }
+    async fn encode<W: AsyncWriteExt + Send + Unpin>(&self, writer: &mut W) -> Result<(), LapasProtocolError> {
+        match self {
+            GuestTarget::All => writer.write_u8(0).await?,
+            GuestTarget::Guests(guests) => {
+                writer.write_u8(1).await?;
+                guests.encode(writer).await?;
+            }
+            GuestTarget::User(username) => {
+                writer.write_u8(2).await?;
+                username.encode(writer).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Action a guest daemon runs on behalf of an administrator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestAction {
+    /// Log out all users that are logged in on the guest
+    Logout,
+    Reboot,
+    PowerOff,
+}
+impl_protoserde_for_enum!(GuestAction { Logout, Reboot, PowerOff });
+
+pub type GuestControlId = u64;
+
+/// Control request, as it was dispatched to the targeted guests
+#[derive(Clone, Debug)]
+pub struct GuestControlTicket {
+    pub id: GuestControlId,
+    pub guests: Vec<GuestId>,
+}
+impl_protoserde_for_struct!(GuestControlTicket { id, guests });
+
+/// Urgency of an announcement, as defined by the freedesktop notification spec
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnouncementUrgency {
+    Low,
+    Normal,
+    Critical,
+}
+impl_protoserde_for_enum!(AnnouncementUrgency { Low, Normal, Critical });
+
+pub type ScheduledEventId = u64;
+
+/// Timed event on the server's schedule.
+/// Guests are sent an announcement reminder_offsets_mins minutes before the event starts.
+#[derive(Clone, Debug)]
+pub struct ScheduledEvent {
+    pub id: ScheduledEventId,
+    pub title: String,
+    pub description: String,
+    pub ts: DateTime<Utc>,
+    pub reminder_offsets_mins: Vec<u32>,
+}
+impl_protoserde_for_struct!(ScheduledEvent { id, title, description, ts, reminder_offsets_mins });
+
+pub type EventSeq = u64;
+
+/// Position in the server's stream of events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventCursor {
+    /// Identifies the server instance, sequence numbers start over when the server restarts
+    pub epoch: u64,
+    /// Sequence number of the last event seen
+    pub seq: EventSeq,
+}
+impl_protoserde_for_struct!(EventCursor { epoch, seq });
+
+pub type ChatMessageId = u64;
+
+/// Message relayed between players by the server
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    pub id: ChatMessageId,
+    pub ts: DateTime<Utc>,
+    /// Name of the sending user
+    pub from: String,
+    /// Receiving user of a direct message, None for messages to the global room
+    pub to: Option<String>,
+    pub text: String,
+}
+impl ChatMessage {
+    /// Whether the given user may see this message
+    pub fn is_visible_to(&self, username: &str) -> bool {
+        match &self.to {
+            None => true,
+            Some(to) => to == username || self.from == username,
+        }
+    }
+}
+impl_protoserde_for_struct!(ChatMessage { id, ts, from, to, text });
+
+pub type GameServerId = u64;
+
+/// Game server hosted on a guest, as announced by its host
+#[derive(Clone, Debug)]
+pub struct GameServerInfo {
+    /// Name of the game
+    pub game: String,
+    pub port: u16,
+    pub player_count: u32,
+    pub password_protected: bool,
+}
+impl_protoserde_for_struct!(GameServerInfo { game, port, player_count, password_protected });
+
+/// Game server registered with the server's game server registry
+#[derive(Clone, Debug)]
+pub struct GameServer {
+    pub id: GameServerId,
+    /// Guest the game server is running on
+    pub guest_id: GuestId,
+    pub hostname: String,
+    pub ip: IpAddr,
+    /// Name of the user hosting the game server, if it was registered by a player
+    pub owner: Option<String>,
+    pub info: GameServerInfo,
+    pub registered_ts: DateTime<Utc>,
+}
+impl_protoserde_for_struct!(GameServer { id, guest_id, hostname, ip, owner, info, registered_ts });
+
+/// What a custom dns record resolves to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsRecordTarget {
+    /// Static address, e.g. of one of the server's services
+    Address(IpAddr),
+    /// Alias of the given user's dns mapping (<username>.<domain>), following the machine the user is logged in on
+    User(String),
+}
+#[async_trait::async_trait]
+impl ProtoSerde for DnsRecordTarget {
+    async fn decode<R: AsyncReadExt + Send + Unpin>(reader: &mut R) -> Result<Self, LapasProtocolError> {
+        let tag = reader.read_u8().await?;
+        match tag {
+            0 => Ok(DnsRecordTarget::Address( IpAddr::decode(reader).await? )),
+            1 => Ok(DnsRecordTarget::User( String::decode(reader).await? )),
+            _ => Err(LapasProtocolError::ProtocolError("Error while deserializing DnsRecordTarget. Invalid Tag".to_owned()))
+        }
+    }
+    async fn encode<W: AsyncWriteExt + Send + Unpin>(&self, writer: &mut W) -> Result<(), LapasProtocolError> {
+        match self {
+            DnsRecordTarget::Address(ip) => {
+                writer.write_u8(0).await?;
+                ip.encode(writer).await?;
+            }
+            DnsRecordTarget::User(username) => {
+                writer.write_u8(1).await?;
+                username.encode(writer).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Dns record managed by the administrator, in addition to the automatic user mappings
+#[derive(Clone, Debug)]
+pub struct DnsRecord {
+    /// Fully qualified name of the record
+    pub name: String,
+    pub target: DnsRecordTarget,
+}
+impl_protoserde_for_struct!(DnsRecord { name, target });
+
+/// Dns mapping of a logged in user to the machine the user is logged in on
+#[derive(Clone, Debug)]
+pub struct DnsMapping {
+    pub username: String,
+    /// Fully qualified name of the mapping (<username>.<domain>)
+    pub name: String,
+    /// Addresses of the machine, an A or AAAA record is served for each of them
+    pub addresses: Vec<IpAddr>,
+}
+impl_protoserde_for_struct!(DnsMapping { username, name, addresses });
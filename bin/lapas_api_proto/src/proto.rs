@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use chrono::{DateTime, Utc};
 use paste::paste;
 use thiserror::Error;
@@ -60,6 +62,20 @@ impl ProtoSerde for () {
     }
 }
 
+#[async_trait::async_trait]
+impl ProtoSerde for bool {
+    async fn decode<R: AsyncReadExt + Send + Unpin>(reader: &mut R) -> Result<Self, LapasProtocolError> {
+        Ok(reader.read_u8().await? != 0)
+    }
+    async fn encode<W: AsyncWriteExt + Send + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), LapasProtocolError> {
+        writer.write_u8(*self as u8).await?;
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl<TOk: ProtoSerde, TErr: ProtoSerde> ProtoSerde for Result<TOk, TErr> {
     async fn decode<R: AsyncReadExt + Send + Unpin>(
@@ -151,10 +167,7 @@ impl ProtoSerde for String {
         reader: &mut R,
     ) -> Result<Self, LapasProtocolError> {
         let len = reader.read_u32().await?;
-        let mut str_bytes = Vec::<u8>::with_capacity(len as usize);
-        unsafe {
-            str_bytes.set_len(str_bytes.capacity());
-        }
+        let mut str_bytes = vec![0u8; len as usize];
         reader.read_exact(&mut str_bytes).await?;
         Ok(String::from_utf8(str_bytes).map_err(|_| {
             LapasProtocolError::ProtocolError(
@@ -191,6 +204,81 @@ impl ProtoSerde for DateTime<Utc> {
     }
 }
 
+#[async_trait::async_trait]
+impl ProtoSerde for IpAddr {
+    async fn decode<R: AsyncReadExt + Send + Unpin>(
+        reader: &mut R,
+    ) -> Result<Self, LapasProtocolError> {
+        String::decode(reader).await?.parse().map_err(|e| {
+            LapasProtocolError::ProtocolError(format!("Failed to parse ip address:\n{}", e))
+        })
+    }
+    async fn encode<W: AsyncWriteExt + Send + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), LapasProtocolError> {
+        self.to_string().encode(writer).await?;
+        Ok(())
+    }
+}
+
+/// Implement ProtoSerde for a struct, by (de-)serializing the given fields in order
+macro_rules! impl_protoserde_for_struct {
+    ($structname:ident { $($fieldname:ident),* $(,)? }) => {
+        #[async_trait::async_trait]
+        impl $crate::ProtoSerde for $structname {
+            async fn decode<R: tokio::io::AsyncReadExt + Send + Unpin>(reader: &mut R) -> Result<Self, $crate::LapasProtocolError> {
+                Ok($structname {
+                    $(
+                        $fieldname: $crate::ProtoSerde::decode(reader).await?,
+                    )*
+                })
+            }
+            async fn encode<W: tokio::io::AsyncWriteExt + Send + Unpin>(&self, writer: &mut W) -> Result<(), $crate::LapasProtocolError> {
+                $(
+                    self.$fieldname.encode(writer).await?;
+                )*
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use impl_protoserde_for_struct;
+
+/// Implement ProtoSerde for a fieldless enum, by (de-)serializing the variant's index as u8
+macro_rules! impl_protoserde_for_enum {
+    ($enumname:ident { $($variant:ident),* $(,)? }) => {
+        #[async_trait::async_trait]
+        impl $crate::ProtoSerde for $enumname {
+            async fn decode<R: tokio::io::AsyncReadExt + Send + Unpin>(reader: &mut R) -> Result<Self, $crate::LapasProtocolError> {
+                let tag = reader.read_u8().await?;
+                let mut variant_tag = 0u8..;
+                $(
+                    if tag == variant_tag.next().unwrap() {
+                        return Ok($enumname::$variant);
+                    }
+                )*
+                Err($crate::LapasProtocolError::ProtocolError(
+                    format!("Error while deserializing {}. Invalid tag", stringify!($enumname))
+                ))
+            }
+            async fn encode<W: tokio::io::AsyncWriteExt + Send + Unpin>(&self, writer: &mut W) -> Result<(), $crate::LapasProtocolError> {
+                let mut variant_tag = 0u8;
+                $(
+                    if let $enumname::$variant = self {
+                        writer.write_u8(variant_tag).await?;
+                        return Ok(());
+                    }
+                    variant_tag += 1;
+                )*
+                let _ = variant_tag;
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use impl_protoserde_for_enum;
+
 macro_rules! define_protocol {
     (
         proto $protoname:ident {
@@ -1,17 +1,19 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, os::unix::prelude::PermissionsExt, path::Path};
 
-use anyhow::{anyhow, Result};
-use tokio::net::TcpListener;
-use crate::{api_services::{PeerRx, PeerTx}, state::SharedState};
-use lapas_api_proto::LapasProtocol;
+use anyhow::{anyhow, Context as _, Result};
+use tokio::{net::{TcpListener, TcpSocket, UnixListener}, task::JoinSet};
+use crate::{api_services::{PeerAddr, PeerRx, PeerTx}, config::ListenAddr, state::SharedState, systemd::{self, ActivatedListener}};
+use lapas_api_proto::{GuestId, GuestInfo, LapasProtocol};
 
 #[derive(Clone)]
 struct ClientContext {
-    addr: SocketAddr,
+    addr: PeerAddr,
+    /// Set once the client registered itself as guest daemon
+    guest_id: Option<GuestId>,
 }
 impl ClientContext {
     pub fn log<M: ToString>(&self, msg: M) {
-        println!("Client[{}]: {}", self.addr.ip(), msg.to_string());
+        println!("Client[{}]: {}", self.addr, msg.to_string());
     }
 }
 
@@ -34,13 +36,13 @@ async fn handle_handshake(rx: &mut PeerRx, tx: &PeerTx) -> Result<()> {
 }
 
 macro_rules! handle_request {
-    ($state:ident, $tx:ident, $(@auth_with($auth:ident),)? $response_pkt:ident = {
+    ($state:ident, $ctx:ident, $tx:ident, $(@auth_with($auth:ident),)? $response_pkt:ident = {
         $result_expr:expr;
         $(Ok = $ok_expr:expr;)?
         $(Err = $err_expr:expr;)?
     }) => {
         $(
-            if !$state.check_auth($auth) {
+            if !$state.check_auth($auth, &$ctx.addr) {
                 let result = Err("Authentication failed".to_string());
                 $tx.send(LapasProtocol::$response_pkt { result }).await?;
                 return Err(anyhow!("Authentication failed"));
@@ -56,24 +58,58 @@ macro_rules! handle_request {
     };
 }
 
+async fn register_guest(state: &SharedState, ctx: &mut ClientContext, info: GuestInfo) -> Result<GuestId> {
+    let ip = ctx.addr.ip().ok_or_else(|| anyhow!("Only network clients can register as guest"))?;
+    if let Some(guest_id) = ctx.guest_id.take() {
+        state.unregister_guest(guest_id).await;
+    }
+    let guest_id = state.register_guest(ip, info).await;
+    ctx.guest_id = Some(guest_id);
+    Ok(guest_id)
+}
+
 async fn handle_client(
     mut rx: PeerRx,
     tx: PeerTx,
-    ctx: ClientContext,
+    mut ctx: ClientContext,
     state: SharedState,
 ) -> Result<()> {
     // Handshake
     handle_handshake(&mut rx, &tx).await?;
 
+    let result = handle_requests(&mut rx, &tx, &mut ctx, &state).await;
+    if let Some(guest_id) = ctx.guest_id {
+        state.unregister_guest(guest_id).await;
+    }
+    result
+}
+
+async fn handle_requests(
+    rx: &mut PeerRx,
+    tx: &PeerTx,
+    ctx: &mut ClientContext,
+    state: &SharedState,
+) -> Result<()> {
     // start handling requests
     loop {
-        match rx.recv().await? {
-            LapasProtocol::ControlListenEvents {} => {
-                state.register_event_listener(tx.clone()).await;
-                ctx.log("Registered for events");
+        let pkt = rx.recv().await?;
+        // requests are blocked once the server is shutting down
+        let _request = state.begin_request().await;
+        // any packet proves the guest's daemon alive and renews its home leases, not only pings
+        if let Some(guest_id) = ctx.guest_id {
+            state.guest_ping(guest_id).await;
+        }
+        match pkt {
+            LapasProtocol::ControlPing => {}
+            LapasProtocol::ControlListenEvents { resume_from } => {
+                state.register_event_listener(tx.clone(), ctx.guest_id, resume_from).await;
+                match resume_from {
+                    Some(cursor) => ctx.log(format!("Registered for events, resuming after {}", cursor.seq)),
+                    None => ctx.log("Registered for events"),
+                }
             }
             LapasProtocol::ControlCheckAuth { auth } => {
-                handle_request!(state, tx, @auth_with(auth), ControlCheckAuthResponse = {
+                handle_request!(state, ctx, tx, @auth_with(auth), ControlCheckAuthResponse = {
                     {let result: Result<(), String> = Ok(()); result};
                 });
             }
@@ -83,23 +119,72 @@ async fn handle_client(
                 new_username,
                 new_password,
             } => {
-                handle_request!(state, tx, @auth_with(auth), UserRegisterResponse = {
+                handle_request!(state, ctx, tx, @auth_with(auth), UserRegisterResponse = {
                     state.add_user(new_username.clone(), new_password).await;
                     Ok = || ctx.log(format!("Successfully registered user: {}", new_username));
                     Err = |e| ctx.log(format!("Failed to register new user: {}\n{}", new_username, e));
                 });
             }
             LapasProtocol::UserDnsMapping { auth, username } => {
-                handle_request!(state, tx, @auth_with(auth), UserDnsMappingResponse = {
-                    state.create_user_host_mapping(username.clone(), ctx.addr).await;
+                handle_request!(state, ctx, tx, @auth_with(auth), UserDnsMappingResponse = {
+                    match ctx.addr.ip() {
+                        Some(ip) => state.create_user_host_mapping(username.clone(), vec![ip]).await,
+                        None => Err(anyhow!("DNS mappings can only be created for network clients")),
+                    };
                     Ok = || ctx.log(format!("Usermapping created to: {}", username));
                     Err = |e| ctx.log(format!("Failed to create usermapping to: {}\n{}", username, e));
                 });
             }
 
+            LapasProtocol::DnsMappingList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), DnsMappingListResponse = {
+                    state.dns_mappings_all().await;
+                    Ok = || ctx.log("Requested dns mappings");
+                    Err = |e| ctx.log(format!("Failed to send dns mappings:\n{}", e));
+                });
+            }
+            LapasProtocol::DnsRecordAdd { auth, record } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), DnsRecordAddResponse = {
+                    state.add_dns_record(record.clone()).await;
+                    Ok = || ctx.log(format!("Added dns record: {}", record.name));
+                    Err = |e| ctx.log(format!("Failed to add dns record: {}\n{}", record.name, e));
+                });
+            }
+            LapasProtocol::DnsRecordList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), DnsRecordListResponse = {
+                    state.dns_records_all().await;
+                    Ok = || ctx.log("Requested dns records");
+                    Err = |e| ctx.log(format!("Failed to send dns records:\n{}", e));
+                });
+            }
+            LapasProtocol::DnsRecordRemove { auth, name } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), DnsRecordRemoveResponse = {
+                    state.remove_dns_record(&name).await;
+                    Ok = || ctx.log(format!("Removed dns record: {}", name));
+                    Err = |e| ctx.log(format!("Failed to remove dns record: {}\n{}", name, e));
+                });
+            }
+
+            LapasProtocol::UserHomeUsageList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), UserHomeUsageListResponse = {
+                    state.home_usage_all().await;
+                    Ok = || ctx.log("Requested home usage");
+                    Err = |e| ctx.log(format!("Failed to send home usage:\n{}", e));
+                });
+            }
+
+            LapasProtocol::UserHomeQuotaSet { auth, username, quota } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), UserHomeQuotaSetResponse = {
+                    state.set_home_quota(username.clone(), quota).await;
+                    Ok = || ctx.log(format!("Set home quota of {}", username));
+                    Err = |e| ctx.log(format!("Failed to set home quota of {}:\n{}", username, e));
+                });
+            }
+
             LapasProtocol::PasswdGetList => {
                 handle_request!(
                     state,
+                    ctx,
                     tx,
                     PasswdGetListResponse = {
                         state.passwd_all().await;
@@ -110,13 +195,225 @@ async fn handle_client(
             }
 
             LapasProtocol::ShadowGetList { auth } => {
-                handle_request!(state, tx, @auth_with(auth), ShadowGetListResponse = {
+                handle_request!(state, ctx, tx, @auth_with(auth), ShadowGetListResponse = {
                     state.shadow_all().await;
                     Ok = || ctx.log("Requested passwd");
                     Err = |e| ctx.log(format!("Failed to send passwd:\n{}", e));
                 });
             }
 
+            LapasProtocol::GuestRegister { auth, info } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GuestRegisterResponse = {
+                    register_guest(state, ctx, info.clone()).await;
+                    Ok = || ctx.log(format!("Registered guest: {} [{}]", info.hostname, info.mac));
+                    Err = |e| ctx.log(format!("Failed to register guest:\n{}", e));
+                });
+            }
+            LapasProtocol::GuestList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GuestListResponse = {
+                    state.guests_all().await;
+                    Ok = || ctx.log("Requested guest list");
+                    Err = |e| ctx.log(format!("Failed to send guest list:\n{}", e));
+                });
+            }
+
+            LapasProtocol::GuestControl { auth, target, action, countdown_secs } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GuestControlResponse = {
+                    state.control_guests(target, action, countdown_secs).await;
+                    Ok = || ctx.log(format!("Requested guest control: {:?}", action));
+                    Err = |e| ctx.log(format!("Failed to request guest control: {:?}\n{}", action, e));
+                });
+            }
+            LapasProtocol::GuestControlResult { control_id, result } => {
+                match ctx.guest_id {
+                    Some(guest_id) => {
+                        ctx.log(format!("Guest control {} finished: {:?}", control_id, result));
+                        state.guest_control_result(guest_id, control_id, result);
+                    }
+                    None => ctx.log("Ignoring guest control result from unregistered guest"),
+                }
+            }
+
+            LapasProtocol::MaintenanceSet { auth, message, logout_countdown_secs } => {
+                let enable = message.is_some();
+                handle_request!(state, ctx, tx, @auth_with(auth), MaintenanceSetResponse = {
+                    state.set_maintenance(message, logout_countdown_secs).await;
+                    Ok = || ctx.log(format!("Turned maintenance mode {}", if enable { "on" } else { "off" }));
+                    Err = |e| ctx.log(format!("Failed to change maintenance mode:\n{}", e));
+                });
+            }
+            LapasProtocol::MaintenanceGet { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), MaintenanceGetResponse = {
+                    state.maintenance().await;
+                    Ok = || ctx.log("Requested maintenance mode");
+                    Err = |e| ctx.log(format!("Failed to send maintenance mode:\n{}", e));
+                });
+            }
+
+            LapasProtocol::RootChanged { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), RootChangedResponse = {
+                    state.root_changed().await;
+                    Ok = || ctx.log("Guest root changed, asked guests to remount");
+                    Err = |e| ctx.log(format!("Failed to announce guest root change:\n{}", e));
+                });
+            }
+            LapasProtocol::RootStatus { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), RootStatusResponse = {
+                    state.root_status().await;
+                    Ok = || ctx.log("Requested guest root status");
+                    Err = |e| ctx.log(format!("Failed to send guest root status:\n{}", e));
+                });
+            }
+            LapasProtocol::RootRolloutResume { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), RootRolloutResumeResponse = {
+                    state.root_rollout_resume().await;
+                    Ok = || ctx.log("Resumed guest root rollout");
+                    Err = |e| ctx.log(format!("Failed to resume guest root rollout:\n{}", e));
+                });
+            }
+            LapasProtocol::GuestRootRemountResult { generation, result } => {
+                match ctx.guest_id {
+                    Some(guest_id) => {
+                        ctx.log(format!("Guest root remount to generation {} finished: {:?}", generation, result));
+                        state.guest_root_remount_result(guest_id, generation, result).await;
+                    }
+                    None => ctx.log("Ignoring guest root remount result from unregistered guest"),
+                }
+            }
+
+            LapasProtocol::Announce { auth, target, title, body, urgency } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), AnnounceResponse = {
+                    state.announce(target, title.clone(), body, urgency).await;
+                    Ok = || ctx.log(format!("Sent announcement: {}", title));
+                    Err = |e| ctx.log(format!("Failed to send announcement: {}\n{}", title, e));
+                });
+            }
+
+            LapasProtocol::KnownGuestList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), KnownGuestListResponse = {
+                    state.known_guests_all().await;
+                    Ok = || ctx.log("Requested list of known guests");
+                    Err = |e| ctx.log(format!("Failed to send list of known guests:\n{}", e));
+                });
+            }
+
+            LapasProtocol::GuestWake { auth, hosts } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GuestWakeResponse = {
+                    state.wake_guests(hosts).await;
+                    Ok = || ctx.log("Sent Wake-on-LAN magic packets");
+                    Err = |e| ctx.log(format!("Failed to wake guests:\n{}", e));
+                });
+            }
+
+            LapasProtocol::BootProfileSet { auth, host, profile } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), BootProfileSetResponse = {
+                    state.set_boot_profile(&host, profile).await;
+                    Ok = || ctx.log(format!("Set boot profile of {} to {:?}", host, profile));
+                    Err = |e| ctx.log(format!("Failed to set boot profile of {}:\n{}", host, e));
+                });
+            }
+
+            LapasProtocol::BootProfileList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), BootProfileListResponse = {
+                    state.boot_profiles_all().await;
+                    Ok = || ctx.log("Requested boot profiles");
+                    Err = |e| ctx.log(format!("Failed to send boot profiles:\n{}", e));
+                });
+            }
+
+            LapasProtocol::ScheduleAdd { auth, title, description, ts, reminder_offsets_mins } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), ScheduleAddResponse = {
+                    state.schedule_add(title.clone(), description, ts, reminder_offsets_mins).await;
+                    Ok = || ctx.log(format!("Scheduled event: {} at {}", title, ts));
+                    Err = |e| ctx.log(format!("Failed to schedule event: {}\n{}", title, e));
+                });
+            }
+            LapasProtocol::ScheduleList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), ScheduleListResponse = {
+                    state.schedule_all().await;
+                    Ok = || ctx.log("Requested schedule");
+                    Err = |e| ctx.log(format!("Failed to send schedule:\n{}", e));
+                });
+            }
+            LapasProtocol::ScheduleRemove { auth, id } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), ScheduleRemoveResponse = {
+                    state.schedule_remove(id).await;
+                    Ok = || ctx.log(format!("Removed scheduled event: {}", id));
+                    Err = |e| ctx.log(format!("Failed to remove scheduled event: {}\n{}", id, e));
+                });
+            }
+
+            LapasProtocol::SessionBegin { auth, username, resume } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), SessionBeginResponse = {
+                    match ctx.guest_id {
+                        Some(guest_id) => state.begin_session(username.clone(), guest_id, resume).await,
+                        None => Err(anyhow!("Sessions can only be started by registered guests")),
+                    };
+                    Ok = || ctx.log(format!("Session started for: {}", username));
+                    Err = |e| ctx.log(format!("Refused session for: {}\n{}", username, e));
+                });
+            }
+            LapasProtocol::SessionEnd { auth, username } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), SessionEndResponse = {
+                    match ctx.guest_id {
+                        Some(guest_id) => state.end_session(&username, guest_id).await,
+                        None => Err(anyhow!("Sessions can only be ended by registered guests")),
+                    };
+                    Ok = || ctx.log(format!("Session ended for: {}", username));
+                    Err = |e| ctx.log(format!("Failed to end session for: {}\n{}", username, e));
+                });
+            }
+
+            LapasProtocol::GameServerRegister { auth, owner, info } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GameServerRegisterResponse = {
+                    match ctx.guest_id {
+                        Some(guest_id) => state.register_game_server(guest_id, owner, info.clone()).await,
+                        None => Err(anyhow!("Game servers can only be registered by registered guests")),
+                    };
+                    Ok = || ctx.log(format!("Registered game server: {} on port {}", info.game, info.port));
+                    Err = |e| ctx.log(format!("Failed to register game server: {}\n{}", info.game, e));
+                });
+            }
+            LapasProtocol::GameServerHeartbeat { auth, id, player_count } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GameServerHeartbeatResponse = {
+                    match ctx.guest_id {
+                        Some(guest_id) => state.game_server_heartbeat(guest_id, id, player_count).await,
+                        None => Err(anyhow!("Game servers can only be kept alive by registered guests")),
+                    };
+                });
+            }
+            LapasProtocol::GameServerUnregister { auth, id } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GameServerUnregisterResponse = {
+                    match ctx.guest_id {
+                        Some(guest_id) => state.unregister_game_server(guest_id, id).await,
+                        None => Err(anyhow!("Game servers can only be unregistered by registered guests")),
+                    };
+                    Ok = || ctx.log(format!("Unregistered game server: {}", id));
+                    Err = |e| ctx.log(format!("Failed to unregister game server: {}\n{}", id, e));
+                });
+            }
+            LapasProtocol::GameServerList { auth } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), GameServerListResponse = {
+                    state.game_servers_all().await;
+                    Ok = || ctx.log("Requested game server list");
+                    Err = |e| ctx.log(format!("Failed to send game server list:\n{}", e));
+                });
+            }
+
+            LapasProtocol::ChatSend { auth, from, to, text } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), ChatSendResponse = {
+                    state.chat_send(from.clone(), to, text).await;
+                    Err = |e| ctx.log(format!("Failed to relay chat message from: {}\n{}", from, e));
+                });
+            }
+            LapasProtocol::ChatHistory { auth, username } => {
+                handle_request!(state, ctx, tx, @auth_with(auth), ChatHistoryResponse = {
+                    state.chat_history(&username).await;
+                    Ok = || ctx.log(format!("Requested chat history for: {}", username));
+                    Err = |e| ctx.log(format!("Failed to send chat history:\n{}", e));
+                });
+            }
+
             _ => {}
         }
     }
@@ -124,29 +421,116 @@ async fn handle_client(
 
 
 
+fn spawn_client(addr: PeerAddr, rx: PeerRx, tx: PeerTx, state: SharedState) {
+    tokio::spawn(async move {
+        let clog = ClientContext { addr, guest_id: None };
+        clog.log("Connected");
+        if let Err(e) = handle_client(rx, tx.clone(), clog.clone(), state).await {
+            clog.log(format!("Error: {}", e));
+        }
+        tx.shutdown().await;
+        clog.log("Disconnected");
+    });
+}
+
+async fn serve_tcp(listener: TcpListener, state: SharedState) -> Result<()> {
+    loop {
+        let (client_stream, mut addr) = listener.accept().await?;
+        // IPv4 clients of dual-stack listeners show up with IPv4-mapped IPv6 addresses
+        addr.set_ip(addr.ip().to_canonical());
+        let (rx, tx) = client_stream.into_split();
+        spawn_client(PeerAddr::Tcp(addr), PeerRx::new(rx), PeerTx::new(tx), state.clone());
+    }
+}
+
+async fn serve_unix(listener: UnixListener, state: SharedState) -> Result<()> {
+    loop {
+        let (client_stream, _) = listener.accept().await?;
+        let cred = client_stream.peer_cred()?;
+        let addr = PeerAddr::Unix { uid: cred.uid(), pid: cred.pid() };
+        let (rx, tx) = client_stream.into_split();
+        spawn_client(addr, PeerRx::new(rx), PeerTx::new(tx), state.clone());
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, interface: Option<&str>) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    // only accept connections arriving on this interface, e.g. to listen on all addresses
+    // of the lapas network, but not on the upstream network
+    socket.bind_device(interface.map(str::as_bytes))?;
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+async fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if let Some(socket_dir) = path.parent() {
+        tokio::fs::create_dir_all(socket_dir).await?;
+    }
+    if path.exists() {
+        tokio::fs::remove_file(path).await?;
+    }
+    let listener = UnixListener::bind(path)?;
+
+    // everyone may connect, only root is trusted without further authentication
+    let mut socket_permissions = tokio::fs::metadata(path).await?.permissions();
+    socket_permissions.set_mode(0o666);
+    tokio::fs::set_permissions(path, socket_permissions).await?;
+    Ok(listener)
+}
+
 pub async fn run(state: SharedState) -> Result<()> {
     // start server
     println!(
         "Starting LAPAS API Server [Protocol Version: {}]",
         lapas_api_proto::VERSION
     );
-    let listener = TcpListener::bind(("0.0.0.0", 1337)).await?;
-    println!("Listening on 0.0.0.0:1337");
-    loop {
-        let (client_stream, addr) = listener.accept().await?;
-        tokio::spawn({
-            let state = state.clone();
-            async move {
-                let clog = ClientContext { addr };
-                clog.log("Connected");
-                let (rx, tx) = client_stream.into_split();
-                let (rx, tx) = (PeerRx::new(rx), PeerTx::new(tx));
-                if let Err(e) = handle_client(rx, tx.clone(), clog.clone(), state).await {
-                    clog.log(format!("Error: {}", e));
-                }
-                tx.shutdown().await;
-                clog.log("Disconnected");
+    let mut listeners = JoinSet::new();
+    let mut activated_listeners = systemd::activated_listeners()?;
+    for listen_addr in state.config().api_listen.iter() {
+        // sockets passed in by systemd are used instead of binding the address ourselves
+        if let Some(idx) = activated_listeners.iter().position(|l| l.is_listening_on(listen_addr)) {
+            match activated_listeners.remove(idx) {
+                ActivatedListener::Tcp(listener) => listeners.spawn(serve_tcp(listener, state.clone())),
+                ActivatedListener::Unix(listener) => listeners.spawn(serve_unix(listener, state.clone())),
+            };
+            println!("Listening on {} (socket activated)", listen_addr);
+            continue;
+        }
+        match listen_addr {
+            ListenAddr::Tcp(addr, interface) => {
+                let listener = bind_tcp(*addr, interface.as_deref())
+                    .with_context(|| format!("Listening on {}", listen_addr))?;
+                listeners.spawn(serve_tcp(listener, state.clone()));
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path).await
+                    .with_context(|| format!("Listening on {}", listen_addr))?;
+                listeners.spawn(serve_unix(listener, state.clone()));
             }
-        });
+        }
+        println!("Listening on {}", listen_addr);
     }
-}
\ No newline at end of file
+    for activated_listener in activated_listeners {
+        match activated_listener {
+            ActivatedListener::Tcp(listener) => {
+                println!("Listening on {} (socket activated)", listener.local_addr()?);
+                listeners.spawn(serve_tcp(listener, state.clone()));
+            }
+            ActivatedListener::Unix(listener) => {
+                println!("Listening on {:?} (socket activated)", listener.local_addr()?);
+                listeners.spawn(serve_unix(listener, state.clone()));
+            }
+        }
+    }
+    systemd::notify_ready();
+
+    // listeners only ever return on errors
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+
+use anyhow::{anyhow, Result};
+use lapas_api_proto::BootProfile;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::api_services::write_atomic;
+
+/// Prefix of the per-MAC configuration files that grub looks for before grub.cfg when booting from the network
+const GRUB_MAC_CONFIG_PREFIX: &str = "grub.cfg-01-";
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StoredBootProfile {
+    User,
+    Admin,
+    Maintenance,
+}
+impl StoredBootProfile {
+    fn from_profile(profile: BootProfile) -> Option<Self> {
+        match profile {
+            BootProfile::Default => None,
+            BootProfile::User => Some(Self::User),
+            BootProfile::Admin => Some(Self::Admin),
+            BootProfile::Maintenance => Some(Self::Maintenance),
+        }
+    }
+
+    fn profile(self) -> BootProfile {
+        match self {
+            Self::User => BootProfile::User,
+            Self::Admin => BootProfile::Admin,
+            Self::Maintenance => BootProfile::Maintenance,
+        }
+    }
+
+    /// Id of the boot menu entry of the newest kernel in this mode (see updateBootmenus.sh)
+    fn menuentry_id(self) -> &'static str {
+        match self {
+            Self::User => "lapas-user",
+            Self::Admin => "lapas-admin",
+            Self::Maintenance => "lapas-maintenance",
+        }
+    }
+}
+
+/// Boot profiles of single machines, persisted in a json file (BOOT_PROFILES) in the homes directory.
+/// Machines without a boot profile get the default boot menu. For all others, a grub configuration
+/// file named after their MAC address is generated in the tftp directory, which loads the default
+/// boot menu and boots the profile's menu entry right away.
+/// A missing grub directory (e.g. before the boot menus were generated the first time) only
+/// disables changing boot profiles, until it exists.
+pub(crate) struct BootProfileService {
+    grub_dir: PathBuf,
+    path: PathBuf,
+    profiles: Mutex<BTreeMap<String, StoredBootProfile>>,
+    /// Whether the grub directory was rebuilt from the persisted profiles yet
+    grub_dir_synced: AtomicBool,
+}
+impl BootProfileService {
+    pub async fn open(homes_dir: &Path, tftp_dir: &Path) -> Result<Self> {
+        let grub_dir = tftp_dir.join("grub2");
+        let path = homes_dir.join("BOOT_PROFILES");
+        let profiles: BTreeMap<String, StoredBootProfile> = match path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?,
+            false => BTreeMap::new(),
+        };
+        let service = Self { grub_dir, path, profiles: Mutex::new(profiles), grub_dir_synced: AtomicBool::new(false) };
+        match service.grub_dir.is_dir() {
+            true => service.sync_grub_dir(&*service.profiles.lock().await).await?,
+            false => eprintln!("Grub directory {:?} does not exist, boot profiles can not be changed until it does", service.grub_dir),
+        }
+        Ok(service)
+    }
+
+    /// Rebuild the generated files from the persisted profiles, dropping files of removed profiles
+    async fn sync_grub_dir(&self, profiles: &BTreeMap<String, StoredBootProfile>) -> Result<()> {
+        let mut grub_files = tokio::fs::read_dir(&self.grub_dir).await?;
+        while let Some(grub_file) = grub_files.next_entry().await? {
+            if grub_file.file_name().to_string_lossy().starts_with(GRUB_MAC_CONFIG_PREFIX) {
+                tokio::fs::remove_file(grub_file.path()).await?;
+            }
+        }
+        for (mac, profile) in profiles {
+            self.write_grub_config(mac, *profile).await?;
+        }
+        self.grub_dir_synced.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn persist(&self, profiles: &BTreeMap<String, StoredBootProfile>) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string_pretty(profiles)?.as_bytes()).await
+    }
+
+    fn grub_config_name(mac: &str) -> String {
+        format!("{}{}", GRUB_MAC_CONFIG_PREFIX, mac.replace(':', "-"))
+    }
+
+    async fn write_grub_config(&self, mac: &str, profile: StoredBootProfile) -> Result<()> {
+        let grub_config = format!(
+            "# Boot profile of {} - generated by the lapas api server, changes are overwritten\n\
+            source \"${{prefix}}/grub.cfg\"\n\
+            set default=\"{}\"\n\
+            set timeout=0\n",
+            mac, profile.menuentry_id()
+        );
+        // the tftp server must never hand out a half-written file
+        write_atomic(&self.grub_dir.join(Self::grub_config_name(mac)), grub_config.as_bytes()).await
+    }
+
+    /// Set the boot profile of the machine with the given (normalized) MAC address
+    pub async fn set(&self, mac: &str, profile: BootProfile) -> Result<()> {
+        let mut profiles = self.profiles.lock().await;
+        if !self.grub_dir.is_dir() {
+            return Err(anyhow!("Grub directory {:?} does not exist", self.grub_dir));
+        }
+        if !self.grub_dir_synced.load(Ordering::Relaxed) {
+            self.sync_grub_dir(&profiles).await?;
+        }
+        match StoredBootProfile::from_profile(profile) {
+            Some(profile) => {
+                self.write_grub_config(mac, profile).await?;
+                profiles.insert(mac.to_owned(), profile);
+            },
+            None => {
+                if profiles.remove(mac).is_some() {
+                    tokio::fs::remove_file(self.grub_dir.join(Self::grub_config_name(mac))).await?;
+                }
+            },
+        }
+        self.persist(&profiles).await
+    }
+
+    /// MAC addresses of all machines with a boot profile other than the default boot menu
+    pub async fn all(&self) -> Vec<(String, BootProfile)> {
+        self.profiles.lock().await.iter()
+            .map(|(mac, profile)| (mac.clone(), profile.profile()))
+            .collect()
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lapas_api_proto::{ChatMessage, ChatMessageId};
+use tokio::sync::Mutex;
+
+/// Amount of recent messages kept for players that open the chat later on
+const CHAT_HISTORY_CAPACITY: usize = 256;
+/// Maximum length of a single chat message (in characters)
+const CHAT_MESSAGE_MAX_LEN: usize = 1000;
+
+struct ChatHistory {
+    next_id: ChatMessageId,
+    messages: VecDeque<ChatMessage>,
+}
+
+/// Relays chat messages between players, keeping a bounded history in memory
+pub(crate) struct ChatService {
+    history: Mutex<ChatHistory>,
+}
+impl ChatService {
+    pub fn new() -> Self {
+        Self {
+            history: Mutex::new(ChatHistory { next_id: 1, messages: VecDeque::new() }),
+        }
+    }
+
+    pub async fn send(&self, from: String, to: Option<String>, text: String) -> Result<ChatMessage> {
+        let text = text.trim().to_owned();
+        if from.is_empty() {
+            return Err(anyhow!("Sender of chat message must not be empty!"));
+        }
+        if text.is_empty() {
+            return Err(anyhow!("Chat message must not be empty!"));
+        }
+        if text.chars().count() > CHAT_MESSAGE_MAX_LEN {
+            return Err(anyhow!("Chat message is longer than {} characters!", CHAT_MESSAGE_MAX_LEN));
+        }
+        let mut history = self.history.lock().await;
+        let message = ChatMessage { id: history.next_id, ts: Utc::now(), from, to, text };
+        history.next_id += 1;
+        history.messages.push_back(message.clone());
+        if history.messages.len() > CHAT_HISTORY_CAPACITY {
+            history.messages.pop_front();
+        }
+        Ok(message)
+    }
+
+    /// Recent messages the given user may see, oldest first
+    pub async fn history(&self, username: &str) -> Vec<ChatMessage> {
+        self.history.lock().await.messages.iter()
+            .filter(|message| message.is_visible_to(username))
+            .cloned()
+            .collect()
+    }
+}
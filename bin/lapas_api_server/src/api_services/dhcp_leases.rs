@@ -0,0 +1,160 @@
+use std::{io::ErrorKind, net::IpAddr, path::{Path, PathBuf}, time::SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+
+use crate::api_services::known_guest::normalize_mac;
+
+/// Lease handed out by dnsmasq's dhcp server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DhcpLease {
+    /// None for infinite leases
+    pub expiry_ts: Option<DateTime<Utc>>,
+    pub mac: String,
+    pub ip: IpAddr,
+    /// Hostname the client sent in its dhcp request
+    pub hostname: Option<String>,
+}
+
+/// Parse a line of dnsmasq's lease file: `<expiry> <mac> <ip> <hostname or *> <client id or *>`
+fn parse_lease(line: &str) -> Option<DhcpLease> {
+    let mut fields = line.split_whitespace();
+    let expiry_ts = match fields.next()?.parse().ok()? {
+        0 => None,
+        expiry => Some(DateTime::from_timestamp(expiry, 0)?),
+    };
+    // DHCPv6 leases carry the IAID instead of a MAC address, those are skipped
+    let mac = normalize_mac(fields.next()?)?;
+    let ip = fields.next()?.parse().ok()?;
+    let hostname = match fields.next()? {
+        "*" => None,
+        hostname => Some(hostname.to_owned()),
+    };
+    Some(DhcpLease { expiry_ts, mac, ip, hostname })
+}
+
+/// Read all leases from dnsmasq's lease file. A missing lease file has no leases.
+pub async fn read_leases(path: &Path) -> Result<Vec<DhcpLease>> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content.lines().filter_map(parse_lease).collect())
+}
+
+#[derive(Default)]
+struct LeaseFile {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    leases: Vec<DhcpLease>,
+}
+
+/// Current leases of dnsmasq's dhcp server, re-read whenever its lease file changes
+pub(crate) struct DhcpLeaseTable {
+    lease_file: Mutex<LeaseFile>,
+}
+impl DhcpLeaseTable {
+    pub fn new() -> Self {
+        Self { lease_file: Mutex::new(LeaseFile::default()) }
+    }
+
+    /// Re-read the lease file at the given path, if it changed since the last call.
+    /// Returns None if it did not change, and the leases that were obtained or renewed otherwise.
+    /// All leases count as unchanged when the lease file is read for the first time.
+    pub async fn refresh(&self, path: &Path) -> Result<Option<Vec<DhcpLease>>> {
+        let modified = match tokio::fs::metadata(path).await {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let mut lease_file = self.lease_file.lock().await;
+        let first_read = lease_file.path.as_deref() != Some(path);
+        if !first_read && lease_file.modified == modified {
+            return Ok(None);
+        }
+        let leases = read_leases(path).await?;
+        let new_leases = match first_read {
+            true => vec![],
+            false => leases.iter().filter(|lease| !lease_file.leases.contains(lease)).cloned().collect(),
+        };
+        *lease_file = LeaseFile { path: Some(path.to_owned()), modified, leases };
+        Ok(Some(new_leases))
+    }
+
+    pub async fn all(&self) -> Vec<DhcpLease> {
+        self.lease_file.lock().await.leases.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parses_leases() {
+        let lease = parse_lease("1700000000 52:54:00:AB:CD:EF 192.168.42.10 guest1 01:52:54:00:ab:cd:ef").unwrap();
+        assert_eq!(lease, DhcpLease {
+            expiry_ts: DateTime::from_timestamp(1700000000, 0),
+            mac: "52:54:00:ab:cd:ef".to_owned(),
+            ip: "192.168.42.10".parse().unwrap(),
+            hostname: Some("guest1".to_owned()),
+        });
+    }
+
+    #[test]
+    fn parses_infinite_lease_without_hostname() {
+        let lease = parse_lease("0 52:54:00:ab:cd:ef 192.168.42.10 * *").unwrap();
+        assert_eq!(lease.expiry_ts, None);
+        assert_eq!(lease.hostname, None);
+    }
+
+    #[test]
+    fn skips_dhcpv6_and_malformed_lines() {
+        assert_eq!(parse_lease("duid 00:01:00:01:2c:7a:1b:2c:52:54:00:ab:cd:ef"), None);
+        assert_eq!(parse_lease("1700000000 1180048711 fd00::10 guest1 00:01:00:01:2c:7a:1b:2c:52:54:00:ab:cd:ef"), None);
+        assert_eq!(parse_lease("1700000000 52:54:00:ab:cd:ef"), None);
+        assert_eq!(parse_lease(""), None);
+    }
+
+    /// Replace the lease file, with a distinct modification time
+    fn write_leases(path: &Path, leases: &str, modified: SystemTime) {
+        std::fs::write(path, leases).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refresh_reports_new_leases() {
+        let dir = std::env::temp_dir().join(format!("lapas-dhcp-leases-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnsmasq.leases");
+        let _ = std::fs::remove_file(&path);
+        let table = DhcpLeaseTable::new();
+        let first = "0 52:54:00:00:00:01 192.168.42.10 guest1 *\n";
+        let renewed = "0 52:54:00:00:00:02 192.168.42.11 guest2 *\n";
+
+        // a missing lease file has no leases
+        assert_eq!(table.refresh(&path).await.unwrap(), Some(vec![]));
+        assert_eq!(table.refresh(&path).await.unwrap(), None);
+
+        let ts = SystemTime::now();
+        write_leases(&path, first, ts);
+        assert_eq!(table.refresh(&path).await.unwrap(), Some(read_leases(&path).await.unwrap()));
+        assert_eq!(table.refresh(&path).await.unwrap(), None);
+
+        write_leases(&path, &format!("{}{}", first, renewed), ts + Duration::from_secs(1));
+        let new_leases = table.refresh(&path).await.unwrap().unwrap();
+        assert_eq!(new_leases.len(), 1);
+        assert_eq!(new_leases[0].mac, "52:54:00:00:00:02");
+        assert_eq!(table.all().await.len(), 2);
+
+        // leases that are gone are not reported
+        write_leases(&path, first, ts + Duration::from_secs(2));
+        assert_eq!(table.refresh(&path).await.unwrap(), Some(vec![]));
+        assert_eq!(table.all().await.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
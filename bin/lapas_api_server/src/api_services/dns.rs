@@ -1,31 +1,250 @@
-use std::{path::PathBuf, net::SocketAddr};
+use std::{collections::HashMap, path::{Path, PathBuf}, net::IpAddr};
 use anyhow::{anyhow, Result};
-use tokio::io::AsyncWriteExt;
+use lapas_api_proto::{DnsMapping, DnsRecord, DnsRecordTarget};
+use serde::{Deserialize, Serialize};
 
+use crate::api_services::{user::is_valid_username, write_atomic};
+
+/// Hostfile within the hostsdir that contains the custom records.
+/// ':' can not be part of a username, so this never collides with a user's mapping file.
+const CUSTOM_RECORDS_HOSTFILE: &str = ":custom-records";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StoredRecordTarget {
+    Address(IpAddr),
+    User(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredRecord {
+    name: String,
+    target: StoredRecordTarget,
+}
+impl From<DnsRecord> for StoredRecord {
+    fn from(record: DnsRecord) -> Self {
+        let target = match record.target {
+            DnsRecordTarget::Address(ip) => StoredRecordTarget::Address(ip),
+            DnsRecordTarget::User(username) => StoredRecordTarget::User(username),
+        };
+        Self { name: record.name, target }
+    }
+}
+impl From<StoredRecord> for DnsRecord {
+    fn from(record: StoredRecord) -> Self {
+        let target = match record.target {
+            StoredRecordTarget::Address(ip) => DnsRecordTarget::Address(ip),
+            StoredRecordTarget::User(username) => DnsRecordTarget::User(username),
+        };
+        Self { name: record.name, target }
+    }
+}
+
+/// Check that the given name is a valid dns name
+fn check_dns_name(name: &str) -> Result<()> {
+    let valid_label = |label: &str| {
+        !label.is_empty() && label.len() <= 63
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    if name.len() > 253 || !name.split('.').all(valid_label) {
+        return Err(anyhow!("Invalid dns name: {}", name));
+    }
+    Ok(())
+}
+
+/// Writes the dns mappings of logged in users (<username>.<domain>) and the custom records
+/// into dnsmasq's hostsdir. User mappings only live as long as the user's session, so the
+/// hostsdir is rebuilt from the server's state on startup.
 pub(crate) struct DnsService {
     dns_domain: String,
-    hosts_dir: PathBuf
+    /// Address of the lapas server itself (lapas.<domain>), if configured
+    server_ip: Option<IpAddr>,
+    hosts_dir: PathBuf,
+    /// Addresses of every user with a dns mapping
+    mappings: HashMap<String, Vec<IpAddr>>,
+    /// Custom records, persisted in a json file (DNS_RECORDS) in the homes directory
+    records_path: PathBuf,
+    records: Vec<StoredRecord>,
 }
 impl DnsService {
-    pub async fn new(dns_domain: String, hosts_dir: String) -> Result<Self> {
-        let hosts_dir = PathBuf::from(hosts_dir);
+    pub async fn new(dns_domain: String, server_ip: Option<IpAddr>, hosts_dir: PathBuf, homes_dir: &Path) -> Result<Self> {
         if !hosts_dir.exists() {
             tokio::fs::create_dir_all(&hosts_dir).await?;
         }
         if !hosts_dir.is_dir() {
             return Err(anyhow!("Failed to create dnsmasq hostsdir!"));
         }
-        Ok(Self { dns_domain, hosts_dir })
+        let records_path = homes_dir.join("DNS_RECORDS");
+        let records = match records_path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&records_path).await?)?,
+            false => vec![],
+        };
+        // mappings of a previous server instance are stale, the users' sessions recreate them
+        let mut hostfiles = tokio::fs::read_dir(&hosts_dir).await?;
+        while let Some(hostfile) = hostfiles.next_entry().await? {
+            if hostfile.file_type().await?.is_file() && hostfile.file_name() != CUSTOM_RECORDS_HOSTFILE {
+                tokio::fs::remove_file(hostfile.path()).await?;
+            }
+        }
+        let dns_service = Self { dns_domain, server_ip, hosts_dir, mappings: HashMap::new(), records_path, records };
+        dns_service.write_custom_records().await?;
+        Ok(dns_service)
     }
 
-    pub async fn create_mapping(&self, username: String, addr: SocketAddr) -> Result<()> {
-        let mut user_file = self.hosts_dir.clone();
-        user_file.push(&username);
-        let mut user_file = tokio::fs::File::create(user_file).await?;
-        let mapping = format!("{} {}.{}\n", addr.ip().to_string(), username, self.dns_domain);
-        user_file.write_all(&mapping.as_bytes()).await?;
-        user_file.flush().await?;
+    /// Map the user's name to the given addresses, with an A or AAAA record for each of them
+    pub async fn create_mapping(&mut self, username: String, addresses: Vec<IpAddr>) -> Result<()> {
+        // the username becomes the name of the user's hostfile
+        if !is_valid_username(&username) {
+            return Err(anyhow!("Invalid username: {}", username));
+        }
+        if addresses.is_empty() {
+            return Err(anyhow!("No addresses to map {} to", username));
+        }
+        let name = self.user_mapping_name(&username);
+        let mapping: String = addresses.iter().map(|ip| format!("{} {}\n", ip, name)).collect();
+        // dnsmasq watches the hostsdir, it must never see a half-written file
+        write_atomic(&self.hosts_dir.join(&username), mapping.as_bytes()).await?;
+        self.mappings.insert(username.clone(), addresses);
+        self.user_mapping_changed(&username).await
+    }
+
+    pub async fn remove_mapping(&mut self, username: &str) -> Result<()> {
+        if self.mappings.remove(username).is_none() {
+            return Ok(());
+        }
+        tokio::fs::remove_file(self.hosts_dir.join(username)).await?;
+        self.user_mapping_changed(username).await
+    }
+
+    /// Aliases of a user follow the user's mapping
+    async fn user_mapping_changed(&self, username: &str) -> Result<()> {
+        if self.records.iter().any(|record| matches!(&record.target, StoredRecordTarget::User(target) if target == username)) {
+            self.write_custom_records().await?;
+        }
         Ok(())
     }
 
+    pub fn mappings(&self) -> Vec<DnsMapping> {
+        let mut mappings: Vec<_> = self.mappings.iter()
+            .map(|(username, addresses)| DnsMapping {
+                username: username.clone(),
+                name: self.user_mapping_name(username),
+                addresses: addresses.clone(),
+            })
+            .collect();
+        mappings.sort_by(|a, b| a.username.cmp(&b.username));
+        mappings
+    }
+
+    /// Qualify names without a dot with the lapas network's domain
+    pub fn qualify_name(&self, name: &str) -> String {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        match name.contains('.') {
+            true => name,
+            false => format!("{}.{}", name, self.dns_domain),
+        }
+    }
+
+    /// Name of the automatic mapping of the given user
+    pub fn user_mapping_name(&self, username: &str) -> String {
+        format!("{}.{}", username, self.dns_domain).to_ascii_lowercase()
+    }
+
+    pub async fn add_record(&mut self, mut record: DnsRecord) -> Result<DnsRecord> {
+        record.name = self.qualify_name(&record.name);
+        check_dns_name(&record.name)?;
+        self.records.retain(|existing| existing.name != record.name);
+        self.records.push(record.clone().into());
+        self.records.sort_by(|a, b| a.name.cmp(&b.name));
+        self.persist().await?;
+        self.write_custom_records().await?;
+        Ok(record)
+    }
+
+    pub async fn remove_record(&mut self, name: &str) -> Result<()> {
+        let name = self.qualify_name(name);
+        let record_cnt = self.records.len();
+        self.records.retain(|record| record.name != name);
+        if self.records.len() == record_cnt {
+            return Err(anyhow!("No dns record with name {}", name));
+        }
+        self.persist().await?;
+        self.write_custom_records().await?;
+        Ok(())
+    }
+
+    pub fn records(&self) -> Vec<DnsRecord> {
+        self.records.iter().cloned().map(DnsRecord::from).collect()
+    }
+
+    /// All names currently resolvable, with their address.
+    /// Aliases of users without a mapping are skipped, just like in the hostfile.
+    fn entries(&self) -> impl Iterator<Item = (String, IpAddr)> + '_ {
+        let mappings = self.mappings.iter().flat_map(|(username, addresses)| {
+            let name = self.user_mapping_name(username);
+            addresses.iter().map(move |ip| (name.clone(), *ip))
+        });
+        let records = self.records.iter().flat_map(|record| {
+            let addresses = match &record.target {
+                StoredRecordTarget::Address(ip) => vec![*ip],
+                StoredRecordTarget::User(username) => self.mappings.get(username).cloned().unwrap_or_default(),
+            };
+            addresses.into_iter().map(|ip| (record.name.clone(), ip))
+        });
+        let server = self.server_ip.map(|ip| (self.server_name(), ip));
+        mappings.chain(records).chain(server)
+    }
+
+    /// Name of the lapas server itself, which dnsmasq answers with an `address=` entry
+    fn server_name(&self) -> String {
+        format!("lapas.{}", self.dns_domain)
+    }
+
+    /// Addresses of the given name.
+    /// Returns None if the name is neither within the lapas network's domain nor a custom record
+    /// (or is the server's name, but its address is not configured), and an empty list if it is
+    /// within the domain, but unknown.
+    pub fn resolve(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.server_ip.is_none() && name == self.server_name() {
+            return None;
+        }
+        let ips: Vec<_> = self.entries().filter(|(entry, _)| *entry == name).map(|(_, ip)| ip).collect();
+        let within_domain = name == self.dns_domain || name.ends_with(&format!(".{}", self.dns_domain));
+        match within_domain || !ips.is_empty() {
+            true => Some(ips),
+            false => None,
+        }
+    }
+
+    /// All names that resolve to the given address
+    pub fn reverse(&self, ip: IpAddr) -> Vec<String> {
+        let mut names: Vec<_> = self.entries().filter(|(_, entry)| *entry == ip).map(|(name, _)| name).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    async fn persist(&self) -> Result<()> {
+        write_atomic(&self.records_path, serde_json::to_string_pretty(&self.records)?.as_bytes()).await
+    }
+
+    /// Write all custom records into their hostfile.
+    /// dnsmasq's hostsdir does not support CNAMEs, so aliases of users are written with the
+    /// addresses of the user's current mapping, and skipped while the user has no mapping.
+    async fn write_custom_records(&self) -> Result<()> {
+        let mut hostfile = String::new();
+        for record in &self.records {
+            let addresses = match &record.target {
+                StoredRecordTarget::Address(ip) => std::slice::from_ref(ip),
+                StoredRecordTarget::User(username) => self.mappings.get(username).map(Vec::as_slice).unwrap_or_default(),
+            };
+            for ip in addresses {
+                hostfile.push_str(&format!("{} {}\n", ip, record.name));
+            }
+        }
+        // dnsmasq watches the hostsdir, it must never see a half-written file
+        write_atomic(&self.hosts_dir.join(CUSTOM_RECORDS_HOSTFILE), hostfile.as_bytes()).await
+    }
 }
@@ -0,0 +1,99 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lapas_api_proto::{GameServer, GameServerId, GameServerInfo, GuestId, LapasGuest};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Time after which a game server that did not send a heartbeat is considered gone
+const GAME_SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct RegisteredGameServer {
+    server: GameServer,
+    last_heartbeat: Instant,
+}
+impl RegisteredGameServer {
+    fn is_expired(&self) -> bool {
+        self.last_heartbeat.elapsed() > GAME_SERVER_TIMEOUT
+    }
+}
+
+/// Registry of the game servers hosted on the guests, used to browse for games to join
+pub(crate) struct GameServerRegistry {
+    next_id: Mutex<GameServerId>,
+    servers: Mutex<HashMap<GameServerId, RegisteredGameServer>>,
+}
+impl GameServerRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: Mutex::new(1),
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn register(&self, guest: LapasGuest, owner: Option<String>, info: GameServerInfo) -> Result<GameServerId> {
+        if info.game.is_empty() {
+            return Err(anyhow!("Game name must not be empty!"));
+        }
+        if info.port == 0 {
+            return Err(anyhow!("Invalid game server port: {}", info.port));
+        }
+        let mut servers = self.servers.lock().await;
+        // a restarted game server replaces its previous registration
+        servers.retain(|_, registered| registered.server.guest_id != guest.id || registered.server.info.port != info.port);
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            *next_id += 1;
+            *next_id - 1
+        };
+        servers.insert(id, RegisteredGameServer {
+            server: GameServer {
+                id,
+                guest_id: guest.id,
+                hostname: guest.info.hostname,
+                ip: guest.ip,
+                owner,
+                info,
+                registered_ts: Utc::now(),
+            },
+            last_heartbeat: Instant::now(),
+        });
+        Ok(id)
+    }
+
+    pub async fn heartbeat(&self, guest_id: GuestId, id: GameServerId, player_count: u32) -> Result<()> {
+        let mut servers = self.servers.lock().await;
+        match servers.get_mut(&id) {
+            Some(registered) if registered.server.guest_id == guest_id && !registered.is_expired() => {
+                registered.server.info.player_count = player_count;
+                registered.last_heartbeat = Instant::now();
+                Ok(())
+            }
+            _ => Err(anyhow!("Game server {} is not registered on this guest", id)),
+        }
+    }
+
+    pub async fn unregister(&self, guest_id: GuestId, id: GameServerId) -> Result<()> {
+        let mut servers = self.servers.lock().await;
+        match servers.get(&id) {
+            Some(registered) if registered.server.guest_id == guest_id => {
+                servers.remove(&id);
+                Ok(())
+            }
+            _ => Err(anyhow!("Game server {} is not registered on this guest", id)),
+        }
+    }
+
+    /// Drop all game servers hosted on the given guest
+    pub async fn remove_guest(&self, guest_id: GuestId) {
+        self.servers.lock().await.retain(|_, registered| registered.server.guest_id != guest_id);
+    }
+
+    pub async fn all(&self) -> Vec<GameServer> {
+        let mut servers = self.servers.lock().await;
+        servers.retain(|_, registered| !registered.is_expired());
+        let mut servers: Vec<_> = servers.values().map(|registered| registered.server.clone()).collect();
+        servers.sort_by_key(|server| server.id);
+        servers
+    }
+}
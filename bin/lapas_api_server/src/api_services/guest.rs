@@ -0,0 +1,91 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lapas_api_proto::{GuestControlId, GuestControlTicket, GuestId, GuestInfo, LapasGuest, RootGeneration};
+use tokio::sync::Mutex;
+
+/// Inventory of all guests that currently have a daemon connected to the server
+pub(crate) struct GuestRegistry {
+    next_id: Mutex<GuestId>,
+    guests: Mutex<HashMap<GuestId, LapasGuest>>,
+    next_control_id: Mutex<GuestControlId>,
+}
+impl GuestRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: Mutex::new(1),
+            guests: Mutex::new(HashMap::new()),
+            next_control_id: Mutex::new(1),
+        }
+    }
+
+    pub async fn register(&self, ip: IpAddr, info: GuestInfo) -> GuestId {
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            *next_id += 1;
+            *next_id - 1
+        };
+        let now = Utc::now();
+        self.guests.lock().await.insert(id, LapasGuest {
+            id,
+            ip,
+            info,
+            connect_ts: now,
+            last_ping_ts: now,
+        });
+        id
+    }
+
+    pub async fn unregister(&self, id: GuestId) {
+        self.guests.lock().await.remove(&id);
+    }
+
+    pub async fn ping(&self, id: GuestId) {
+        if let Some(guest) = self.guests.lock().await.get_mut(&id) {
+            guest.last_ping_ts = Utc::now();
+        }
+    }
+
+    /// Remember that the guest mounted the given generation of the guest root
+    pub async fn set_root_generation(&self, id: GuestId, generation: RootGeneration) {
+        if let Some(guest) = self.guests.lock().await.get_mut(&id) {
+            guest.info.root_generation = Some(generation);
+        }
+    }
+
+    pub async fn get(&self, id: GuestId) -> Option<LapasGuest> {
+        self.guests.lock().await.get(&id).cloned()
+    }
+
+    pub async fn all(&self) -> Vec<LapasGuest> {
+        let mut guests: Vec<_> = self.guests.lock().await.values().cloned().collect();
+        guests.sort_by_key(|guest| guest.id);
+        guests
+    }
+
+    pub async fn ids(&self) -> Vec<GuestId> {
+        let mut guests: Vec<_> = self.guests.lock().await.keys().copied().collect();
+        guests.sort();
+        guests
+    }
+
+    /// Make sure all of the given guests are connected
+    pub async fn check_connected(&self, guests: &[GuestId]) -> Result<()> {
+        let registered_guests = self.guests.lock().await;
+        match guests.iter().find(|id| !registered_guests.contains_key(id)) {
+            Some(unknown_guest) => Err(anyhow!("Guest {} is not connected", unknown_guest)),
+            None => Ok(()),
+        }
+    }
+
+    /// Assign an id to a control request for the given guests
+    pub async fn new_control_ticket(&self, guests: Vec<GuestId>) -> GuestControlTicket {
+        let id = {
+            let mut next_control_id = self.next_control_id.lock().await;
+            *next_control_id += 1;
+            *next_control_id - 1
+        };
+        GuestControlTicket { id, guests }
+    }
+}
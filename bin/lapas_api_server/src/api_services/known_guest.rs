@@ -0,0 +1,121 @@
+use std::{net::IpAddr, path::{Path, PathBuf}};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use lapas_api_proto::KnownGuest;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::api_services::{dhcp_leases::DhcpLease, write_atomic};
+
+/// Normalize a MAC address to lowercase, colon separated notation (e.g.: `52:54:00:ab:cd:ef`).
+/// Returns None if the given string is not a MAC address.
+pub(crate) fn normalize_mac(mac: &str) -> Option<String> {
+    let octets: Vec<_> = mac.split([':', '-']).collect();
+    let valid = octets.len() == 6 && octets.iter().all(|octet| octet.len() == 2 && u8::from_str_radix(octet, 16).is_ok());
+    match valid {
+        true => Some(octets.join(":").to_ascii_lowercase()),
+        false => None,
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredKnownGuest {
+    mac: String,
+    hostname: String,
+    ip: Option<IpAddr>,
+    last_seen_ts: DateTime<Utc>,
+}
+impl From<&StoredKnownGuest> for KnownGuest {
+    fn from(guest: &StoredKnownGuest) -> Self {
+        KnownGuest {
+            mac: guest.mac.clone(),
+            hostname: guest.hostname.clone(),
+            ip: guest.ip,
+            last_seen_ts: guest.last_seen_ts,
+            guest_id: None,
+            has_lease: false,
+        }
+    }
+}
+
+/// Every machine that was ever seen in the lapas network, so it can be woken up while it is powered off.
+/// Persisted in a json file (KNOWN_GUESTS) in the homes directory.
+pub(crate) struct KnownGuestRegistry {
+    path: PathBuf,
+    guests: Mutex<Vec<StoredKnownGuest>>,
+}
+impl KnownGuestRegistry {
+    pub async fn open(homes_dir: &Path) -> Result<Self> {
+        let path = homes_dir.join("KNOWN_GUESTS");
+        let guests = match path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?,
+            false => vec![],
+        };
+        Ok(Self { path, guests: Mutex::new(guests) })
+    }
+
+    async fn persist(&self, guests: &[StoredKnownGuest]) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string_pretty(guests)?.as_bytes()).await
+    }
+
+    /// Remember the machine a guest daemon registered from
+    pub async fn seen(&self, mac: &str, hostname: &str, ip: IpAddr) -> Result<()> {
+        let Some(mac) = normalize_mac(mac) else {
+            return Ok(()); // daemons that failed to determine their MAC address
+        };
+        let mut guests = self.guests.lock().await;
+        let guest = StoredKnownGuest { mac, hostname: hostname.to_owned(), ip: Some(ip), last_seen_ts: Utc::now() };
+        match guests.iter_mut().find(|known| known.mac == guest.mac) {
+            Some(known) => *known = guest,
+            None => guests.push(guest),
+        }
+        guests.sort_by(|a, b| a.hostname.cmp(&b.hostname).then(a.mac.cmp(&b.mac)));
+        self.persist(&guests).await
+    }
+
+    /// Learn about machines from their dhcp leases.
+    /// Leases outlive the machine being powered on, so they don't count as the machine being seen.
+    pub async fn merge_leases(&self, leases: &[DhcpLease]) -> Result<()> {
+        let mut guests = self.guests.lock().await;
+        let mut changed = false;
+        for lease in leases {
+            match guests.iter_mut().find(|known| known.mac == lease.mac) {
+                Some(known) => {
+                    let hostname = lease.hostname.as_ref().unwrap_or(&known.hostname);
+                    if known.ip != Some(lease.ip) || &known.hostname != hostname {
+                        known.hostname = hostname.clone();
+                        known.ip = Some(lease.ip);
+                        changed = true;
+                    }
+                },
+                None => {
+                    guests.push(StoredKnownGuest {
+                        mac: lease.mac.clone(),
+                        hostname: lease.hostname.clone().unwrap_or_default(),
+                        ip: Some(lease.ip),
+                        last_seen_ts: Utc::now(),
+                    });
+                    changed = true;
+                },
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        guests.sort_by(|a, b| a.hostname.cmp(&b.hostname).then(a.mac.cmp(&b.mac)));
+        self.persist(&guests).await
+    }
+
+    pub async fn all(&self) -> Vec<KnownGuest> {
+        self.guests.lock().await.iter().map(KnownGuest::from).collect()
+    }
+
+    /// Find a known guest by its hostname or MAC address
+    pub async fn find(&self, host: &str) -> Option<KnownGuest> {
+        let mac = normalize_mac(host);
+        self.guests.lock().await.iter()
+            .find(|guest| Some(&guest.mac) == mac.as_ref() || guest.hostname.eq_ignore_ascii_case(host))
+            .map(KnownGuest::from)
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use lapas_api_proto::MaintenanceMode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::api_services::write_atomic;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredMaintenanceMode {
+    message: String,
+    since_ts: DateTime<Utc>,
+}
+impl From<&StoredMaintenanceMode> for MaintenanceMode {
+    fn from(maintenance: &StoredMaintenanceMode) -> Self {
+        MaintenanceMode { message: maintenance.message.clone(), since_ts: maintenance.since_ts }
+    }
+}
+
+/// Server-wide maintenance mode, persisted in a json file (MAINTENANCE) in the homes directory,
+/// so a restart of the server in the middle of an update does not let players back in.
+pub(crate) struct MaintenanceService {
+    path: PathBuf,
+    maintenance: Mutex<Option<StoredMaintenanceMode>>,
+}
+impl MaintenanceService {
+    pub async fn open(homes_dir: &Path) -> Result<Self> {
+        let path = homes_dir.join("MAINTENANCE");
+        let maintenance = match path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?,
+            false => None,
+        };
+        Ok(Self { path, maintenance: Mutex::new(maintenance) })
+    }
+
+    async fn persist(&self, maintenance: &Option<StoredMaintenanceMode>) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string_pretty(maintenance)?.as_bytes()).await
+    }
+
+    /// Turn the maintenance mode on with the given message, or off (None).
+    /// Turning it on while it already is only replaces the message.
+    pub async fn set(&self, message: Option<String>) -> Result<Option<MaintenanceMode>> {
+        let mut maintenance = self.maintenance.lock().await;
+        let new_maintenance = message.map(|message| StoredMaintenanceMode {
+            message,
+            since_ts: maintenance.as_ref().map(|maintenance| maintenance.since_ts).unwrap_or_else(Utc::now),
+        });
+        self.persist(&new_maintenance).await?;
+        *maintenance = new_maintenance;
+        Ok(maintenance.as_ref().map(MaintenanceMode::from))
+    }
+
+    pub async fn get(&self) -> Option<MaintenanceMode> {
+        self.maintenance.lock().await.as_ref().map(MaintenanceMode::from)
+    }
+}
@@ -1,22 +1,95 @@
-use std::{ops::DerefMut as _, sync::Arc};
+use std::{fmt::Display, io::ErrorKind, net::{IpAddr, SocketAddr}, ops::DerefMut as _, path::Path, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use lapas_api_proto::{LapasProtocol, ProtoSerde as _};
-use tokio::{io::AsyncWriteExt as _, net::tcp::{OwnedReadHalf, OwnedWriteHalf}, sync::Mutex};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncRead, AsyncWrite, AsyncWriteExt as _}, sync::Mutex};
 
+pub mod boot_profile;
+pub mod chat;
+pub mod dhcp_leases;
 pub mod dns;
+pub mod game_server;
+pub mod guest;
+pub mod known_guest;
+pub mod maintenance;
 pub mod notification;
+pub mod root_generation;
+pub mod root_rollout;
+pub mod schedule;
+pub mod session;
 pub mod user;
+pub mod wake_on_lan;
 
+/// Replace the file at `path` with `data`.
+/// The data is written into a hidden temporary file next to it first (`.<name>.tmp`, which
+/// directory watchers like dnsmasq's hostsdir skip), so a crash never leaves a half-written
+/// file behind, and the directory is synced afterwards to make the rename durable.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_atomic_with_mode(path, data, 0o666).await
+}
+
+/// Like [write_atomic], but the file is created with the given mode (minus the umask), before
+/// any data is written into it.
+pub async fn write_atomic_with_mode(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let file_name = path.file_name().ok_or_else(|| anyhow!("Invalid path: {:?}", path))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    // the mode only applies to newly created files, so never reuse a leftover of a crash
+    if let Err(e) = tokio::fs::remove_file(&tmp_path).await {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(mode).open(&tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp_path, path).await?;
+    if let Some(dir) = path.parent() {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Address of a connected api client
+#[derive(Clone, Debug)]
+pub enum PeerAddr {
+    /// Client connected via tcp
+    Tcp(SocketAddr),
+    /// Local client connected via the unix socket, identified by its peer credentials
+    Unix { uid: u32, pid: Option<i32> },
+}
+impl PeerAddr {
+    /// Ip address of the client, if it is connected via network
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix { .. } => None,
+        }
+    }
+
+    /// Whether the client is root on the server itself, connected through the unix socket
+    pub fn is_local_root(&self) -> bool {
+        matches!(self, PeerAddr::Unix { uid: 0, .. })
+    }
+}
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr.ip()),
+            PeerAddr::Unix { uid, pid: Some(pid) } => write!(f, "local uid={} pid={}", uid, pid),
+            PeerAddr::Unix { uid, pid: None } => write!(f, "local uid={}", uid),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct PeerTx {
-    tx: Arc<Mutex<OwnedWriteHalf>>,
+    tx: Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>,
 }
 impl PeerTx {
-    pub fn new(tx: OwnedWriteHalf) -> Self {
+    pub fn new(tx: impl AsyncWrite + Send + Unpin + 'static) -> Self {
         Self {
-            tx: Arc::new(Mutex::new(tx)),
+            tx: Arc::new(Mutex::new(Box::new(tx))),
         }
     }
     pub async fn shutdown(&self) {
@@ -30,13 +103,13 @@ impl PeerTx {
 }
 
 pub struct PeerRx {
-    rx: OwnedReadHalf,
+    rx: Box<dyn AsyncRead + Send + Unpin>,
 }
 impl PeerRx {
-    pub fn new(rx: OwnedReadHalf) -> Self {
-        Self { rx }
+    pub fn new(rx: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self { rx: Box::new(rx) }
     }
     pub async fn recv(&mut self) -> Result<LapasProtocol> {
         Ok(LapasProtocol::decode(&mut self.rx).await?)
     }
-}
\ No newline at end of file
+}
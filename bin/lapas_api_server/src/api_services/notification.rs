@@ -1,35 +1,147 @@
-use std::time::Duration;
+use std::{collections::VecDeque, sync::{Arc, Mutex}, time::Duration};
 
-use lapas_api_proto::LapasProtocol;
-use tokio::{sync::{broadcast::{self, Receiver}}, time};
+use lapas_api_proto::{EventCursor, EventSeq, LapasProtocol};
+use tokio::{sync::watch, time};
 use anyhow::Result;
 
 use crate::api_services::PeerTx;
 
+/// Amount of past events kept for listeners that are behind, or resume after reconnecting
+const EVENT_LOG_CAPACITY: usize = 1024;
+
+/// Listeners an event is delivered to
+#[derive(Clone, Debug)]
+pub(crate) enum EventRecipients {
+    All,
+    /// Only the listeners of the guests with the given boot ids. Unlike their GuestId, a guest's
+    /// boot id stays the same when its daemon reconnects, so it still receives the events it missed.
+    Guests(Vec<String>),
+}
+impl EventRecipients {
+    fn includes(&self, boot_id: Option<&str>) -> bool {
+        match self {
+            EventRecipients::All => true,
+            EventRecipients::Guests(guests) => boot_id.is_some_and(|boot_id| guests.iter().any(|guest| guest == boot_id)),
+        }
+    }
+}
+
+struct LoggedEvent {
+    seq: EventSeq,
+    recipients: EventRecipients,
+    event: LapasProtocol,
+}
+
+struct EventLog {
+    epoch: u64,
+    next_seq: EventSeq,
+    events: VecDeque<LoggedEvent>,
+}
+impl EventLog {
+    fn new(epoch: u64) -> Self {
+        Self { epoch, next_seq: 1, events: VecDeque::new() }
+    }
+
+    /// Append an event, dropping the oldest one if the log is full. Returns the event's sequence number.
+    fn push(&mut self, recipients: EventRecipients, event: LapasProtocol) -> EventSeq {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.events.push_back(LoggedEvent { seq, recipients, event });
+        if self.events.len() > EVENT_LOG_CAPACITY {
+            self.events.pop_front();
+        }
+        seq
+    }
+
+    fn oldest_seq(&self) -> EventSeq {
+        self.events.front().map(|logged| logged.seq).unwrap_or(self.next_seq)
+    }
+
+    /// Sequence number of the first event to deliver to a listener resuming from the given cursor
+    fn resume_seq(&self, resume_from: Option<EventCursor>) -> EventSeq {
+        match resume_from {
+            Some(cursor) if cursor.epoch == self.epoch => cursor.seq + 1,
+            // events of another server instance can't be replayed, which is detected as lost events
+            Some(_) => 0,
+            None => self.next_seq,
+        }
+    }
+
+    /// All events starting at seq that are meant for the guest with the given boot id, and the sequence
+    /// number of the latest event in the log. None if some of the events were already dropped from the log.
+    fn since(&self, seq: EventSeq, boot_id: Option<&str>) -> Option<(Vec<LapasProtocol>, EventSeq)> {
+        if seq < self.oldest_seq() {
+            return None;
+        }
+        let events = self.events.iter()
+            .filter(|logged| logged.seq >= seq && logged.recipients.includes(boot_id))
+            .map(|logged| logged.event.clone())
+            .collect();
+        Some((events, self.next_seq - 1))
+    }
+}
+
+/// Delivers events to the registered listeners.
+/// Events are kept in a bounded log, from which every listener is served at its own pace,
+/// starting at its own position (sequence number). Senders never wait for slow listeners, so
+/// delivery is only lossless as long as a listener is at most EVENT_LOG_CAPACITY events behind
+/// (including the time its daemon needs to reconnect). Listeners that fall behind further are
+/// told that they lost events instead of being dropped, and have to resynchronize their state.
 pub(crate) struct NotificationService {
-    notifier: broadcast::Sender<LapasProtocol>
+    log: Arc<Mutex<EventLog>>,
+    /// Sequence number of the latest event, to wake up the listeners
+    latest_seq: watch::Sender<EventSeq>,
 }
 impl NotificationService {
     pub fn new() -> Self {
-        let (notifier, _) = broadcast::channel(1);
-        Self { notifier }
+        Self {
+            log: Arc::new(Mutex::new(EventLog::new(rand::random()))),
+            latest_seq: watch::Sender::new(0),
+        }
     }
 
     pub fn send(&self, notification: LapasProtocol) {
-        println!("Sending Notification: {:?}", notification);
-        let _ = self.notifier.send(notification);
+        self.send_to(EventRecipients::All, notification);
     }
 
-    pub async fn add(&self, client_tx: PeerTx) {
-        let notify_rx = self.notifier.subscribe();
+    pub fn send_to(&self, recipients: EventRecipients, notification: LapasProtocol) {
+        println!("Sending Notification to {:?}: {:?}", recipients, notification);
+        let seq = self.log.lock().expect("Event log lock poisoned").push(recipients, notification);
+        self.latest_seq.send_replace(seq);
+    }
+
+    /// Register a listener, which receives all events addressed to everyone, or to the guest with the given boot id
+    pub async fn add(&self, client_tx: PeerTx, boot_id: Option<String>, resume_from: Option<EventCursor>) {
+        let (epoch, next_seq) = {
+            let log = self.log.lock().expect("Event log lock poisoned");
+            (log.epoch, log.resume_seq(resume_from))
+        };
 
-        async fn forward_notifications(client_tx: PeerTx, mut notify_rx: Receiver<LapasProtocol>) -> Result<()> {
+        async fn forward_notifications(
+            client_tx: PeerTx, log: Arc<Mutex<EventLog>>, mut latest_seq: watch::Receiver<EventSeq>,
+            boot_id: Option<String>, epoch: u64, mut next_seq: EventSeq
+        ) -> Result<()> {
             loop {
+                latest_seq.borrow_and_update();
+                let events = log.lock().expect("Event log lock poisoned").since(next_seq, boot_id.as_deref());
+                match events {
+                    // the cursor also moves past the events meant for other guests
+                    Some((events, seq)) if seq >= next_seq => {
+                        for event in events {
+                            client_tx.send(event).await?;
+                        }
+                        client_tx.send(LapasProtocol::ControlEventSeq { cursor: EventCursor { epoch, seq } }).await?;
+                        next_seq = seq + 1;
+                    }
+                    Some(_) => {}
+                    None => {
+                        next_seq = log.lock().expect("Event log lock poisoned").next_seq;
+                        client_tx.send(LapasProtocol::NotifyEventsLost).await?;
+                        client_tx.send(LapasProtocol::ControlEventSeq { cursor: EventCursor { epoch, seq: next_seq - 1 } }).await?;
+                    }
+                }
                 tokio::select! {
-                    notification = notify_rx.recv() => {
-                        let notification = notification?;
-                        client_tx.send(notification).await?;
-                    },
+                    changed = latest_seq.changed() => changed?,
                     _ = time::sleep(Duration::from_millis(1000)) => {
                         client_tx.send(LapasProtocol::ControlPing).await?;
                     }
@@ -37,6 +149,71 @@ impl NotificationService {
             }
         }
 
-        tokio::spawn(forward_notifications(client_tx, notify_rx));
+        tokio::spawn(forward_notifications(client_tx, self.log.clone(), self.latest_seq.subscribe(), boot_id, epoch, next_seq));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPOCH: u64 = 42;
+
+    fn generations(events: Vec<LapasProtocol>) -> Vec<u64> {
+        events.into_iter()
+            .map(|event| match event {
+                LapasProtocol::NotifyRootChanged { generation } => generation,
+                _ => panic!("Unexpected event"),
+            })
+            .collect()
+    }
+
+    fn log_with(event_count: u64) -> EventLog {
+        let mut log = EventLog::new(EPOCH);
+        for generation in 1..=event_count {
+            log.push(EventRecipients::All, LapasProtocol::NotifyRootChanged { generation });
+        }
+        log
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn resumes_after_cursor() {
+        let log = log_with(5);
+        let seq = log.resume_seq(Some(EventCursor { epoch: EPOCH, seq: 3 }));
+        let (events, latest) = log.since(seq, None).unwrap();
+        assert_eq!(generations(events), vec![4, 5]);
+        assert_eq!(latest, 5);
+    }
+
+    #[test]
+    fn new_listeners_start_after_latest_event() {
+        let log = log_with(5);
+        let (events, latest) = log.since(log.resume_seq(None), None).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(latest, 5);
+    }
+
+    #[test]
+    fn detects_overflowed_events() {
+        let log = log_with(EVENT_LOG_CAPACITY as u64 + 3);
+        assert_eq!(log.oldest_seq(), 4);
+        assert!(log.since(log.resume_seq(Some(EventCursor { epoch: EPOCH, seq: 2 })), None).is_none());
+        let (events, _) = log.since(log.resume_seq(Some(EventCursor { epoch: EPOCH, seq: 3 })), None).unwrap();
+        assert_eq!(events.len(), EVENT_LOG_CAPACITY);
+    }
+
+    #[test]
+    fn detects_cursor_of_other_server_instance() {
+        let log = log_with(5);
+        assert!(log.since(log.resume_seq(Some(EventCursor { epoch: EPOCH + 1, seq: 5 })), None).is_none());
+    }
+
+    #[test]
+    fn delivers_targeted_events_by_boot_id() {
+        let mut log = log_with(1);
+        log.push(EventRecipients::Guests(vec!["a".to_owned()]), LapasProtocol::NotifyRootChanged { generation: 2 });
+        log.push(EventRecipients::Guests(vec!["b".to_owned()]), LapasProtocol::NotifyRootChanged { generation: 3 });
+        assert_eq!(generations(log.since(1, Some("a")).unwrap().0), vec![1, 2]);
+        assert_eq!(generations(log.since(1, None).unwrap().0), vec![1]);
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use lapas_api_proto::RootGeneration;
+use tokio::sync::Mutex;
+
+use crate::api_services::write_atomic;
+
+/// Generation of the guest root image, persisted in a json file (ROOT_GENERATION) in the homes directory.
+/// It is increased every time the administrator reports a change of the guest root, so guests
+/// that did not remount their root since can be told apart from the ones that did.
+pub(crate) struct RootGenerationCounter {
+    path: PathBuf,
+    generation: Mutex<RootGeneration>,
+}
+impl RootGenerationCounter {
+    pub async fn open(homes_dir: &Path) -> Result<Self> {
+        let path = homes_dir.join("ROOT_GENERATION");
+        let generation = match path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?,
+            false => 0,
+        };
+        Ok(Self { path, generation: Mutex::new(generation) })
+    }
+
+    async fn persist(&self, generation: RootGeneration) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string(&generation)?.as_bytes()).await
+    }
+
+    pub async fn current(&self) -> RootGeneration {
+        *self.generation.lock().await
+    }
+
+    /// Advance to the next generation, returning it
+    pub async fn bump(&self) -> Result<RootGeneration> {
+        let mut generation = self.generation.lock().await;
+        self.persist(*generation + 1).await?;
+        *generation += 1;
+        Ok(*generation)
+    }
+}
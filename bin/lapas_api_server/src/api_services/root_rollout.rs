@@ -0,0 +1,327 @@
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use anyhow::{anyhow, Result};
+use lapas_api_proto::{GuestId, LapasGuest, RootGeneration, RootRemountFailure, RootRollout, RootRolloutState};
+use tokio::{sync::{Mutex, Notify}, time::Instant};
+
+use crate::config::ServerConfig;
+
+struct Rollout {
+    generation: RootGeneration,
+    state: RootRolloutState,
+    /// Guests of the current wave that did not report back yet, with their hostname
+    wave: HashMap<GuestId, String>,
+    /// Point in time the guests of the current wave time out
+    wave_deadline: Instant,
+    /// Point in time the next wave is started
+    next_wave_ts: Instant,
+    /// Guests that were asked to remount their root during this rollout
+    attempted: HashSet<GuestId>,
+    remounted: Vec<GuestId>,
+    failures: Vec<RootRemountFailure>,
+    /// Failures since the rollout was started or resumed
+    recent_failures: usize,
+}
+impl Rollout {
+    fn status(&self) -> RootRollout {
+        let mut pending: Vec<_> = self.wave.keys().copied().collect();
+        pending.sort();
+        RootRollout {
+            generation: self.generation,
+            state: self.state,
+            pending,
+            remounted: self.remounted.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+
+    fn fail(&mut self, guest_id: GuestId, hostname: String, error: String) {
+        self.failures.push(RootRemountFailure { guest_id, hostname, error });
+        self.recent_failures += 1;
+    }
+}
+
+/// Step taken by the rollout of a root change
+pub(crate) struct RootRolloutStep {
+    /// Guests to ask for a remount of the given generation now
+    pub wave: Vec<GuestId>,
+    pub generation: RootGeneration,
+    /// Progress of the rollout, if it changed
+    pub progress: Option<RootRollout>,
+    /// Point in time the rollout needs to be advanced again, None if that only happens after a change
+    pub next_due: Option<Instant>,
+}
+
+/// Rolls the remount of a changed guest root out to the guests in waves, so they don't hit the
+/// storage all at once. Every wave waits for its guests to report back (or time out), and the
+/// rollout is paused once more guests failed than the configuration tolerates.
+pub(crate) struct RootRolloutService {
+    rollout: Mutex<Option<Rollout>>,
+    /// Signaled whenever the rollout changed, so the next step is recalculated
+    changed: Notify,
+}
+impl RootRolloutService {
+    pub fn new() -> Self {
+        Self { rollout: Mutex::new(None), changed: Notify::new() }
+    }
+
+    /// Start rolling out the given generation, replacing the rollout of a previous generation
+    pub async fn start(&self, generation: RootGeneration) {
+        let now = Instant::now();
+        *self.rollout.lock().await = Some(Rollout {
+            generation,
+            state: RootRolloutState::Running,
+            wave: HashMap::new(),
+            wave_deadline: now,
+            next_wave_ts: now,
+            attempted: HashSet::new(),
+            remounted: vec![],
+            failures: vec![],
+            recent_failures: 0,
+        });
+        self.changed.notify_one();
+    }
+
+    /// Continue a paused rollout, retrying the guests that failed so far
+    pub async fn resume(&self) -> Result<RootRollout> {
+        let mut rollout = self.rollout.lock().await;
+        let rollout = rollout.as_mut()
+            .filter(|rollout| rollout.state == RootRolloutState::Paused)
+            .ok_or_else(|| anyhow!("No paused rollout"))?;
+        for failure in std::mem::take(&mut rollout.failures) {
+            rollout.attempted.remove(&failure.guest_id);
+        }
+        rollout.state = RootRolloutState::Running;
+        rollout.recent_failures = 0;
+        rollout.next_wave_ts = Instant::now();
+        self.changed.notify_one();
+        Ok(rollout.status())
+    }
+
+    /// Record the result of a guest's remount. Returns the progress of the rollout, if it changed.
+    pub async fn remount_result(&self, guest_id: GuestId, generation: RootGeneration, result: Result<(), String>, wave_interval: Duration) -> Option<RootRollout> {
+        let mut rollout = self.rollout.lock().await;
+        let rollout = rollout.as_mut().filter(|rollout| rollout.generation == generation)?;
+        let hostname = rollout.wave.remove(&guest_id)?;
+        match result {
+            Ok(()) => rollout.remounted.push(guest_id),
+            Err(e) => rollout.fail(guest_id, hostname, e),
+        }
+        if rollout.wave.is_empty() {
+            rollout.next_wave_ts = Instant::now() + wave_interval;
+            self.changed.notify_one();
+        }
+        Some(rollout.status())
+    }
+
+    /// Time out unresponsive guests, pause the rollout if too many guests failed, and
+    /// pick the next wave of the given connected guests when it is due.
+    pub async fn advance(&self, guests: &[LapasGuest], config: &ServerConfig) -> Option<RootRolloutStep> {
+        let now = Instant::now();
+        let mut rollout = self.rollout.lock().await;
+        let rollout = rollout.as_mut().filter(|rollout| rollout.state == RootRolloutState::Running)?;
+        let mut step = RootRolloutStep { wave: vec![], generation: rollout.generation, progress: None, next_due: None };
+
+        if !rollout.wave.is_empty() {
+            if now < rollout.wave_deadline {
+                step.next_due = Some(rollout.wave_deadline);
+                return Some(step);
+            }
+            for (guest_id, hostname) in std::mem::take(&mut rollout.wave) {
+                rollout.fail(guest_id, hostname, format!("No response within {}s", config.remount_timeout_secs));
+            }
+            rollout.next_wave_ts = now + Duration::from_secs(config.remount_wave_interval_secs);
+            step.progress = Some(rollout.status());
+        }
+        if rollout.recent_failures > config.remount_max_failures {
+            rollout.state = RootRolloutState::Paused;
+            step.progress = Some(rollout.status());
+            return Some(step);
+        }
+        if now < rollout.next_wave_ts {
+            step.next_due = Some(rollout.next_wave_ts);
+            return Some(step);
+        }
+
+        // guests that connected in the meantime with an outdated root are picked up as well
+        let wave: Vec<_> = guests.iter()
+            .filter(|guest| guest.info.root_generation != Some(rollout.generation) && !rollout.attempted.contains(&guest.id))
+            .take(config.remount_wave_size)
+            .collect();
+        if wave.is_empty() {
+            rollout.state = RootRolloutState::Finished;
+            step.progress = Some(rollout.status());
+            return Some(step);
+        }
+        for guest in wave {
+            rollout.attempted.insert(guest.id);
+            rollout.wave.insert(guest.id, guest.info.hostname.clone());
+            step.wave.push(guest.id);
+        }
+        rollout.wave_deadline = now + Duration::from_secs(config.remount_timeout_secs);
+        step.next_due = Some(rollout.wave_deadline);
+        step.progress = Some(rollout.status());
+        Some(step)
+    }
+
+    pub async fn status(&self) -> Option<RootRollout> {
+        self.rollout.lock().await.as_ref().map(Rollout::status)
+    }
+
+    /// Wait until the rollout changed
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, path::PathBuf};
+
+    use chrono::Utc;
+    use lapas_api_proto::{GuestBootMode, GuestInfo};
+
+    use super::*;
+    use crate::api_services::user::UserStorageKind;
+
+    const GENERATION: RootGeneration = 2;
+
+    fn config(wave_size: usize, timeout_secs: u64, max_failures: usize) -> ServerConfig {
+        ServerConfig {
+            homes_dir: PathBuf::new(),
+            net_domain: "lan".to_owned(),
+            net_ip: None,
+            dns_hostmappings_dir: PathBuf::new(),
+            tftp_dir: PathBuf::new(),
+            password_salt: String::new(),
+            password_hash: String::new(),
+            user_storage: UserStorageKind::Json,
+            api_listen: vec![],
+            dns_listen: vec![],
+            dns_upstream: (Ipv4Addr::LOCALHOST, 53).into(),
+            dnsmasq_leases: PathBuf::new(),
+            remount_wave_size: wave_size,
+            remount_wave_interval_secs: 0,
+            remount_timeout_secs: timeout_secs,
+            remount_max_failures: max_failures,
+        }
+    }
+
+    fn guest(id: GuestId, root_generation: RootGeneration) -> LapasGuest {
+        LapasGuest {
+            id,
+            ip: Ipv4Addr::new(192, 168, 42, id as u8).into(),
+            info: GuestInfo {
+                mac: String::new(),
+                hostname: format!("guest{}", id),
+                daemon_version: String::new(),
+                boot_mode: GuestBootMode::User,
+                addresses: vec![],
+                root_generation: Some(root_generation),
+                boot_id: String::new(),
+            },
+            connect_ts: Utc::now(),
+            last_ping_ts: Utc::now(),
+        }
+    }
+
+    fn failed(progress: &RootRollout) -> Vec<GuestId> {
+        progress.failures.iter().map(|failure| failure.guest_id).collect()
+    }
+
+    #[tokio::test]
+    async fn rolls_out_in_waves() {
+        let config = config(2, 60, 0);
+        let guests = vec![guest(1, 1), guest(2, GENERATION), guest(3, 1), guest(4, 1), guest(5, 1), guest(6, 1)];
+        let rollouts = RootRolloutService::new();
+        rollouts.start(GENERATION).await;
+
+        let step = rollouts.advance(&guests, &config).await.unwrap();
+        assert_eq!(step.wave, vec![1, 3]);
+        assert_eq!(step.generation, GENERATION);
+        assert_eq!(step.progress.unwrap().pending, vec![1, 3]);
+        // the next wave waits for the current one
+        let step = rollouts.advance(&guests, &config).await.unwrap();
+        assert!(step.wave.is_empty());
+        assert!(step.progress.is_none());
+        assert!(step.next_due.is_some());
+
+        for guest_id in [1, 3] {
+            rollouts.remount_result(guest_id, GENERATION, Ok(()), Duration::ZERO).await.unwrap();
+        }
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![4, 5]);
+        for guest_id in [4, 5] {
+            rollouts.remount_result(guest_id, GENERATION, Ok(()), Duration::ZERO).await.unwrap();
+        }
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![6]);
+        rollouts.remount_result(6, GENERATION, Ok(()), Duration::ZERO).await.unwrap();
+
+        let progress = rollouts.advance(&guests, &config).await.unwrap().progress.unwrap();
+        assert_eq!(progress.state, RootRolloutState::Finished);
+        assert_eq!(progress.remounted, vec![1, 3, 4, 5, 6]);
+        assert!(progress.failures.is_empty());
+        assert!(rollouts.advance(&guests, &config).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unresponsive_guests_fail() {
+        let config = config(2, 0, 10);
+        let guests = vec![guest(1, 1), guest(2, 1), guest(3, 1)];
+        let rollouts = RootRolloutService::new();
+        rollouts.start(GENERATION).await;
+
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![1, 2]);
+        // the timed out wave is failed, and the next one started right away
+        let step = rollouts.advance(&guests, &config).await.unwrap();
+        assert_eq!(step.wave, vec![3]);
+        let mut failed = failed(step.progress.as_ref().unwrap());
+        failed.sort();
+        assert_eq!(failed, vec![1, 2]);
+        // late results of timed out guests are ignored
+        assert!(rollouts.remount_result(1, GENERATION, Ok(()), Duration::ZERO).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pauses_after_too_many_failures() {
+        let config = config(3, 60, 1);
+        let guests = vec![guest(1, 1), guest(2, 1), guest(3, 1), guest(4, 1)];
+        let rollouts = RootRolloutService::new();
+        rollouts.start(GENERATION).await;
+
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![1, 2, 3]);
+        rollouts.remount_result(1, GENERATION, Err("busy".to_owned()), Duration::ZERO).await.unwrap();
+        rollouts.remount_result(2, GENERATION, Ok(()), Duration::ZERO).await.unwrap();
+        // a single failure is tolerated
+        let step = rollouts.advance(&guests, &config).await.unwrap();
+        assert!(step.wave.is_empty());
+        rollouts.remount_result(3, GENERATION, Err("busy".to_owned()), Duration::ZERO).await.unwrap();
+
+        let step = rollouts.advance(&guests, &config).await.unwrap();
+        assert!(step.wave.is_empty());
+        let progress = step.progress.unwrap();
+        assert_eq!(progress.state, RootRolloutState::Paused);
+        assert_eq!(failed(&progress), vec![1, 3]);
+        assert!(rollouts.advance(&guests, &config).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn resume_retries_failed_guests() {
+        let config = config(2, 60, 0);
+        let guests = vec![guest(1, 1), guest(2, 1), guest(3, 1)];
+        let rollouts = RootRolloutService::new();
+        assert!(rollouts.resume().await.is_err());
+        rollouts.start(GENERATION).await;
+
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![1, 2]);
+        rollouts.remount_result(1, GENERATION, Ok(()), Duration::ZERO).await.unwrap();
+        rollouts.remount_result(2, GENERATION, Err("busy".to_owned()), Duration::ZERO).await.unwrap();
+        let progress = rollouts.advance(&guests, &config).await.unwrap().progress.unwrap();
+        assert_eq!(progress.state, RootRolloutState::Paused);
+
+        let progress = rollouts.resume().await.unwrap();
+        assert_eq!(progress.state, RootRolloutState::Running);
+        assert!(progress.failures.is_empty());
+        // guests that remounted successfully are not asked again
+        assert_eq!(rollouts.advance(&guests, &config).await.unwrap().wave, vec![2, 3]);
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Local, Utc};
+use lapas_api_proto::{AnnouncementUrgency, ScheduledEvent, ScheduledEventId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::api_services::write_atomic;
+
+/// Reminders that were missed (e.g. because the server was down) are only announced
+/// if they are not older than this.
+const MISSED_REMINDER_GRACE: Duration = Duration::minutes(5);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ScheduleEntry {
+    id: ScheduledEventId,
+    title: String,
+    description: String,
+    ts: DateTime<Utc>,
+    reminder_offsets_mins: Vec<u32>,
+    /// Offsets of the reminders that were already handled
+    sent_reminders: Vec<u32>,
+}
+impl ScheduleEntry {
+    fn reminder_ts(&self, offset_mins: u32) -> DateTime<Utc> {
+        self.ts - Duration::minutes(offset_mins.into())
+    }
+
+    fn pending_reminders(&self) -> impl Iterator<Item = u32> + '_ {
+        self.reminder_offsets_mins.iter().copied()
+            .filter(|offset| !self.sent_reminders.contains(offset))
+    }
+
+    fn reminder(&self, offset_mins: u32) -> ScheduledAnnouncement {
+        let starts_at = self.ts.with_timezone(&Local).format("%H:%M");
+        let body = match offset_mins {
+            0 => format!("Starting now\n{}", self.description),
+            _ => format!("Starts at {} (in {} min)\n{}", starts_at, offset_mins, self.description),
+        };
+        ScheduledAnnouncement {
+            title: self.title.clone(),
+            body: body.trim_end().to_owned(),
+            urgency: match offset_mins {
+                0 => AnnouncementUrgency::Critical,
+                _ => AnnouncementUrgency::Normal,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Schedule {
+    next_id: ScheduledEventId,
+    events: Vec<ScheduleEntry>,
+}
+impl Default for Schedule {
+    fn default() -> Self {
+        Self { next_id: 1, events: vec![] }
+    }
+}
+
+/// Announcement of a scheduled event that is due to be sent to the guests
+pub(crate) struct ScheduledAnnouncement {
+    pub title: String,
+    pub body: String,
+    pub urgency: AnnouncementUrgency,
+}
+
+/// Schedule of timed events, persisted in a json file (SCHEDULE) in the homes directory
+pub(crate) struct ScheduleService {
+    schedule_path: PathBuf,
+    schedule: Mutex<Schedule>,
+    /// Signaled whenever the schedule changed, so the next due reminder is recalculated
+    changed: Notify,
+}
+impl ScheduleService {
+    pub async fn open(homes_dir: &Path) -> Result<Self> {
+        let schedule_path = homes_dir.join("SCHEDULE");
+        let schedule = match schedule_path.exists() {
+            true => serde_json::from_str(&tokio::fs::read_to_string(&schedule_path).await?)?,
+            false => Schedule::default(),
+        };
+        Ok(Self {
+            schedule_path,
+            schedule: Mutex::new(schedule),
+            changed: Notify::new(),
+        })
+    }
+
+    async fn persist(&self, schedule: &Schedule) -> Result<()> {
+        write_atomic(&self.schedule_path, serde_json::to_string_pretty(schedule)?.as_bytes()).await
+    }
+
+    pub async fn add(&self, title: String, description: String, ts: DateTime<Utc>, mut reminder_offsets_mins: Vec<u32>) -> Result<ScheduledEventId> {
+        if title.is_empty() {
+            return Err(anyhow!("Event title must not be empty!"));
+        }
+        if ts <= Utc::now() {
+            return Err(anyhow!("Event must be in the future!"));
+        }
+        reminder_offsets_mins.sort_unstable_by(|a, b| b.cmp(a));
+        reminder_offsets_mins.dedup();
+
+        let mut schedule = self.schedule.lock().await;
+        let id = schedule.next_id;
+        schedule.next_id += 1;
+        // reminders that would have been due before the event was added are not sent
+        let sent_reminders = reminder_offsets_mins.iter().copied()
+            .filter(|offset| ts - Duration::minutes((*offset).into()) <= Utc::now())
+            .collect();
+        schedule.events.push(ScheduleEntry { id, title, description, ts, reminder_offsets_mins, sent_reminders });
+        schedule.events.sort_by_key(|event| event.ts);
+        self.persist(&schedule).await?;
+        self.changed.notify_one();
+        Ok(id)
+    }
+
+    pub async fn remove(&self, id: ScheduledEventId) -> Result<()> {
+        let mut schedule = self.schedule.lock().await;
+        let event_cnt = schedule.events.len();
+        schedule.events.retain(|event| event.id != id);
+        if schedule.events.len() == event_cnt {
+            return Err(anyhow!("No scheduled event with id {}", id));
+        }
+        self.persist(&schedule).await?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    pub async fn all(&self) -> Vec<ScheduledEvent> {
+        self.schedule.lock().await.events.iter()
+            .map(|event| ScheduledEvent {
+                id: event.id,
+                title: event.title.clone(),
+                description: event.description.clone(),
+                ts: event.ts,
+                reminder_offsets_mins: event.reminder_offsets_mins.clone(),
+            })
+            .collect()
+    }
+
+    /// Collect all reminders that are due, and drop events that already started.
+    /// Returns the due announcements, and the point in time at which the schedule needs attention next.
+    pub async fn take_due(&self) -> Result<(Vec<ScheduledAnnouncement>, Option<DateTime<Utc>>)> {
+        let now = Utc::now();
+        let mut schedule = self.schedule.lock().await;
+        let mut announcements = vec![];
+        let mut changed = false;
+        for event in schedule.events.iter_mut() {
+            let due_reminders: Vec<_> = event.pending_reminders()
+                .filter(|offset| event.reminder_ts(*offset) <= now)
+                .collect();
+            // of multiple due reminders, only the most recent one is worth announcing
+            if let Some(offset) = due_reminders.iter().copied().min() {
+                if now - event.reminder_ts(offset) <= MISSED_REMINDER_GRACE {
+                    announcements.push(event.reminder(offset));
+                }
+            }
+            changed |= !due_reminders.is_empty();
+            event.sent_reminders.extend(due_reminders);
+        }
+        let event_cnt = schedule.events.len();
+        schedule.events.retain(|event| event.ts > now);
+        changed |= schedule.events.len() != event_cnt;
+        if changed {
+            self.persist(&schedule).await?;
+        }
+
+        let next_due = schedule.events.iter()
+            .flat_map(|event| event.pending_reminders().map(|offset| event.reminder_ts(offset)).chain([event.ts]))
+            .min();
+        Ok((announcements, next_due))
+    }
+
+    /// Wait until the schedule was changed
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
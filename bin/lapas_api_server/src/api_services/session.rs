@@ -0,0 +1,133 @@
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use anyhow::{anyhow, Result};
+use lapas_api_proto::{GuestId, LapasGuest};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Time after which a home lease that was not renewed by its guest's daemon is considered stale
+const HOME_LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exclusive right of a guest to mount a user's home image
+struct HomeLease {
+    holder: LapasGuest,
+    renewed: Instant,
+}
+impl HomeLease {
+    fn is_expired(&self) -> bool {
+        self.renewed.elapsed() > HOME_LEASE_TIMEOUT
+    }
+}
+
+/// Keeps track of which guest a user is logged in on.
+/// Home images are mounted read-write, so only one guest at a time may hold a user's home.
+pub(crate) struct SessionService {
+    leases: Mutex<HashMap<String, HomeLease>>,
+    /// Users whose home image is currently modified offline, and must not be mounted
+    locked_homes: Mutex<HashSet<String>>,
+    /// Boot ids of the machines that may still have a user's home mounted. A guest's daemon only
+    /// ends a session once it unmounted the home, so these are only left behind by sessions that
+    /// never ended cleanly (e.g. crashed daemons, or leases taken over after they expired).
+    mounted_homes: Mutex<HashMap<String, HashSet<String>>>,
+}
+impl SessionService {
+    pub fn new() -> Self {
+        Self {
+            leases: Mutex::new(HashMap::new()),
+            locked_homes: Mutex::new(HashSet::new()),
+            mounted_homes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn begin(&self, username: String, guest: LapasGuest) -> Result<()> {
+        let mut leases = self.leases.lock().await;
+        if self.locked_homes.lock().await.contains(&username) {
+            return Err(anyhow!("Home of {} is being resized, try again later", username));
+        }
+        if let Some(lease) = leases.get(&username) {
+            // a guest with the same ip is the same machine, whose daemon reconnected in the meantime
+            let same_guest = lease.holder.id == guest.id || lease.holder.ip == guest.ip;
+            if !same_guest && !lease.is_expired() {
+                return Err(anyhow!("User {} is already logged in on {} ({}). Log out there first.",
+                    username, lease.holder.info.hostname, lease.holder.ip));
+            }
+        }
+        if !guest.info.boot_id.is_empty() {
+            self.mounted_homes.lock().await.entry(username.clone()).or_default().insert(guest.info.boot_id.clone());
+        }
+        leases.insert(username, HomeLease { holder: guest, renewed: Instant::now() });
+        Ok(())
+    }
+
+    pub async fn end(&self, username: &str, guest_id: GuestId) -> Result<()> {
+        let mut leases = self.leases.lock().await;
+        match leases.get(username) {
+            Some(lease) if lease.holder.id == guest_id => {
+                let boot_id = &lease.holder.info.boot_id;
+                let mut mounted_homes = self.mounted_homes.lock().await;
+                if let Some(boot_ids) = mounted_homes.get_mut(username) {
+                    boot_ids.remove(boot_id);
+                    if boot_ids.is_empty() {
+                        mounted_homes.remove(username);
+                    }
+                }
+                leases.remove(username);
+                Ok(())
+            }
+            _ => Err(anyhow!("User {} has no session on this guest", username)),
+        }
+    }
+
+    /// Guest the given user is currently logged in on
+    pub async fn guest_of(&self, username: &str) -> Option<GuestId> {
+        self.leases.lock().await.get(username)
+            .filter(|lease| !lease.is_expired())
+            .map(|lease| lease.holder.id)
+    }
+
+    /// Users whose home is leased to the given guest
+    pub async fn users_on(&self, guest_id: GuestId) -> Vec<String> {
+        self.leases.lock().await.iter()
+            .filter(|(_, lease)| lease.holder.id == guest_id)
+            .map(|(username, _)| username.clone())
+            .collect()
+    }
+
+    /// Renew all leases held by the given guest
+    pub async fn renew(&self, guest_id: GuestId) {
+        let now = Instant::now();
+        for lease in self.leases.lock().await.values_mut() {
+            if lease.holder.id == guest_id {
+                lease.renewed = now;
+            }
+        }
+    }
+
+    /// Keep the given user from logging in, while their home image is modified offline.
+    /// Fails if the user's home is already locked, leased to one of the given connected guests, or
+    /// might still be mounted on one of them: an expired lease only means that the guest's daemon
+    /// stopped renewing it, its machine might still have the home mounted.
+    pub async fn lock_home(&self, username: &str, connected_guests: &[LapasGuest]) -> Result<()> {
+        let leases = self.leases.lock().await;
+        // a guest with the same ip is the same machine, whose daemon reconnected in the meantime
+        let is_connected = |holder: &LapasGuest| connected_guests.iter()
+            .any(|guest| guest.id == holder.id || guest.ip == holder.ip);
+        if let Some(lease) = leases.get(username).filter(|lease| is_connected(&lease.holder)) {
+            return Err(anyhow!("User {} is logged in on {} ({}). Log out there first.",
+                username, lease.holder.info.hostname, lease.holder.ip));
+        }
+        if let Some(boot_ids) = self.mounted_homes.lock().await.get(username) {
+            if let Some(guest) = connected_guests.iter().find(|guest| boot_ids.contains(&guest.info.boot_id)) {
+                return Err(anyhow!("Home of {} might still be mounted on {} ({}). Reboot it first.",
+                    username, guest.info.hostname, guest.ip));
+            }
+        }
+        if !self.locked_homes.lock().await.insert(username.to_owned()) {
+            return Err(anyhow!("Home of {} is already being resized", username));
+        }
+        Ok(())
+    }
+
+    pub async fn unlock_home(&self, username: &str) {
+        self.locked_homes.lock().await.remove(username);
+    }
+}
@@ -0,0 +1,61 @@
+use std::{io::ErrorKind, os::unix::fs::MetadataExt as _, path::Path};
+
+use anyhow::{anyhow, Context as _, Result};
+use tokio::process::Command;
+
+/// Smallest home image size that is accepted for a quota
+pub(super) const MIN_HOME_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size and allocated bytes of the (sparse) home image at the given path, None if it does not exist
+pub(super) async fn usage(image_path: &Path) -> Result<Option<(u64, u64)>> {
+    match tokio::fs::metadata(image_path).await {
+        Ok(metadata) => Ok(Some((metadata.len(), metadata.blocks() * 512))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn run(program: &str, args: &[&str], ok_codes: &[i32]) -> Result<()> {
+    let output = Command::new(program).args(args).output().await
+        .with_context(|| format!("Running {}", program))?;
+    if !output.status.code().is_some_and(|code| ok_codes.contains(&code)) {
+        return Err(anyhow!("{} {} failed ({}):\n{}", program, args.join(" "), output.status,
+            String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Create a sparse home image of the given size with an empty ext4 filesystem (just like mountHome.sh does)
+pub(super) async fn create(image_path: &Path, size: u64) -> Result<()> {
+    let image = tokio::fs::OpenOptions::new().write(true).create_new(true).open(image_path).await?;
+    image.set_len(size).await?;
+    drop(image);
+    let image_path_str = image_path.to_string_lossy();
+    if let Err(e) = run("/usr/sbin/mkfs.ext4", &["-q", "-m0", &image_path_str], &[0]).await {
+        let _ = tokio::fs::remove_file(image_path).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Grow or shrink the home image at the given path, including its filesystem.
+/// The image must not be mounted anywhere.
+pub(super) async fn resize(image_path: &Path, size: u64) -> Result<()> {
+    let image_path_str = image_path.to_string_lossy();
+    // resize2fs insists on a freshly checked filesystem. Exit codes 1 and 2 mean errors were fixed.
+    run("/usr/sbin/e2fsck", &["-f", "-p", &image_path_str], &[0, 1, 2]).await
+        .context("Checking home image filesystem")?;
+    let current_size = tokio::fs::metadata(image_path).await?.len();
+    if size >= current_size {
+        tokio::fs::OpenOptions::new().write(true).open(image_path).await?.set_len(size).await?;
+        run("/usr/sbin/resize2fs", &[&image_path_str], &[0]).await
+            .context("Growing home image filesystem")?;
+    } else {
+        // the filesystem has to be shrunk before the image, resize2fs refuses if the data does not fit
+        let size_arg = format!("{}K", size / 1024);
+        run("/usr/sbin/resize2fs", &[&image_path_str, &size_arg], &[0]).await
+            .context("Shrinking home image filesystem")?;
+        tokio::fs::OpenOptions::new().write(true).open(image_path).await?.set_len(size).await?;
+    }
+    Ok(())
+}
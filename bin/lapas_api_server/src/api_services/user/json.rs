@@ -0,0 +1,278 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use lapas_api_proto::UserId;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncReadExt, sync::Mutex};
+
+use super::{NewUser, UserEntry, UserStorage};
+use crate::api_services::write_atomic_with_mode;
+
+/// Schema version of the USER_INDEX file written by this server.
+const USER_INDEX_VERSION: u32 = USER_INDEX_MIGRATIONS.len() as u32;
+/// Amount of rotated backups (USER_INDEX.1 ... USER_INDEX.N) kept next to the index.
+const USER_INDEX_BACKUPS: usize = 3;
+
+type UserIndexMigration = fn(&mut serde_json::Map<String, serde_json::Value>) -> Result<()>;
+/// Migrations of the raw USER_INDEX json, indexed by the version they migrate from.
+/// (`USER_INDEX_MIGRATIONS[0]` migrates from v0 to v1, and so on)
+const USER_INDEX_MIGRATIONS: &[UserIndexMigration] = &[
+    // v0 -> v1: Introduction of the version field, nothing else changed
+    |_| Ok(()),
+    // v1 -> v2: Introduction of per-user home quotas
+    |index| {
+        let users = index.get_mut("users").and_then(|users| users.as_array_mut())
+            .ok_or_else(|| anyhow!("USER_INDEX has no user list"))?;
+        for user in users.iter_mut().filter_map(|user| user.as_object_mut()) {
+            user.insert("home_quota".to_owned(), serde_json::Value::Null);
+        }
+        Ok(())
+    },
+];
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct UserIndex {
+    version: u32,
+    pub next_id: UserId,
+    pub users: Vec<UserEntry>,
+}
+impl Default for UserIndex {
+    fn default() -> Self {
+        Self {
+            version: USER_INDEX_VERSION,
+            next_id: 10000,
+            users: vec![],
+        }
+    }
+}
+
+pub(super) async fn read_user_index(path: &Path) -> Result<UserIndex> {
+    if !path.exists() {
+        return Ok(Default::default());
+    }
+    let mut file = File::open(path).await?;
+    let mut file_data = String::new();
+    file.read_to_string(&mut file_data).await?;
+
+    let mut index: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&file_data)?;
+    // indices written before versioning was introduced don't have a version field
+    let version = index.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    if version > USER_INDEX_VERSION {
+        return Err(anyhow!(
+            "USER_INDEX has version {}, but this server only supports up to version {}",
+            version, USER_INDEX_VERSION
+        ));
+    }
+    if version == USER_INDEX_VERSION {
+        return Ok(serde_json::from_value(index.into())?);
+    }
+
+    println!("Migrating USER_INDEX from version {} to {}", version, USER_INDEX_VERSION);
+    for migration in &USER_INDEX_MIGRATIONS[version as usize..] {
+        migration(&mut index)?;
+    }
+    index.insert("version".to_owned(), USER_INDEX_VERSION.into());
+    let index: UserIndex = serde_json::from_value(index.into())?;
+    // persist migrated index, the old one is kept as backup
+    write_user_index(path, &index).await?;
+    Ok(index)
+}
+
+fn user_index_backup_path(path: &Path, backup_nr: usize) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(format!(".{}", backup_nr));
+    PathBuf::from(backup_path)
+}
+
+async fn rotate_user_index_backups(path: &Path) -> Result<()> {
+    for backup_nr in (1..USER_INDEX_BACKUPS).rev() {
+        let backup_path = user_index_backup_path(path, backup_nr);
+        if backup_path.exists() {
+            tokio::fs::rename(&backup_path, user_index_backup_path(path, backup_nr + 1)).await?;
+        }
+    }
+    let backup_path = user_index_backup_path(path, 1);
+    if backup_path.exists() {
+        tokio::fs::remove_file(&backup_path).await?;
+    }
+    // hardlink, so that there is no point in time at which path does not exist
+    tokio::fs::hard_link(path, backup_path).await?;
+    Ok(())
+}
+
+async fn write_user_index(path: &Path, index: &UserIndex) -> Result<()> {
+    if path.exists() {
+        rotate_user_index_backups(path).await?;
+    }
+    // the index contains the password hashes, so it must never be readable by anyone but root,
+    // not even while it is being written
+    write_atomic_with_mode(path, serde_json::to_string_pretty(&index)?.as_bytes(), 0o0).await
+}
+
+/// User storage keeping all users in a single json file (USER_INDEX) that is
+/// rewritten on every change.
+pub(crate) struct JsonUserStorage {
+    user_index_path: PathBuf,
+    user_index: Mutex<UserIndex>,
+}
+impl JsonUserStorage {
+    pub async fn open(user_index_path: PathBuf) -> Result<Self> {
+        let user_index = read_user_index(&user_index_path).await?;
+        Ok(Self {
+            user_index_path,
+            user_index: Mutex::new(user_index),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStorage for JsonUserStorage {
+    async fn all(&self) -> Result<Vec<UserEntry>> {
+        let user_index = self.user_index.lock().await;
+        Ok(user_index.users.clone())
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<UserEntry>> {
+        let user_index = self.user_index.lock().await;
+        Ok(user_index.users.iter().find(|user| user.name == name).cloned())
+    }
+
+    async fn insert(&self, new_user: NewUser) -> Result<UserEntry> {
+        let mut user_index = self.user_index.lock().await;
+        if user_index.users.iter().any(|user| user.name == new_user.name) {
+            return Err(anyhow!("User with the requested name already exists!"));
+        }
+
+        let user = new_user.into_entry(user_index.next_id);
+        user_index.users.push(user.clone());
+        user_index.next_id += 1;
+
+        if let Err(e) = write_user_index(&self.user_index_path, &user_index).await {
+            // keep memory and disk in sync
+            user_index.users.pop();
+            user_index.next_id -= 1;
+            return Err(e);
+        }
+        Ok(user)
+    }
+
+    async fn set_home_quota(&self, name: &str, home_quota: Option<u64>) -> Result<()> {
+        let mut user_index = self.user_index.lock().await;
+        let user = user_index.users.iter_mut().find(|user| user.name == name)
+            .ok_or_else(|| anyhow!("User {} does not exist", name))?;
+        let previous_quota = std::mem::replace(&mut user.home_quota, home_quota);
+
+        if let Err(e) = write_user_index(&self.user_index_path, &user_index).await {
+            // keep memory and disk in sync
+            if let Some(user) = user_index.users.iter_mut().find(|user| user.name == name) {
+                user.home_quota = previous_quota;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    /// Empty scratch directory for a single test
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lapas-user-index-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn user_json(name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": 10000,
+            "name": name,
+            "password_hash": "$6$hash",
+            "creation_ts": "2024-01-01T00:00:00Z",
+            "last_update_ts": "2024-01-01T00:00:00Z",
+        })
+    }
+
+    fn read_json(path: &Path) -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    async fn migrate_from(test: &str, index: serde_json::Value) {
+        let dir = scratch_dir(test);
+        let path = dir.join("USER_INDEX");
+        std::fs::write(&path, index.to_string()).unwrap();
+
+        let migrated = read_user_index(&path).await.unwrap();
+        assert_eq!(migrated.version, USER_INDEX_VERSION);
+        assert_eq!(migrated.next_id, 10001);
+        assert_eq!(migrated.users.len(), 1);
+        assert_eq!(migrated.users[0].name, "alice");
+        assert_eq!(migrated.users[0].home_quota, None);
+        // the migrated index is persisted, the original one kept as backup
+        assert_eq!(read_json(&path)["version"], USER_INDEX_VERSION);
+        assert_eq!(read_json(&user_index_backup_path(&path, 1)), index);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrates_unversioned_index() {
+        migrate_from("v0", serde_json::json!({ "next_id": 10001, "users": [user_json("alice")] })).await;
+    }
+
+    #[tokio::test]
+    async fn migrates_index_without_home_quotas() {
+        migrate_from("v1", serde_json::json!({ "version": 1, "next_id": 10001, "users": [user_json("alice")] })).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_index_of_newer_server() {
+        let dir = scratch_dir("newer");
+        let path = dir.join("USER_INDEX");
+        let index = serde_json::json!({ "version": USER_INDEX_VERSION + 1, "next_id": 10000, "users": [] });
+        std::fs::write(&path, index.to_string()).unwrap();
+
+        assert!(read_user_index(&path).await.is_err());
+        // an index that can not be read is left untouched
+        assert_eq!(read_json(&path), index);
+        assert!(!user_index_backup_path(&path, 1).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_backups() {
+        let dir = scratch_dir("rotate");
+        let path = dir.join("USER_INDEX");
+        let mut index = UserIndex::default();
+        for next_id in 0..(USER_INDEX_BACKUPS as UserId + 3) {
+            index.next_id = next_id;
+            write_user_index(&path, &index).await.unwrap();
+        }
+
+        let last_id = USER_INDEX_BACKUPS as UserId + 2;
+        assert_eq!(read_json(&path)["next_id"], last_id);
+        for backup_nr in 1..=USER_INDEX_BACKUPS {
+            let backup = read_json(&user_index_backup_path(&path, backup_nr));
+            assert_eq!(backup["next_id"], last_id - backup_nr as UserId);
+        }
+        assert!(!user_index_backup_path(&path, USER_INDEX_BACKUPS + 1).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn index_is_never_readable_by_others() {
+        let dir = scratch_dir("mode");
+        let path = dir.join("USER_INDEX");
+        write_user_index(&path, &UserIndex::default()).await.unwrap();
+        write_user_index(&path, &UserIndex::default()).await.unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0);
+        assert_eq!(std::fs::metadata(user_index_backup_path(&path, 1)).unwrap().permissions().mode() & 0o777, 0);
+        // no temporary file is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use lapas_api_proto::{LapasUserPasswd, LapasUserShadow, UserHomeUsage, UserId};
+use rand::{distr::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha_crypt::{sha512_crypt_b64, Sha512Params};
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+mod home_image;
+mod json;
+mod sqlite;
+
+use json::JsonUserStorage;
+use sqlite::SqliteUserStorage;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct UserEntry {
+    pub id: UserId,
+    pub name: String,
+    pub password_hash: String,
+    pub creation_ts: DateTime<Utc>,
+    pub last_update_ts: DateTime<Utc>,
+    /// Size the user's home image is kept at, in bytes
+    pub home_quota: Option<u64>,
+}
+
+/// A user that is about to be registered, but has not been assigned an id yet.
+pub(crate) struct NewUser {
+    pub name: String,
+    pub password_hash: String,
+}
+impl NewUser {
+    fn into_entry(self, id: UserId) -> UserEntry {
+        UserEntry {
+            id,
+            name: self.name,
+            password_hash: self.password_hash,
+            creation_ts: Utc::now(),
+            last_update_ts: Utc::now(),
+            home_quota: None,
+        }
+    }
+}
+
+/// Persistence backend of the [`UserService`].
+/// Implementations do their own locking and have to make sure that user names stay unique.
+#[async_trait::async_trait]
+pub(crate) trait UserStorage: Send + Sync {
+    /// List of all registered users
+    async fn all(&self) -> Result<Vec<UserEntry>>;
+    async fn find_by_name(&self, name: &str) -> Result<Option<UserEntry>>;
+    /// Persist the given new user, assigning it the next free user id
+    async fn insert(&self, new_user: NewUser) -> Result<UserEntry>;
+    /// Persist the home quota of the given user
+    async fn set_home_quota(&self, name: &str, home_quota: Option<u64>) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UserStorageKind {
+    /// Single json file (USER_INDEX) in the homes directory
+    Json,
+    /// SQLite database (USER_INDEX.sqlite) in the homes directory
+    Sqlite,
+}
+impl FromStr for UserStorageKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(anyhow!("Unknown user storage: {} (expected json or sqlite)", s)),
+        }
+    }
+}
+
+fn password_to_ghost_random_salt(password: &str) -> String {
+    let params = Sha512Params::new(5000).expect("Failed to initialize password hasher");
+
+    let salt: String = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+
+    let hashed_password = sha512_crypt_b64(password.as_bytes(), salt.as_bytes(), &params)
+        .expect("Failed to create crypted password hash");
+    format!("$6${}${}", salt, hashed_password)
+}
+
+/// Whether the given name may be used as a username.
+/// Usernames end up in file names (home images, dns hostfiles) and dns names, so they are restricted
+/// to letters, digits, '_' and '-'.
+pub(crate) fn is_valid_username(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub(crate) struct UserService {
+    storage: Box<dyn UserStorage>,
+    /// Directory containing the home images, named after their user
+    homes_dir: PathBuf,
+}
+impl UserService {
+    pub async fn new(homes_dir: PathBuf, storage_kind: UserStorageKind) -> Result<Self> {
+        let user_index_path = homes_dir.join("USER_INDEX");
+
+        let storage: Box<dyn UserStorage> = match storage_kind {
+            UserStorageKind::Json => Box::new(JsonUserStorage::open(user_index_path).await?),
+            UserStorageKind::Sqlite => {
+                let db_path = user_index_path.with_extension("sqlite");
+                Box::new(SqliteUserStorage::open(db_path, user_index_path).await?)
+            }
+        };
+
+        Ok(Self { storage, homes_dir })
+    }
+
+    pub async fn add_user(&self, username: String, password: String) -> Result<()> {
+        // validation
+        if username.len() < 3 {
+            return Err(anyhow!("Username too short!"));
+        }
+
+        if !is_valid_username(&username) {
+            return Err(anyhow!("Username may only contain letters, digits, '_' and '-', and must start with a letter or '_'!"));
+        }
+
+        if self.storage.find_by_name(&username).await?.is_some() {
+            return Err(anyhow!("User with the requested name already exists!"));
+        }
+
+        if password.is_empty() {
+            return Err(anyhow!("Password must not be empty!"));
+        }
+
+        // add user
+        self.storage.insert(NewUser {
+            name: username,
+            password_hash: password_to_ghost_random_salt(&password),
+        }).await?;
+
+        Ok(())
+    }
+
+    pub async fn exists(&self, username: &str) -> Result<bool> {
+        Ok(self.storage.find_by_name(username).await?.is_some())
+    }
+
+    pub async fn passwd_all(&self) -> Result<Vec<LapasUserPasswd>> {
+        Ok(self.storage.all().await?
+            .into_iter()
+            .map(|usr| LapasUserPasswd {
+                id: usr.id,
+                name: usr.name,
+            })
+            .collect())
+    }
+
+    pub async fn shadow_all(&self) -> Result<Vec<LapasUserShadow>> {
+        Ok(self.storage.all().await?
+            .into_iter()
+            .map(|usr| LapasUserShadow {
+                id: usr.id,
+                name: usr.name,
+                password_hash: usr.password_hash,
+                last_update_ts: usr.last_update_ts,
+            })
+            .collect())
+    }
+
+    async fn home_usage(&self, user: &UserEntry) -> Result<UserHomeUsage> {
+        let (image_size, used) = match home_image::usage(&self.homes_dir.join(&user.name)).await? {
+            Some((image_size, used)) => (Some(image_size), used),
+            None => (None, 0),
+        };
+        Ok(UserHomeUsage { username: user.name.clone(), quota: user.home_quota, image_size, used })
+    }
+
+    pub async fn home_usage_all(&self) -> Result<Vec<UserHomeUsage>> {
+        let mut usage = vec![];
+        for user in self.storage.all().await? {
+            usage.push(self.home_usage(&user).await?);
+        }
+        Ok(usage)
+    }
+
+    /// Set the user's home quota, resizing the home image to it (or creating it, if the user has none yet).
+    /// The home image must not be mounted anywhere.
+    pub async fn set_home_quota(&self, username: &str, home_quota: Option<u64>) -> Result<UserHomeUsage> {
+        let mut user = self.storage.find_by_name(username).await?
+            .ok_or_else(|| anyhow!("User {} does not exist", username))?;
+        if let Some(size) = home_quota {
+            if size < home_image::MIN_HOME_IMAGE_SIZE || size % (1024 * 1024) != 0 {
+                return Err(anyhow!("Home quota must be a multiple of 1M and at least {}M",
+                    home_image::MIN_HOME_IMAGE_SIZE / 1024 / 1024));
+            }
+            let image_path = self.homes_dir.join(username);
+            match home_image::usage(&image_path).await? {
+                Some((image_size, _)) if image_size == size => {},
+                Some(_) => home_image::resize(&image_path, size).await?,
+                None => home_image::create(&image_path, size).await?,
+            }
+        }
+        self.storage.set_home_quota(username, home_quota).await?;
+        user.home_quota = home_quota;
+        self.home_usage(&user).await
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use rusqlite::{params, Connection, OptionalExtension as _, Transaction};
+
+use super::{json, NewUser, UserEntry, UserStorage};
+
+/// Schema migrations of the user database, indexed by the version they migrate from.
+/// The current schema version is tracked using sqlite's `user_version` pragma.
+const USER_DB_MIGRATIONS: &[&str] = &[
+    // v0 -> v1: Initial schema
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        creation_ts TEXT NOT NULL,
+        last_update_ts TEXT NOT NULL
+    );
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    INSERT INTO meta (key, value) VALUES ('next_id', 10000);",
+    // v1 -> v2: Introduction of per-user home quotas
+    "ALTER TABLE users ADD COLUMN home_quota INTEGER;",
+];
+
+fn migrate(db: &mut Connection) -> Result<()> {
+    let version: usize = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > USER_DB_MIGRATIONS.len() {
+        return Err(anyhow!(
+            "User database has version {}, but this server only supports up to version {}",
+            version, USER_DB_MIGRATIONS.len()
+        ));
+    }
+    for (from_version, migration) in USER_DB_MIGRATIONS.iter().enumerate().skip(version) {
+        println!("Migrating user database from version {} to {}", from_version, from_version + 1);
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", from_version + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn row_to_user(row: &rusqlite::Row) -> rusqlite::Result<UserEntry> {
+    Ok(UserEntry {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        password_hash: row.get("password_hash")?,
+        creation_ts: row.get("creation_ts")?,
+        last_update_ts: row.get("last_update_ts")?,
+        home_quota: row.get("home_quota")?,
+    })
+}
+
+fn insert_user(tx: &Transaction, user: &UserEntry) -> Result<()> {
+    tx.execute(
+        "INSERT INTO users (id, name, password_hash, creation_ts, last_update_ts, home_quota) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![user.id, user.name, user.password_hash, user.creation_ts, user.last_update_ts, user.home_quota],
+    )?;
+    Ok(())
+}
+
+/// User storage backed by a SQLite database, giving transactional updates and
+/// indexed lookups by name and id.
+pub(crate) struct SqliteUserStorage {
+    db: Arc<Mutex<Connection>>,
+}
+impl SqliteUserStorage {
+    /// Open (or create) the user database at `db_path`.
+    /// If the database has no users yet and a json `USER_INDEX` exists at `legacy_index_path`,
+    /// all of its users are imported and the json index is moved out of the way.
+    /// (An import that failed is thus retried on the next start)
+    pub async fn open(db_path: PathBuf, legacy_index_path: PathBuf) -> Result<Self> {
+        let is_new_db = !db_path.exists();
+        let mut db = Connection::open(&db_path).context("Opening user database")?;
+        if is_new_db {
+            // fix file permissions
+            let mut db_file_permissions = tokio::fs::metadata(&db_path).await?.permissions();
+            db_file_permissions.set_mode(0o0);
+            tokio::fs::set_permissions(&db_path, db_file_permissions).await?;
+        }
+        migrate(&mut db).context("Migrating user database")?;
+
+        let user_count: u64 = db.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+        if user_count == 0 && legacy_index_path.exists() {
+            Self::import_user_index(&mut db, &legacy_index_path).await
+                .context("Importing users from USER_INDEX")?;
+        }
+
+        Ok(Self { db: Arc::new(Mutex::new(db)) })
+    }
+
+    async fn import_user_index(db: &mut Connection, legacy_index_path: &Path) -> Result<()> {
+        let user_index = json::read_user_index(legacy_index_path).await?;
+        let tx = db.transaction()?;
+        for user in &user_index.users {
+            insert_user(&tx, user)?;
+        }
+        tx.execute("UPDATE meta SET value = ?1 WHERE key = 'next_id'", [user_index.next_id])?;
+        tx.commit()?;
+
+        let mut migrated_path = legacy_index_path.as_os_str().to_owned();
+        migrated_path.push(".migrated");
+        tokio::fs::rename(legacy_index_path, &migrated_path).await?;
+        println!(
+            "Imported {} users from USER_INDEX into user database (old index moved to: {:?})",
+            user_index.users.len(), migrated_path
+        );
+        Ok(())
+    }
+
+    async fn with_db<T, F>(&self, op: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&mut Connection) -> Result<T> + Send + 'static
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut db = db.lock().map_err(|_| anyhow!("User database lock poisoned"))?;
+            op(&mut db)
+        }).await?
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStorage for SqliteUserStorage {
+    async fn all(&self) -> Result<Vec<UserEntry>> {
+        self.with_db(|db| {
+            let mut stmt = db.prepare("SELECT * FROM users ORDER BY id")?;
+            let users = stmt.query_map([], row_to_user)?.collect::<rusqlite::Result<_>>()?;
+            Ok(users)
+        }).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<UserEntry>> {
+        let name = name.to_owned();
+        self.with_db(move |db| {
+            Ok(db.query_row("SELECT * FROM users WHERE name = ?1", [name], row_to_user).optional()?)
+        }).await
+    }
+
+    async fn insert(&self, new_user: NewUser) -> Result<UserEntry> {
+        self.with_db(move |db| {
+            let tx = db.transaction()?;
+            let name_taken: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE name = ?1)", [&new_user.name], |row| row.get(0))?;
+            if name_taken {
+                return Err(anyhow!("User with the requested name already exists!"));
+            }
+            let next_id = tx.query_row("SELECT value FROM meta WHERE key = 'next_id'", [], |row| row.get(0))?;
+            let user = new_user.into_entry(next_id);
+            insert_user(&tx, &user)?;
+            tx.execute("UPDATE meta SET value = value + 1 WHERE key = 'next_id'", [])?;
+            tx.commit()?;
+            Ok(user)
+        }).await
+    }
+
+    async fn set_home_quota(&self, name: &str, home_quota: Option<u64>) -> Result<()> {
+        let name = name.to_owned();
+        self.with_db(move |db| {
+            let updated = db.execute("UPDATE users SET home_quota = ?1 WHERE name = ?2", params![home_quota, name])?;
+            if updated == 0 {
+                return Err(anyhow!("User {} does not exist", name));
+            }
+            Ok(())
+        }).await
+    }
+}
@@ -0,0 +1,62 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, Result};
+use tokio::net::UdpSocket;
+
+/// Port magic packets are sent to (discard protocol)
+const WAKE_ON_LAN_PORT: u16 = 9;
+/// Bond interface of the internal lapas network (see dnsmasq.conf).
+/// The internal NICs are its slaves, sending on them directly is unreliable.
+const LAPAS_NET_INTERFACE: &str = "lapas";
+
+/// Magic packet for the given MAC address: 6 times 0xff, followed by the MAC address repeated 16 times
+fn magic_packet(mac: &str) -> Result<Vec<u8>> {
+    let mac = mac.split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|octets| octets.len() == 6)
+        .ok_or_else(|| anyhow!("Invalid MAC address: {}", mac))?;
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    Ok(packet)
+}
+
+async fn broadcast(packet: &[u8]) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.bind_device(Some(LAPAS_NET_INTERFACE.as_bytes()))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(packet, (Ipv4Addr::BROADCAST, WAKE_ON_LAN_PORT)).await?;
+    Ok(())
+}
+
+/// Broadcast a magic packet for the machine with the given MAC address into the internal lapas network
+pub async fn wake(mac: &str) -> Result<()> {
+    let packet = magic_packet(mac)?;
+    broadcast(&packet).await
+        .map_err(|e| anyhow!("Failed to send magic packet to {} ({}: {})", mac, LAPAS_NET_INTERFACE, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_packet_layout() {
+        let packet = magic_packet("52:54:00:ab:CD:ef").unwrap();
+        assert_eq!(packet.len(), 102);
+        assert_eq!(packet[..6], [0xff; 6]);
+        for mac in packet[6..].chunks(6) {
+            assert_eq!(mac, [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_mac_addresses() {
+        assert!(magic_packet("52:54:00:ab:cd:xx").is_err());
+        assert!(magic_packet("52:54:00:ab:cd").is_err());
+        assert!(magic_packet("").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::HashMap, fmt::Display, net::{IpAddr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, str::FromStr};
+
+use anyhow::{anyhow, Context as _, Result};
+use tokio::{fs::File, io::{AsyncBufReadExt as _, BufReader}};
+
+use crate::api_services::user::UserStorageKind;
+
+/// Collects typed values from the raw key-value pairs of the lapas script configuration,
+/// remembering every problem instead of bailing out on the first one.
+struct ConfigReader {
+    values: HashMap<String, String>,
+    errors: Vec<String>,
+}
+impl ConfigReader {
+    fn parse<T: FromStr>(&mut self, key: &str, value: &str) -> Option<T> where T::Err: Display {
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("Parameter {} has invalid value \"{}\": {}", key, value, e));
+                None
+            }
+        }
+    }
+
+    fn required<T: FromStr + Default>(&mut self, key: &str) -> T where T::Err: Display {
+        match self.values.get(key).cloned() {
+            Some(value) => self.parse(key, &value).unwrap_or_default(),
+            None => {
+                self.errors.push(format!("Missing parameter {}", key));
+                T::default()
+            }
+        }
+    }
+
+    fn optional<T: FromStr>(&mut self, key: &str, default: T) -> T where T::Err: Display {
+        match self.values.get(key).cloned() {
+            Some(value) => self.parse(key, &value).unwrap_or(default),
+            None => default,
+        }
+    }
+
+    /// Value without a default, None if it is not set
+    fn maybe<T: FromStr>(&mut self, key: &str) -> Option<T> where T::Err: Display {
+        let value = self.values.get(key).cloned()?;
+        self.parse(key, &value)
+    }
+
+    /// Whitespace separated list of values
+    fn optional_list<T: FromStr>(&mut self, key: &str, default: Vec<T>) -> Vec<T> where T::Err: Display {
+        match self.values.get(key).cloned() {
+            Some(values) => values.split_whitespace()
+                .filter_map(|value| self.parse(key, value))
+                .collect(),
+            None => default,
+        }
+    }
+
+    fn check(&mut self, condition: bool, error: impl ToString) {
+        if !condition {
+            self.errors.push(error.to_string());
+        }
+    }
+}
+
+/// Address the api server listens on for client connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// Tcp socket address, e.g.: `192.168.42.1:1337` or `[::]:1337`, optionally only listening
+    /// on the given network interface, e.g.: `[::]:1337%lapas`
+    Tcp(SocketAddr, Option<String>),
+    /// Local unix socket, e.g.: `unix:/run/lapas/api-server.socket`
+    Unix(PathBuf),
+}
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("unix:") {
+            Some(path) if path.starts_with('/') => Ok(Self::Unix(PathBuf::from(path))),
+            Some(_) => Err(anyhow!("Unix socket path has to be absolute")),
+            None => match s.split_once('%') {
+                Some((_, "")) => Err(anyhow!("Network interface name missing")),
+                Some((addr, interface)) => Ok(Self::Tcp(addr.parse()?, Some(interface.to_owned()))),
+                None => Ok(Self::Tcp(s.parse()?, None)),
+            },
+        }
+    }
+}
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr, None) => write!(f, "{}", addr),
+            ListenAddr::Tcp(addr, Some(interface)) => write!(f, "{}%{}", addr, interface),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Typed configuration of the api server, read from the lapas script configuration file.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Directory containing the player home images and the user index. (`LAPAS_USERHOMES_DIR`)
+    pub homes_dir: PathBuf,
+    /// Domain of the internal lapas network. (`LAPAS_NET_DOMAIN`)
+    pub net_domain: String,
+    /// Address of the lapas server within the internal network, `lapas.<domain>` resolves to it. (`LAPAS_NET_IP`)
+    pub net_ip: Option<IpAddr>,
+    /// dnsmasq hostsdir into which user dns mappings are written. (`LAPAS_DNS_HOSTMAPPINGS_DIR`)
+    pub dns_hostmappings_dir: PathBuf,
+    /// Root of the tftp server, into which the per-MAC boot configurations are written. (`LAPAS_TFTP_DIR`)
+    pub tftp_dir: PathBuf,
+    /// Salt prepended to the administration password before hashing. (`LAPAS_PASSWORD_SALT`)
+    /// Reloadable at runtime.
+    pub password_salt: String,
+    /// Hex-encoded sha512 hash of the salted administration password. (`LAPAS_PASSWORD_HASH`)
+    /// Reloadable at runtime.
+    pub password_hash: String,
+    /// Storage backend for registered users. (`LAPAS_USER_STORAGE`, default: `json`)
+    pub user_storage: UserStorageKind,
+    /// Whitespace separated list of addresses the api server listens on.
+    /// `[::]` listens on IPv4 and IPv6, unless the `net.ipv6.bindv6only` sysctl is set.
+    /// `%<interface>` restricts a tcp address to one network interface, e.g.: `[::]:1337%lapas`
+    /// (`LAPAS_API_LISTEN`, default: `[::]:1337 unix:/run/lapas/api-server.socket`)
+    pub api_listen: Vec<ListenAddr>,
+    /// Whitespace separated list of udp addresses the built-in dns server listens on.
+    /// The built-in dns server is disabled if empty. (`LAPAS_DNS_LISTEN`, default: empty)
+    pub dns_listen: Vec<SocketAddr>,
+    /// Resolver that queries outside of the lapas network's domain are forwarded to.
+    /// (`LAPAS_DNS_UPSTREAM`, default: `127.0.0.53:53`)
+    pub dns_upstream: SocketAddr,
+    /// Lease file of dnsmasq's dhcp server. (`LAPAS_DNSMASQ_LEASES`, default: `/var/lib/misc/dnsmasq.leases`)
+    /// Reloadable at runtime.
+    pub dnsmasq_leases: PathBuf,
+    /// Amount of guests asked to remount their root at once after a root change.
+    /// (`LAPAS_REMOUNT_WAVE_SIZE`, default: `4`) Reloadable at runtime.
+    pub remount_wave_size: usize,
+    /// Seconds to wait between two waves of remounts. (`LAPAS_REMOUNT_WAVE_INTERVAL_SECS`, default: `10`)
+    /// Reloadable at runtime.
+    pub remount_wave_interval_secs: u64,
+    /// Seconds a guest has to report back after it was asked to remount, before it counts as failed.
+    /// (`LAPAS_REMOUNT_TIMEOUT_SECS`, default: `60`) Reloadable at runtime.
+    pub remount_timeout_secs: u64,
+    /// Amount of failed remounts the rollout of a root change tolerates before it is paused.
+    /// (`LAPAS_REMOUNT_MAX_FAILURES`, default: `2`) Reloadable at runtime.
+    pub remount_max_failures: usize,
+}
+impl ServerConfig {
+    /// Load and validate the lapas script configuration at the given path.
+    /// All problems found within the file are reported at once.
+    pub async fn load(config_path: &Path) -> Result<Self> {
+        let mut reader = ConfigReader { values: HashMap::new(), errors: vec![] };
+
+        let config_file = File::open(config_path)
+            .await
+            .context("Failed to open lapas scripts configuration file.")?;
+        let mut config_file_lines = BufReader::new(config_file).lines();
+        let mut line_nr = 0;
+        while let Some(line) = config_file_lines.next_line().await? {
+            line_nr += 1;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let Some((key, mut value)) = line.split_once('=') else {
+                reader.errors.push(format!("Line {}: Expected KEY=VALUE", line_nr));
+                continue;
+            };
+            if value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+                value = &value[1..value.len() - 1];
+            }
+            reader.values.insert(key.to_owned(), value.to_owned());
+        }
+
+        let config = ServerConfig {
+            homes_dir: reader.required("LAPAS_USERHOMES_DIR"),
+            net_domain: reader.required("LAPAS_NET_DOMAIN"),
+            net_ip: reader.maybe("LAPAS_NET_IP"),
+            dns_hostmappings_dir: reader.required("LAPAS_DNS_HOSTMAPPINGS_DIR"),
+            tftp_dir: reader.required("LAPAS_TFTP_DIR"),
+            password_salt: reader.required("LAPAS_PASSWORD_SALT"),
+            password_hash: reader.required("LAPAS_PASSWORD_HASH"),
+            user_storage: reader.optional("LAPAS_USER_STORAGE", UserStorageKind::Json),
+            api_listen: reader.optional_list("LAPAS_API_LISTEN", vec![
+                ListenAddr::Tcp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 1337)), None),
+                ListenAddr::Unix(PathBuf::from("/run/lapas/api-server.socket")),
+            ]),
+            dns_listen: reader.optional_list("LAPAS_DNS_LISTEN", vec![]),
+            dns_upstream: reader.optional("LAPAS_DNS_UPSTREAM", SocketAddr::from(([127, 0, 0, 53], 53))),
+            dnsmasq_leases: reader.optional("LAPAS_DNSMASQ_LEASES", PathBuf::from("/var/lib/misc/dnsmasq.leases")),
+            remount_wave_size: reader.optional("LAPAS_REMOUNT_WAVE_SIZE", 4),
+            remount_wave_interval_secs: reader.optional("LAPAS_REMOUNT_WAVE_INTERVAL_SECS", 10),
+            remount_timeout_secs: reader.optional("LAPAS_REMOUNT_TIMEOUT_SECS", 60),
+            remount_max_failures: reader.optional("LAPAS_REMOUNT_MAX_FAILURES", 2),
+        };
+
+        reader.check(config.homes_dir.is_dir(),
+            format!("LAPAS_USERHOMES_DIR {:?} is not a directory", config.homes_dir));
+        reader.check(!config.api_listen.is_empty(), "LAPAS_API_LISTEN must contain at least one address");
+        reader.check(!config.net_domain.is_empty(), "LAPAS_NET_DOMAIN must not be empty");
+        reader.check(config.remount_wave_size > 0, "LAPAS_REMOUNT_WAVE_SIZE must be at least 1");
+        reader.check(
+            config.password_hash.len() == 128 && config.password_hash.chars().all(|c| c.is_ascii_hexdigit()),
+            "LAPAS_PASSWORD_HASH is not a hex-encoded sha512 hash"
+        );
+
+        if !reader.errors.is_empty() {
+            return Err(anyhow!(
+                "Invalid configuration file {:?}:\n - {}",
+                config_path, reader.errors.join("\n - ")
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Take over all settings from `new_config` that can safely be changed at runtime.
+    /// Returns the names of changed settings that only take effect after a restart.
+    pub fn reload_from(&mut self, new_config: ServerConfig) -> Vec<&'static str> {
+        let mut restart_required = vec![];
+        if new_config.homes_dir != self.homes_dir {
+            restart_required.push("LAPAS_USERHOMES_DIR");
+        }
+        if new_config.net_domain != self.net_domain {
+            restart_required.push("LAPAS_NET_DOMAIN");
+        }
+        if new_config.net_ip != self.net_ip {
+            restart_required.push("LAPAS_NET_IP");
+        }
+        if new_config.dns_hostmappings_dir != self.dns_hostmappings_dir {
+            restart_required.push("LAPAS_DNS_HOSTMAPPINGS_DIR");
+        }
+        if new_config.tftp_dir != self.tftp_dir {
+            restart_required.push("LAPAS_TFTP_DIR");
+        }
+        if new_config.user_storage != self.user_storage {
+            restart_required.push("LAPAS_USER_STORAGE");
+        }
+        if new_config.api_listen != self.api_listen {
+            restart_required.push("LAPAS_API_LISTEN");
+        }
+        if new_config.dns_listen != self.dns_listen {
+            restart_required.push("LAPAS_DNS_LISTEN");
+        }
+        if new_config.dns_upstream != self.dns_upstream {
+            restart_required.push("LAPAS_DNS_UPSTREAM");
+        }
+
+        self.password_salt = new_config.password_salt;
+        self.password_hash = new_config.password_hash;
+        self.dnsmasq_leases = new_config.dnsmasq_leases;
+        self.remount_wave_size = new_config.remount_wave_size;
+        self.remount_wave_interval_secs = new_config.remount_wave_interval_secs;
+        self.remount_timeout_secs = new_config.remount_timeout_secs;
+        self.remount_max_failures = new_config.remount_max_failures;
+        restart_required
+    }
+}
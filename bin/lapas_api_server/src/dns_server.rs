@@ -0,0 +1,338 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context as _, Result};
+use hickory_proto::{op::{Message, MessageType, OpCode, ResponseCode}, rr::{rdata::{A, AAAA, PTR, SOA}, Name, RData, Record, RecordType}};
+use tokio::{net::UdpSocket, sync::Semaphore, time};
+use crate::state::{SharedState, State};
+
+/// Time to live of answers for the lapas network's domain.
+/// Mappings follow the users' sessions, so resolvers must not cache them for long.
+const DNS_TTL: u32 = 5;
+/// How long to wait for the upstream resolver to answer a forwarded query
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Largest dns message accepted over udp (with EDNS)
+const MAX_MESSAGE_SIZE: usize = 4096;
+/// Queries handled at once per listen address. Further queries wait in the socket's receive buffer.
+const MAX_CONCURRENT_QUERIES: usize = 64;
+
+/// Records the dns server answers queries for the lapas network's domain from
+#[async_trait::async_trait]
+pub(crate) trait DnsLookup: Send + Sync {
+    /// Domain of the lapas network, which the dns server is authoritative for
+    fn domain(&self) -> String;
+    /// Addresses of the given name, None if the name is not answered by the dns server,
+    /// and an empty list if it is within the domain, but unknown.
+    async fn resolve(&self, name: &str) -> Option<Vec<IpAddr>>;
+    /// All names that resolve to the given address
+    async fn reverse(&self, ip: IpAddr) -> Vec<String>;
+}
+#[async_trait::async_trait]
+impl DnsLookup for State {
+    fn domain(&self) -> String {
+        self.config().net_domain.clone()
+    }
+
+    async fn resolve(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.dns_resolve(name).await
+    }
+
+    async fn reverse(&self, ip: IpAddr) -> Vec<String> {
+        self.dns_reverse(ip).await
+    }
+}
+
+/// Bind all configured dns listen addresses and start answering queries.
+/// Does nothing if the built-in dns server is disabled.
+pub async fn start(state: SharedState) -> Result<()> {
+    let config = state.config();
+    for listen_addr in config.dns_listen.iter() {
+        let socket = UdpSocket::bind(listen_addr).await
+            .with_context(|| format!("DNS listening on {}", listen_addr))?;
+        println!("DNS listening on {} (upstream: {})", listen_addr, config.dns_upstream);
+        tokio::spawn(serve_udp(Arc::new(socket), config.dns_upstream, state.clone()));
+    }
+    Ok(())
+}
+
+async fn serve_udp<L: DnsLookup + 'static>(socket: Arc<UdpSocket>, upstream: SocketAddr, lookup: Arc<L>) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    loop {
+        // stop receiving while all permits are taken, instead of piling up tasks
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("DNS: Failed to receive query: {}", e);
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let lookup = lookup.clone();
+        // forwarded queries take a while, don't block others on them
+        tokio::spawn(async move {
+            match handle_query(&query, upstream, lookup.as_ref()).await {
+                Ok(Some(response)) => {
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        eprintln!("DNS: Failed to answer {}: {}", peer, e);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => eprintln!("DNS: Failed to handle query of {}: {}", peer, e),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Answer queries for the lapas network's domain from the given lookup,
+/// forward everything else to the upstream resolver.
+async fn handle_query(query: &[u8], upstream: SocketAddr, lookup: &impl DnsLookup) -> Result<Option<Vec<u8>>> {
+    let Ok(request) = Message::from_vec(query) else {
+        return Ok(None); // not even a header to answer to
+    };
+    if request.message_type() != MessageType::Query || request.op_code() != OpCode::Query || request.queries().len() != 1 {
+        return forward(query, upstream).await.map(Some);
+    }
+    let question = &request.queries()[0];
+    let name = question.name();
+    let name_str = name.to_ascii();
+
+    if question.query_type() == RecordType::PTR {
+        let Some(ip) = arpa_name_to_ip(name) else {
+            return forward(query, upstream).await.map(Some);
+        };
+        let names = lookup.reverse(ip).await;
+        if names.is_empty() {
+            return forward(query, upstream).await.map(Some);
+        }
+        let mut answers = vec![];
+        for ptr_name in names {
+            let ptr_name = Name::from_ascii(format!("{}.", ptr_name))?;
+            answers.push(Record::from_rdata(name.clone(), DNS_TTL, RData::PTR(PTR(ptr_name))));
+        }
+        return respond(&request, ResponseCode::NoError, answers, None).map(Some);
+    }
+
+    let Some(ips) = lookup.resolve(&name_str).await else {
+        return forward(query, upstream).await.map(Some);
+    };
+    let domain = lookup.domain();
+    // the domain's apex exists, even though it has no addresses
+    let is_apex = name_str.trim_end_matches('.').eq_ignore_ascii_case(&domain);
+    if ips.is_empty() && !is_apex {
+        return respond(&request, ResponseCode::NXDomain, vec![], Some(soa_record(&domain)?)).map(Some);
+    }
+    // names that exist, but have no record of the queried type, are answered without records
+    let answers: Vec<_> = ips.into_iter()
+        .filter_map(|ip| match (ip, question.query_type()) {
+            (IpAddr::V4(ip), RecordType::A | RecordType::ANY) => Some(RData::A(A(ip))),
+            (IpAddr::V6(ip), RecordType::AAAA | RecordType::ANY) => Some(RData::AAAA(AAAA(ip))),
+            _ => None,
+        })
+        .map(|rdata| Record::from_rdata(name.clone(), DNS_TTL, rdata))
+        .collect();
+    let soa = match answers.is_empty() {
+        true => Some(soa_record(&domain)?),
+        false => None,
+    };
+    respond(&request, ResponseCode::NoError, answers, soa).map(Some)
+}
+
+/// Address of a complete reverse lookup name (e.g.: `1.42.168.192.in-addr.arpa.`)
+fn arpa_name_to_ip(name: &Name) -> Option<IpAddr> {
+    let net = name.parse_arpa_name().ok()?;
+    match net.prefix_len() == net.max_prefix_len() {
+        true => Some(net.addr()),
+        false => None,
+    }
+}
+
+/// SOA record of the lapas network's domain. Negative answers carry it, so resolvers know
+/// how long they may cache them (its minimum).
+fn soa_record(domain: &str) -> Result<Record> {
+    let zone = Name::from_ascii(format!("{}.", domain))?;
+    let soa = SOA::new(
+        Name::from_ascii(format!("lapas.{}.", domain))?,
+        Name::from_ascii(format!("hostmaster.{}.", domain))?,
+        1, 3600, 600, 86400, DNS_TTL,
+    );
+    Ok(Record::from_rdata(zone, DNS_TTL, RData::SOA(soa)))
+}
+
+/// Build an authoritative response to the given request
+fn respond(request: &Message, response_code: ResponseCode, answers: Vec<Record>, soa: Option<Record>) -> Result<Vec<u8>> {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(response_code)
+        .add_queries(request.queries().to_vec())
+        .add_answers(answers)
+        .add_name_servers(soa);
+    Ok(response.to_vec()?)
+}
+
+/// Pass the raw query on to the upstream resolver and return its raw response
+async fn forward(query: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    let bind_addr: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    let len = time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await
+        .map_err(|_| anyhow!("Timeout while waiting for upstream resolver {}", upstream))??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hickory_proto::op::Query;
+
+    use super::*;
+
+    /// Address every query forwarded to the upstream stub is answered with
+    const UPSTREAM_IP: Ipv4Addr = Ipv4Addr::new(9, 9, 9, 9);
+
+    struct StaticLookup {
+        names: HashMap<String, Vec<IpAddr>>,
+    }
+    impl StaticLookup {
+        fn new(names: &[(&str, IpAddr)]) -> Self {
+            let mut lookup = Self { names: HashMap::new() };
+            for (name, ip) in names {
+                lookup.names.entry(name.to_string()).or_default().push(*ip);
+            }
+            lookup
+        }
+    }
+    #[async_trait::async_trait]
+    impl DnsLookup for StaticLookup {
+        fn domain(&self) -> String {
+            "lan".to_string()
+        }
+
+        async fn resolve(&self, name: &str) -> Option<Vec<IpAddr>> {
+            let name = name.trim_end_matches('.');
+            match (self.names.get(name), name == "lan" || name.ends_with(".lan")) {
+                (Some(ips), _) => Some(ips.clone()),
+                (None, true) => Some(vec![]),
+                (None, false) => None,
+            }
+        }
+
+        async fn reverse(&self, ip: IpAddr) -> Vec<String> {
+            self.names.iter().filter(|(_, ips)| ips.contains(&ip)).map(|(name, _)| name.clone()).collect()
+        }
+    }
+
+    /// Stand-in for the upstream resolver, answering every query with UPSTREAM_IP
+    async fn upstream_stub() -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let answer = Record::from_rdata(request.queries()[0].name().clone(), 60, RData::A(A(UPSTREAM_IP)));
+                let response = respond(&request, ResponseCode::NoError, vec![answer], None).unwrap();
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn query(lookup: &StaticLookup, name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(42).set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        let response = handle_query(&request.to_vec().unwrap(), upstream_stub().await, lookup).await
+            .unwrap().unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 42);
+        response
+    }
+
+    fn answer_data(response: &Message) -> Vec<RData> {
+        response.answers().iter().filter_map(|record| record.data().cloned()).collect()
+    }
+
+    fn has_soa(response: &Message) -> bool {
+        response.name_servers().iter().any(|record| record.record_type() == RecordType::SOA)
+    }
+
+    #[tokio::test]
+    async fn forwards_foreign_names() {
+        let lookup = StaticLookup::new(&[]);
+        let response = query(&lookup, "example.com.", RecordType::A).await;
+        assert_eq!(answer_data(&response), vec![RData::A(A(UPSTREAM_IP))]);
+    }
+
+    #[tokio::test]
+    async fn answers_local_a_and_aaaa() {
+        let v4 = Ipv4Addr::new(192, 168, 42, 10);
+        let v6: Ipv6Addr = "fd00::10".parse().unwrap();
+        let lookup = StaticLookup::new(&[("alice.lan", v4.into()), ("alice.lan", v6.into())]);
+
+        let response = query(&lookup, "alice.lan.", RecordType::A).await;
+        assert!(response.authoritative());
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_data(&response), vec![RData::A(A(v4))]);
+
+        let response = query(&lookup, "alice.lan.", RecordType::AAAA).await;
+        assert_eq!(answer_data(&response), vec![RData::AAAA(AAAA(v6))]);
+    }
+
+    #[tokio::test]
+    async fn answers_local_ptr() {
+        let lookup = StaticLookup::new(&[("alice.lan", Ipv4Addr::new(192, 168, 42, 10).into())]);
+        let response = query(&lookup, "10.42.168.192.in-addr.arpa.", RecordType::PTR).await;
+        assert!(response.authoritative());
+        assert_eq!(answer_data(&response), vec![RData::PTR(PTR(Name::from_ascii("alice.lan.").unwrap()))]);
+
+        // addresses without a local name are not ours to answer
+        let response = query(&lookup, "11.42.168.192.in-addr.arpa.", RecordType::PTR).await;
+        assert_eq!(answer_data(&response), vec![RData::A(A(UPSTREAM_IP))]);
+    }
+
+    #[tokio::test]
+    async fn unknown_local_names_are_nxdomain() {
+        let lookup = StaticLookup::new(&[]);
+        let response = query(&lookup, "nobody.lan.", RecordType::A).await;
+        assert!(response.authoritative());
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.answers().is_empty());
+        assert!(has_soa(&response));
+    }
+
+    #[tokio::test]
+    async fn apex_exists_without_addresses() {
+        let lookup = StaticLookup::new(&[]);
+        let response = query(&lookup, "lan.", RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert!(has_soa(&response));
+    }
+
+    #[tokio::test]
+    async fn missing_record_type_is_nodata() {
+        let lookup = StaticLookup::new(&[("alice.lan", Ipv4Addr::new(192, 168, 42, 10).into())]);
+        let response = query(&lookup, "alice.lan.", RecordType::AAAA).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert!(has_soa(&response));
+    }
+}
@@ -1,12 +1,24 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use anyhow::Result;
 use clap::Parser;
+use chrono::Utc;
+use tokio::{signal::unix::{signal, SignalKind}, time::{self, Instant}};
 
-use crate::state::State;
+use crate::state::{SharedState, State};
 
 mod api_services;
 mod api_server;
+mod config;
+mod dns_server;
 mod state;
+mod systemd;
+
+/// Upper bound for how long the scheduler sleeps, so it notices wall clock changes
+const SCHEDULER_MAX_SLEEP: Duration = Duration::from_secs(60);
+/// Interval in which dnsmasq's lease file is checked for changes
+const DHCP_LEASES_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Time until the rollout of a root change is retried after it failed to advance
+const ROOT_ROLLOUT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Parser)]
 #[command(name = "lapas_api_server")]
@@ -17,12 +29,87 @@ struct CliArgs {
     config_file: PathBuf,
 }
 
+/// Reload the configuration whenever the server receives a SIGHUP
+async fn reload_config_on_sighup(state: SharedState) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        println!("Received SIGHUP - reloading configuration");
+        if let Err(e) = state.reload_config().await {
+            eprintln!("Failed to reload configuration, keeping the current one:\n{}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Keep track of dnsmasq's dhcp leases
+async fn watch_dhcp_leases(state: SharedState) {
+    loop {
+        if let Err(e) = state.refresh_dhcp_leases().await {
+            eprintln!("Failed to read dhcp leases:\n{}", e);
+        }
+        time::sleep(DHCP_LEASES_POLL_INTERVAL).await;
+    }
+}
+
+/// Send the announcements of scheduled events whenever they are due
+async fn run_scheduler(state: SharedState) {
+    loop {
+        let next_due = state.announce_due_events().await.unwrap_or_else(|e| {
+            eprintln!("Failed to process schedule:\n{}", e);
+            None
+        });
+        let sleep_duration = next_due
+            .map(|next_due| (next_due - Utc::now()).to_std().unwrap_or_default())
+            .unwrap_or(SCHEDULER_MAX_SLEEP)
+            .min(SCHEDULER_MAX_SLEEP);
+        tokio::select! {
+            _ = time::sleep(sleep_duration) => {},
+            _ = state.schedule_changed() => {},
+        }
+    }
+}
+
+/// Roll the remount of a changed guest root out to the guests, wave by wave
+async fn run_root_rollout(state: SharedState) {
+    loop {
+        let next_due = state.advance_root_rollout().await.unwrap_or_else(|e| {
+            eprintln!("Failed to advance guest root rollout:\n{}", e);
+            Some(Instant::now() + ROOT_ROLLOUT_RETRY_INTERVAL)
+        });
+        let due = async {
+            match next_due {
+                Some(next_due) => time::sleep_until(next_due).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = due => {},
+            _ = state.root_rollout_changed() => {},
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let args = CliArgs::parse();
     let state = Arc::new(State::init(&args.config_file).await?);
+    dns_server::start(state.clone()).await?;
+    tokio::spawn(reload_config_on_sighup(state.clone()));
+    tokio::spawn(run_scheduler(state.clone()));
+    tokio::spawn(watch_dhcp_leases(state.clone()));
+    tokio::spawn(run_root_rollout(state.clone()));
+    systemd::spawn_watchdog();
 
-    api_server::run(state.clone()).await?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        result = api_server::run(state.clone()) => result?,
+        _ = terminate.recv() => println!("Received SIGTERM - shutting down"),
+        _ = interrupt.recv() => println!("Received SIGINT - shutting down"),
+    }
+    // listeners are closed at this point
+    systemd::notify_stopping();
+    state.shutdown().await;
 
     Ok(())
 }
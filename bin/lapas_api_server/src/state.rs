@@ -1,119 +1,573 @@
-use anyhow::{Context as _, Result, anyhow};
-use lapas_api_proto::{ApiAuth, LapasProtocol, LapasUserPasswd, LapasUserShadow};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use lapas_api_proto::{AnnouncementUrgency, ApiAuth, BootProfile, ChatMessage, ChatMessageId, DnsMapping, DnsRecord, DnsRecordTarget, EventCursor, GameServer, GameServerId, GameServerInfo, GuestAction, GuestBootProfile, GuestControlId, GuestControlTicket, GuestId, GuestInfo, GuestTarget, KnownGuest, LapasGuest, LapasProtocol, LapasUserPasswd, LapasUserShadow, MaintenanceMode, RootGeneration, RootRollout, RootStatus, ScheduledEvent, ScheduledEventId, UserHomeUsage};
 use sha2::{Digest as _, Sha512};
-use std::{collections::HashMap, net::SocketAddr, path::Path, sync::Arc};
-use tokio::{fs::File, io::{AsyncBufReadExt as _, BufReader}, sync::Mutex};
-use crate::api_services::{PeerTx, dns::DnsService, notification::NotificationService, user::UserService};
+use std::{net::IpAddr, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::Duration};
+use tokio::{sync::{Mutex, RwLock as AsyncRwLock, RwLockReadGuard}, time::{self, Instant}};
+use crate::{api_services::{PeerAddr, PeerTx, boot_profile::BootProfileService, chat::ChatService, dns::DnsService, dhcp_leases::DhcpLeaseTable, game_server::GameServerRegistry, guest::GuestRegistry, known_guest::{normalize_mac, KnownGuestRegistry}, maintenance::MaintenanceService, notification::NotificationService, root_generation::RootGenerationCounter, root_rollout::RootRolloutService, schedule::ScheduleService, session::SessionService, notification::EventRecipients, user::UserService, wake_on_lan}, config::ServerConfig};
 
 pub type SharedState = Arc<State>;
 
 pub struct State {
-    config: HashMap<String, String>,
-    user_service: Mutex<UserService>,
+    config_path: PathBuf,
+    config: RwLock<Arc<ServerConfig>>,
+    user_service: UserService,
     dns_service: Mutex<DnsService>,
     notification_service: NotificationService,
+    guest_registry: GuestRegistry,
+    known_guests: KnownGuestRegistry,
+    dhcp_leases: DhcpLeaseTable,
+    boot_profiles: BootProfileService,
+    root_generation: RootGenerationCounter,
+    root_rollout: RootRolloutService,
+    maintenance: MaintenanceService,
+    game_server_registry: GameServerRegistry,
+    session_service: SessionService,
+    schedule_service: ScheduleService,
+    chat_service: ChatService,
+    /// Held shared while a client request is processed.
+    /// Shutdown takes it exclusively, waiting for in-flight requests and blocking new ones.
+    request_lock: AsyncRwLock<()>,
 }
 impl State {
     pub async fn init(config_path: &Path) -> Result<State> {
-        let mut config = HashMap::new();
-
-        let config_file = File::open(config_path)
-            .await
-            .context("Failed to open lapas scripts configuration file.")?;
-        let mut config_file_lines = BufReader::new(config_file).lines();
-        while let Some(line) = config_file_lines.next_line().await? {
-            if line.starts_with("#") {
-                continue;
-            }
-            let line_parts: Vec<_> = line.splitn(2, "=").collect();
-            if line_parts.len() != 2 {
-                return Err(anyhow!("Invalid config file"));
-            }
-            let key = line_parts[0];
-            let mut value = line_parts[1];
-            if value.len() > 1 && value.starts_with("\"") && value.ends_with("\"") {
-                value = &value[1..value.len() - 1];
-            }
-            config.insert(key.to_owned(), value.to_owned());
-        }
-
-        let homes_dir = config
-            .get("LAPAS_USERHOMES_DIR")
-            .expect("Config File missing parameter LAPAS_USERHOMES_DIR")
-            .clone();
-        let dns_domain = config
-            .get("LAPAS_NET_DOMAIN")
-            .expect("Config File missing parameter LAPAS_NET_DOMAIN")
-            .clone();
-        let dns_hostmap_dir = config
-            .get("LAPAS_DNS_HOSTMAPPINGS_DIR")
-            .expect("Config File missing parameter LAPAS_DNS_HOSTMAPPINGS_DIR")
-            .clone();
+        let config = ServerConfig::load(config_path).await?;
 
         Ok(State {
-            config,
-            user_service: Mutex::new(UserService::new(homes_dir).await?),
-            dns_service: Mutex::new(DnsService::new(dns_domain, dns_hostmap_dir).await?),
+            config_path: config_path.to_owned(),
+            user_service: UserService::new(config.homes_dir.clone(), config.user_storage).await?,
+            dns_service: Mutex::new(DnsService::new(config.net_domain.clone(), config.net_ip, config.dns_hostmappings_dir.clone(), &config.homes_dir).await?),
             notification_service: NotificationService::new(),
+            guest_registry: GuestRegistry::new(),
+            known_guests: KnownGuestRegistry::open(&config.homes_dir).await?,
+            dhcp_leases: DhcpLeaseTable::new(),
+            boot_profiles: BootProfileService::open(&config.homes_dir, &config.tftp_dir).await?,
+            root_generation: RootGenerationCounter::open(&config.homes_dir).await?,
+            root_rollout: RootRolloutService::new(),
+            maintenance: MaintenanceService::open(&config.homes_dir).await?,
+            game_server_registry: GameServerRegistry::new(),
+            session_service: SessionService::new(),
+            schedule_service: ScheduleService::open(&config.homes_dir).await?,
+            chat_service: ChatService::new(),
+            request_lock: AsyncRwLock::new(()),
+            config: RwLock::new(Arc::new(config)),
         })
     }
 
-    fn password_salt(&self) -> &str {
-        self.config
-            .get("LAPAS_PASSWORD_SALT")
-            .expect("Config File missing parameter LAPAS_PASSWORD_SALT")
+    /// Mark the beginning of a client request, which lasts until the returned guard is dropped.
+    pub async fn begin_request(&self) -> RwLockReadGuard<'_, ()> {
+        self.request_lock.read().await
+    }
+
+    /// Prepare for the server process to exit.
+    /// Event listeners are told that the server goes away, and in-flight requests
+    /// (e.g. writes to the user storage) are given the chance to finish.
+    pub async fn shutdown(&self) {
+        self.notify(LapasProtocol::NotifyServerShutdown);
+        match time::timeout(Duration::from_secs(10), self.request_lock.write()).await {
+            Ok(request_lock) => std::mem::forget(request_lock), // never process requests again
+            Err(_) => eprintln!("Timeout while waiting for in-flight requests to finish"),
+        }
+        // give the notification forwarders a moment to deliver the shutdown notification
+        time::sleep(Duration::from_millis(250)).await;
+    }
+
+    /// Snapshot of the current configuration
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().expect("Config lock poisoned").clone()
     }
-    fn password_hash(&self) -> &str {
-        self.config
-            .get("LAPAS_PASSWORD_HASH")
-            .expect("Config File missing parameter LAPAS_PASSWORD_HASH")
+
+    /// Re-read the configuration file and apply all settings that can be changed at runtime.
+    /// The currently active configuration is kept if the new one is invalid.
+    pub async fn reload_config(&self) -> Result<()> {
+        let new_config = ServerConfig::load(&self.config_path).await?;
+        let mut config = self.config.write().expect("Config lock poisoned");
+        let mut reloaded_config = ServerConfig::clone(&config);
+        for setting in reloaded_config.reload_from(new_config) {
+            println!("Config: Changed setting {} only takes effect after a restart", setting);
+        }
+        *config = Arc::new(reloaded_config);
+        Ok(())
     }
 
-    pub fn check_auth(&self, auth: ApiAuth) -> bool {
+    pub fn check_auth(&self, auth: ApiAuth, peer: &PeerAddr) -> bool {
         match auth {
             ApiAuth::Password(api_password) => {
-                let salted_password = format!("{}{}", self.password_salt(), api_password); // prepend salt
+                let config = self.config();
+                let salted_password = format!("{}{}", config.password_salt, api_password); // prepend salt
                 let mut hasher = Sha512::new();
                 hasher.update(salted_password.as_bytes());
                 let salted_password_hash = hex::encode(hasher.finalize());
-                self.password_hash() == salted_password_hash
+                config.password_hash == salted_password_hash
             }
+            ApiAuth::PeerCredentials => peer.is_local_root(),
         }
     }
 
     pub async fn add_user(&self, username: String, password: String) -> Result<()> {
-        let user_service = self.user_service.lock().await;
-        let result = user_service.add_user(username, password).await;
-        if let Ok(_) = result {
+        let result = self.user_service.add_user(username, password).await;
+        if result.is_ok() {
             self.notify(LapasProtocol::NotifyUsersChanged {});
         }
         result
     }
 
-    pub async fn create_user_host_mapping(&self, username: String, addr: SocketAddr) -> Result<()> {
-        let dns_service = self.dns_service.lock().await;
-        let result = dns_service.create_mapping(username, addr).await;
-        if let Ok(_) = result {
+    pub async fn create_user_host_mapping(&self, username: String, addresses: Vec<IpAddr>) -> Result<()> {
+        if !self.user_service.exists(&username).await? {
+            return Err(anyhow!("User {} does not exist", username));
+        }
+        let mut dns_service = self.dns_service.lock().await;
+        let result = dns_service.create_mapping(username, addresses).await;
+        if result.is_ok() {
+            self.notify(LapasProtocol::NotifyDnsMappingsChanged {});
+        }
+        result
+    }
+
+    async fn remove_user_host_mappings(&self, usernames: &[String]) -> Result<()> {
+        let mut dns_service = self.dns_service.lock().await;
+        for username in usernames {
+            dns_service.remove_mapping(username).await?;
+        }
+        if !usernames.is_empty() {
             self.notify(LapasProtocol::NotifyDnsMappingsChanged {});
         }
+        Ok(())
+    }
+
+    pub async fn dns_mappings_all(&self) -> Result<Vec<DnsMapping>> {
+        Ok(self.dns_service.lock().await.mappings())
+    }
+
+    pub async fn add_dns_record(&self, record: DnsRecord) -> Result<DnsRecord> {
+        let users = self.user_service.passwd_all().await?;
+        if let DnsRecordTarget::User(username) = &record.target {
+            if !users.iter().any(|user| &user.name == username) {
+                return Err(anyhow!("User {} does not exist", username));
+            }
+        }
+        let mut dns_service = self.dns_service.lock().await;
+        let name = dns_service.qualify_name(&record.name);
+        if users.iter().any(|user| dns_service.user_mapping_name(&user.name) == name) {
+            return Err(anyhow!("{} is the dns mapping of a user", name));
+        }
+        let record = dns_service.add_record(record).await?;
+        self.notify(LapasProtocol::NotifyDnsMappingsChanged {});
+        Ok(record)
+    }
+
+    pub async fn remove_dns_record(&self, name: &str) -> Result<()> {
+        self.dns_service.lock().await.remove_record(name).await?;
+        self.notify(LapasProtocol::NotifyDnsMappingsChanged {});
+        Ok(())
+    }
+
+    pub async fn dns_records_all(&self) -> Result<Vec<DnsRecord>> {
+        Ok(self.dns_service.lock().await.records())
+    }
+
+    pub async fn dns_resolve(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.dns_service.lock().await.resolve(name)
+    }
+
+    pub async fn dns_reverse(&self, ip: IpAddr) -> Vec<String> {
+        self.dns_service.lock().await.reverse(ip)
+    }
+
+    pub async fn home_usage_all(&self) -> Result<Vec<UserHomeUsage>> {
+        self.user_service.home_usage_all().await
+    }
+
+    pub async fn set_home_quota(&self, username: String, quota: Option<u64>) -> Result<UserHomeUsage> {
+        // the image is resized offline, so the user must neither be logged in nor log in meanwhile
+        let guests = self.guest_registry.all().await;
+        self.session_service.lock_home(&username, &guests).await?;
+        let result = self.user_service.set_home_quota(&username, quota).await;
+        self.session_service.unlock_home(&username).await;
         result
     }
 
     pub async fn passwd_all(&self) -> Result<Vec<LapasUserPasswd>> {
-        let user_service = self.user_service.lock().await;
-        user_service.passwd_all().await
+        self.user_service.passwd_all().await
     }
 
     pub async fn shadow_all(&self) -> Result<Vec<LapasUserShadow>> {
-        let user_service = self.user_service.lock().await;
-        user_service.shadow_all().await
+        self.user_service.shadow_all().await
+    }
+
+    pub async fn register_guest(&self, ip: IpAddr, mut info: GuestInfo) -> GuestId {
+        if let Err(e) = self.known_guests.seen(&info.mac, &info.hostname, ip).await {
+            eprintln!("Failed to remember guest {}: {}", info.hostname, e);
+        }
+        // a daemon that never remounted the root runs on the root the machine booted with
+        if info.root_generation.is_none() {
+            info.root_generation = Some(self.root_generation.current().await);
+        }
+        self.guest_registry.register(ip, info).await
+    }
+
+    pub async fn unregister_guest(&self, id: GuestId) {
+        self.guest_registry.unregister(id).await;
+        self.game_server_registry.remove_guest(id).await;
+        // the leases survive a reconnect of the guest's daemon, which restores the mappings
+        let users = self.session_service.users_on(id).await;
+        if let Err(e) = self.remove_user_host_mappings(&users).await {
+            eprintln!("Failed to remove dns mappings of guest {}: {}", id, e);
+        }
+    }
+
+    pub async fn guest_ping(&self, id: GuestId) {
+        self.guest_registry.ping(id).await;
+        self.session_service.renew(id).await;
+    }
+
+    pub async fn guests_all(&self) -> Result<Vec<LapasGuest>> {
+        let mut guests = self.guest_registry.all().await;
+        // daemons that failed to determine their MAC address or hostname are identified by their dhcp lease
+        let leases = self.dhcp_leases.all().await;
+        for guest in guests.iter_mut() {
+            let Some(lease) = leases.iter().find(|lease| lease.ip == guest.ip) else {
+                continue;
+            };
+            if guest.info.mac.is_empty() {
+                guest.info.mac = lease.mac.clone();
+            }
+            if guest.info.hostname.is_empty() {
+                guest.info.hostname = lease.hostname.clone().unwrap_or_default();
+            }
+        }
+        Ok(guests)
+    }
+
+    /// Re-read dnsmasq's lease file if it changed, learn about new machines from it and
+    /// notify about machines that obtained a lease while their daemon is not connected (booting).
+    pub async fn refresh_dhcp_leases(&self) -> Result<()> {
+        let leases_path = self.config().dnsmasq_leases.clone();
+        let Some(new_leases) = self.dhcp_leases.refresh(&leases_path).await? else {
+            return Ok(());
+        };
+        self.known_guests.merge_leases(&self.dhcp_leases.all().await).await?;
+        for guest in self.known_guests_all().await? {
+            let booting = guest.guest_id.is_none() && new_leases.iter().any(|lease| lease.mac == guest.mac);
+            if booting {
+                println!("Guest {} ({}) obtained a dhcp lease and is booting", guest.hostname, guest.mac);
+                self.notify(LapasProtocol::NotifyGuestBooting { guest });
+            }
+        }
+        Ok(())
+    }
+
+    /// All known guests, with their connected daemon and dhcp lease
+    pub async fn known_guests_all(&self) -> Result<Vec<KnownGuest>> {
+        let guests = self.guest_registry.all().await;
+        let leases = self.dhcp_leases.all().await;
+        let mut known_guests = self.known_guests.all().await;
+        for known_guest in known_guests.iter_mut() {
+            known_guest.guest_id = guests.iter()
+                .find(|guest| normalize_mac(&guest.info.mac).as_ref() == Some(&known_guest.mac))
+                .map(|guest| guest.id);
+            known_guest.has_lease = leases.iter().any(|lease| lease.mac == known_guest.mac);
+        }
+        Ok(known_guests)
+    }
+
+    /// Send Wake-on-LAN magic packets to the known guests with the given hostnames or MAC addresses, or to all of them
+    pub async fn wake_guests(&self, hosts: Option<Vec<String>>) -> Result<Vec<KnownGuest>> {
+        let guests = match hosts {
+            None => self.known_guests.all().await,
+            Some(hosts) => {
+                let mut guests = vec![];
+                for host in hosts {
+                    let guest = self.known_guests.find(&host).await
+                        .ok_or_else(|| anyhow!("Unknown guest: {}", host))?;
+                    guests.push(guest);
+                }
+                guests
+            },
+        };
+        if guests.is_empty() {
+            return Err(anyhow!("No known guests to wake"));
+        }
+        let mut errors = vec![];
+        for guest in &guests {
+            if let Err(e) = wake_on_lan::wake(&guest.mac).await {
+                errors.push(e.to_string());
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(guests),
+            false => Err(anyhow!(errors.join("\n"))),
+        }
+    }
+
+    /// Set what the machine with the given hostname or MAC address boots into.
+    /// Unlike waking guests, this also works for machines that were never seen in the lapas network.
+    pub async fn set_boot_profile(&self, host: &str, profile: BootProfile) -> Result<GuestBootProfile> {
+        let known_guest = self.known_guests.find(host).await;
+        let mac = match (&known_guest, normalize_mac(host)) {
+            (Some(known_guest), _) => known_guest.mac.clone(),
+            (None, Some(mac)) => mac,
+            (None, None) => return Err(anyhow!("Unknown guest: {}", host)),
+        };
+        self.boot_profiles.set(&mac, profile).await?;
+        Ok(GuestBootProfile { mac, hostname: known_guest.map(|guest| guest.hostname), profile })
+    }
+
+    pub async fn boot_profiles_all(&self) -> Result<Vec<GuestBootProfile>> {
+        let mut boot_profiles = vec![];
+        for (mac, profile) in self.boot_profiles.all().await {
+            let hostname = self.known_guests.find(&mac).await.map(|guest| guest.hostname);
+            boot_profiles.push(GuestBootProfile { mac, hostname, profile });
+        }
+        Ok(boot_profiles)
+    }
+
+    /// Ids of the connected guests the given target refers to
+    async fn resolve_guest_target(&self, target: GuestTarget) -> Result<Vec<GuestId>> {
+        let guests = match target {
+            GuestTarget::All => self.guest_registry.ids().await,
+            GuestTarget::Guests(guests) => {
+                self.guest_registry.check_connected(&guests).await?;
+                guests
+            }
+            GuestTarget::User(username) => {
+                let guest_id = self.session_service.guest_of(&username).await
+                    .ok_or_else(|| anyhow!("User {} is not logged in", username))?;
+                vec![guest_id]
+            }
+        };
+        if guests.is_empty() {
+            return Err(anyhow!("No guests targeted"));
+        }
+        Ok(guests)
+    }
+
+    pub async fn control_guests(&self, target: GuestTarget, action: GuestAction, countdown_secs: u32) -> Result<GuestControlTicket> {
+        let guests = self.resolve_guest_target(target).await?;
+        let ticket = self.guest_registry.new_control_ticket(guests.clone()).await;
+        self.notify_guests(guests, LapasProtocol::NotifyGuestControl { ticket: ticket.clone(), action, countdown_secs }).await;
+        Ok(ticket)
+    }
+
+    pub fn guest_control_result(&self, guest_id: GuestId, control_id: GuestControlId, result: Result<(), String>) {
+        self.notify(LapasProtocol::NotifyGuestControlResult { control_id, guest_id, result });
+    }
+
+    /// Turn the maintenance mode on or off (message = None).
+    /// When turning it on, players that are already logged in can be warned and logged out after a countdown.
+    pub async fn set_maintenance(&self, message: Option<String>, logout_countdown_secs: Option<u32>) -> Result<Option<MaintenanceMode>> {
+        let maintenance = self.maintenance.set(message).await?;
+        self.notify(LapasProtocol::NotifyMaintenance { maintenance: maintenance.clone() });
+        if let (Some(maintenance), Some(countdown_secs)) = (&maintenance, logout_countdown_secs) {
+            if !maintenance.message.is_empty() {
+                self.announce(GuestTarget::All, "Maintenance".to_owned(), maintenance.message.clone(), AnnouncementUrgency::Critical).await?;
+            }
+            // only guests with logged in players act on the logout
+            if !self.guest_registry.ids().await.is_empty() {
+                self.control_guests(GuestTarget::All, GuestAction::Logout, countdown_secs).await?;
+            }
+        }
+        Ok(maintenance)
+    }
+
+    pub async fn maintenance(&self) -> Result<Option<MaintenanceMode>> {
+        Ok(self.maintenance.get().await)
+    }
+
+    /// Start a new generation of the guest root and roll its remount out to the guests
+    pub async fn root_changed(&self) -> Result<RootGeneration> {
+        let generation = self.root_generation.bump().await?;
+        self.root_rollout.start(generation).await;
+        Ok(generation)
+    }
+
+    pub async fn root_status(&self) -> Result<RootStatus> {
+        let generation = self.root_generation.current().await;
+        let stragglers = self.guests_all().await?.into_iter()
+            .filter(|guest| guest.info.root_generation != Some(generation))
+            .collect();
+        Ok(RootStatus { generation, stragglers, rollout: self.root_rollout.status().await })
+    }
+
+    pub async fn root_rollout_resume(&self) -> Result<RootRollout> {
+        let rollout = self.root_rollout.resume().await?;
+        self.notify(LapasProtocol::NotifyRootRollout { rollout: rollout.clone() });
+        Ok(rollout)
+    }
+
+    pub async fn guest_root_remount_result(&self, guest_id: GuestId, generation: RootGeneration, result: Result<(), String>) {
+        if result.is_ok() {
+            self.guest_registry.set_root_generation(guest_id, generation).await;
+        }
+        let wave_interval = Duration::from_secs(self.config().remount_wave_interval_secs);
+        if let Some(rollout) = self.root_rollout.remount_result(guest_id, generation, result, wave_interval).await {
+            self.notify(LapasProtocol::NotifyRootRollout { rollout });
+        }
+    }
+
+    /// Take the next step of the rollout of a root change, asking the next wave of guests to remount.
+    /// Returns the point in time at which this has to be called again.
+    pub async fn advance_root_rollout(&self) -> Result<Option<Instant>> {
+        let guests = self.guests_all().await?;
+        let Some(step) = self.root_rollout.advance(&guests, &self.config()).await else {
+            return Ok(None);
+        };
+        if !step.wave.is_empty() {
+            println!("Asking guests {:?} to remount root generation {}", step.wave, step.generation);
+            self.notify_guests(step.wave, LapasProtocol::NotifyRootChanged { generation: step.generation }).await;
+        }
+        if let Some(rollout) = step.progress {
+            self.notify(LapasProtocol::NotifyRootRollout { rollout });
+        }
+        Ok(step.next_due)
+    }
+
+    /// Wait until the rollout of a root change needs attention
+    pub async fn root_rollout_changed(&self) {
+        self.root_rollout.changed().await
+    }
+
+    pub async fn announce(&self, target: GuestTarget, title: String, body: String, urgency: AnnouncementUrgency) -> Result<()> {
+        if title.is_empty() {
+            return Err(anyhow!("Announcement title must not be empty!"));
+        }
+        let announcement = LapasProtocol::NotifyAnnouncement { title, body, urgency };
+        match target {
+            GuestTarget::All => self.notify(announcement),
+            target => self.notify_guests(self.resolve_guest_target(target).await?, announcement).await,
+        }
+        Ok(())
+    }
+
+    pub async fn schedule_add(&self, title: String, description: String, ts: DateTime<Utc>, reminder_offsets_mins: Vec<u32>) -> Result<ScheduledEventId> {
+        self.schedule_service.add(title, description, ts, reminder_offsets_mins).await
     }
 
-    pub async fn register_event_listener(&self, tx: PeerTx) {
-        self.notification_service.add(tx).await
+    pub async fn schedule_remove(&self, id: ScheduledEventId) -> Result<()> {
+        self.schedule_service.remove(id).await
+    }
+
+    pub async fn schedule_all(&self) -> Result<Vec<ScheduledEvent>> {
+        Ok(self.schedule_service.all().await)
+    }
+
+    /// Send the announcements of scheduled events that are due.
+    /// Returns the point in time at which this has to be called again.
+    pub async fn announce_due_events(&self) -> Result<Option<DateTime<Utc>>> {
+        let (announcements, next_due) = self.schedule_service.take_due().await?;
+        for announcement in announcements {
+            self.notify(LapasProtocol::NotifyAnnouncement {
+                title: announcement.title,
+                body: announcement.body,
+                urgency: announcement.urgency,
+            });
+        }
+        Ok(next_due)
+    }
+
+    /// Wait until the schedule was changed
+    pub async fn schedule_changed(&self) {
+        self.schedule_service.changed().await
+    }
+
+    pub async fn begin_session(&self, username: String, guest_id: GuestId, resume: bool) -> Result<()> {
+        let guest = self.guest_registry.get(guest_id).await
+            .ok_or_else(|| anyhow!("Guest is not registered"))?;
+        // further sessions of a user that is already logged in on the guest are no new logins
+        let logged_in = self.session_service.guest_of(&username).await == Some(guest_id);
+        if let Some(maintenance) = self.maintenance.get().await.filter(|_| !resume && !logged_in) {
+            return Err(match maintenance.message.is_empty() {
+                true => anyhow!("Logins are disabled for maintenance"),
+                false => anyhow!("Logins are disabled for maintenance: {}", maintenance.message),
+            });
+        }
+        let addresses = guest.addresses();
+        self.session_service.begin(username.clone(), guest).await?;
+        let result = self.create_user_host_mapping(username.clone(), addresses).await;
+        // the login is refused, so a lease acquired for it must not stay behind
+        if result.is_err() && !logged_in {
+            let _ = self.session_service.end(&username, guest_id).await;
+        }
+        result
+    }
+
+    pub async fn end_session(&self, username: &str, guest_id: GuestId) -> Result<()> {
+        self.session_service.end(username, guest_id).await?;
+        self.remove_user_host_mappings(&[username.to_owned()]).await
+    }
+
+    pub async fn chat_send(&self, from: String, to: Option<String>, text: String) -> Result<ChatMessageId> {
+        if let Some(to) = &to {
+            let is_user = self.user_service.passwd_all().await?.iter().any(|user| &user.name == to);
+            if !is_user {
+                return Err(anyhow!("User {} does not exist", to));
+            }
+        }
+        let message = self.chat_service.send(from, to, text).await?;
+        let id = message.id;
+        match &message.to {
+            None => self.notify(LapasProtocol::NotifyChatMessage { message }),
+            Some(to) => {
+                // direct messages only go to the guests of the sender and the receiver.
+                // Receivers that are not logged in find them in the history later on.
+                let mut guests = vec![];
+                for username in [&message.from, to] {
+                    if let Some(guest_id) = self.session_service.guest_of(username).await {
+                        guests.push(guest_id);
+                    }
+                }
+                guests.dedup();
+                if !guests.is_empty() {
+                    self.notify_guests(guests, LapasProtocol::NotifyChatMessage { message }).await;
+                }
+            }
+        }
+        Ok(id)
+    }
+
+    pub async fn chat_history(&self, username: &str) -> Result<Vec<ChatMessage>> {
+        Ok(self.chat_service.history(username).await)
+    }
+
+    pub async fn register_game_server(&self, guest_id: GuestId, owner: Option<String>, info: GameServerInfo) -> Result<GameServerId> {
+        let guest = self.guest_registry.get(guest_id).await
+            .ok_or_else(|| anyhow!("Guest is not registered"))?;
+        self.game_server_registry.register(guest, owner, info).await
+    }
+
+    pub async fn game_server_heartbeat(&self, guest_id: GuestId, id: GameServerId, player_count: u32) -> Result<()> {
+        self.game_server_registry.heartbeat(guest_id, id, player_count).await
+    }
+
+    pub async fn unregister_game_server(&self, guest_id: GuestId, id: GameServerId) -> Result<()> {
+        self.game_server_registry.unregister(guest_id, id).await
+    }
+
+    pub async fn game_servers_all(&self) -> Result<Vec<GameServer>> {
+        Ok(self.game_server_registry.all().await)
+    }
+
+    pub async fn register_event_listener(&self, tx: PeerTx, guest_id: Option<GuestId>, resume_from: Option<EventCursor>) {
+        let boot_id = match guest_id {
+            Some(guest_id) => self.guest_registry.get(guest_id).await.map(|guest| guest.info.boot_id),
+            None => None,
+        };
+        self.notification_service.add(tx, boot_id, resume_from).await
     }
 
     pub fn notify(&self, notification: LapasProtocol) {
         self.notification_service.send(notification);
     }
+
+    /// Send a notification to the given guests only
+    pub async fn notify_guests(&self, guests: Vec<GuestId>, notification: LapasProtocol) {
+        let mut boot_ids = vec![];
+        for guest_id in guests {
+            match self.guest_registry.get(guest_id).await {
+                Some(guest) if !guest.info.boot_id.is_empty() => boot_ids.push(guest.info.boot_id),
+                _ => eprintln!("Guest {} can not receive notifications, it is not connected or sent no boot id", guest_id),
+            }
+        }
+        self.notification_service.send_to(EventRecipients::Guests(boot_ids), notification);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,69 @@
+use std::{
+    os::{fd::{FromRawFd as _, IntoRawFd as _}, unix::net::UnixListener as StdUnixListener},
+    net::TcpListener as StdTcpListener,
+};
+
+use anyhow::Result;
+use sd_notify::NotifyState;
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::config::ListenAddr;
+
+/// Listening socket passed to us by systemd (socket activation)
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+impl ActivatedListener {
+    /// Whether this socket is listening on the given configured address
+    pub fn is_listening_on(&self, listen_addr: &ListenAddr) -> bool {
+        match (self, listen_addr) {
+            // the interface a passed socket is bound to (BindToDevice=) is up to the socket unit
+            (ActivatedListener::Tcp(listener), ListenAddr::Tcp(addr, _)) =>
+                listener.local_addr().is_ok_and(|local_addr| local_addr == *addr),
+            (ActivatedListener::Unix(listener), ListenAddr::Unix(path)) =>
+                listener.local_addr().is_ok_and(|local_addr| local_addr.as_pathname() == Some(path)),
+            _ => false,
+        }
+    }
+}
+
+/// Take over all listening sockets that systemd passed to us, if we were socket-activated.
+pub fn activated_listeners() -> Result<Vec<ActivatedListener>> {
+    let mut listeners = vec![];
+    for fd in sd_notify::listen_fds()? {
+        // systemd hands us ownership of the passed file descriptors
+        let tcp_listener = unsafe { StdTcpListener::from_raw_fd(fd) };
+        if tcp_listener.local_addr().is_ok() {
+            tcp_listener.set_nonblocking(true)?;
+            listeners.push(ActivatedListener::Tcp(TcpListener::from_std(tcp_listener)?));
+        } else {
+            let unix_listener = unsafe { StdUnixListener::from_raw_fd(tcp_listener.into_raw_fd()) };
+            unix_listener.set_nonblocking(true)?;
+            listeners.push(ActivatedListener::Unix(UnixListener::from_std(unix_listener)?));
+        }
+    }
+    Ok(listeners)
+}
+
+pub fn notify_ready() {
+    let _ = sd_notify::notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(&[NotifyState::Stopping]);
+}
+
+/// Keep systemd's watchdog happy for as long as the runtime is responsive, if it is enabled.
+pub fn spawn_watchdog() {
+    let Some(watchdog_timeout) = sd_notify::watchdog_enabled() else {
+        return;
+    };
+    println!("Systemd watchdog enabled [timeout: {}ms]", watchdog_timeout.as_millis());
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(watchdog_timeout / 2).await;
+            let _ = sd_notify::notify(&[NotifyState::Watchdog]);
+        }
+    });
+}
@@ -19,6 +19,8 @@ cargo build --target x86_64-unknown-linux-musl --release || exit $?;
 popd;
 cp bin/lapas_api_client/target/x86_64-unknown-linux-musl/release/lapas-api-client res/lapas/guest/lapas/lapas-api-client || exit $?;
 chmod a+x res/lapas/guest/lapas/lapas-api-client;
+cp bin/lapas_api_client/target/x86_64-unknown-linux-musl/release/lapas-api-client res/lapas/scripts/lapas-api-client || exit $?;
+chmod +x res/lapas/scripts/lapas-api-client;
 
 
 pushd bin/lapas_nss;
@@ -8,6 +8,8 @@ USER_IMAGE_SIZE="16G";
 USER_IMAGE_BASE="/mnt/homes";
 USER_MOUNT_BASE="/mnt/.mounts";
 USER_BASE_DIR="/mnt/homeBase";
+LAPAS_DAEMON_SOCKET="/run/lapas/auth_serv.socket";
+KEEP_APPLIED_MARKER_DIR="/run/lapas-keep-applied"; # users whose home was cleaned up on this boot
 
 # import lapas config
 . "/lapas/common.sh" || exit 1;
@@ -30,14 +32,20 @@ echo "[LOGON] Login user: ${PAM_USER}, home: ${LOGINUSER_HOME}";
 
 if [ "$PAM_USER" != "$BASEUSER_NAME" ] && [ "$PAM_TYPE" == "open_session" ]; then
 	echo "[LOGON] Detected normal user";
-	API_PASSWORD="${API_PASSWORD}" /lapas/lapas-api-client add-dns-mapping "${PAM_USER}";
+	# acquire the exclusive lease on the user's home, so it is never mounted on two machines at once.
+	# This also maps the user's dns name to this machine for the duration of the session.
+	if ! SESSION_RESULT=$(/lapas/lapas-api-client --socket "${LAPAS_DAEMON_SOCKET}" session-begin "${PAM_USER}" 2>&1); then
+		echo "[LOGON] Login refused: ${SESSION_RESULT}";
+		exit 1;
+	fi
 
 	USER_MOUNT_DIR="${USER_MOUNT_BASE}/${PAM_USER}";
 	USER_PERSISTENT_MOUNT_DIR="${USER_MOUNT_DIR}/overlay"; # contains mounted user ext4 image
 	USER_BASE_MOUNT_DIR="${USER_MOUNT_DIR}/base"; # contains a bindmount pointing to /mnt/homeBase but idmapped to user
 	USER_PERSISTENT_IMAGE="${USER_IMAGE_BASE}/${PAM_USER}";
 
-	# create and format user persistence (ext4) image if it doesn't exist yet
+	# create and format user persistence (ext4) image if it doesn't exist yet.
+	# (images of users with a home quota are created and resized by the api server instead)
 	if [ ! -f "$USER_PERSISTENT_IMAGE" ]; then
 		# create image for user-specific dynamic data
 		assertSuccessfull truncate -s $USER_IMAGE_SIZE "$USER_PERSISTENT_IMAGE";
@@ -60,17 +68,18 @@ if [ "$PAM_USER" != "$BASEUSER_NAME" ] && [ "$PAM_TYPE" == "open_session" ]; the
 		assertSuccessfull mkdir -p "$USER_PERSISTENT_MOUNT_DIR/upper";
 		assertSuccessfull mkdir -p "$USER_PERSISTENT_MOUNT_DIR/work";
 		assertSuccessfull chown -R $PAM_USER:lanparty "$USER_PERSISTENT_MOUNT_DIR";
-		# remove cleanup marker from user's upper dir on every mount (once per boot)
-		if [ -f "${USER_PERSISTENT_MOUNT_DIR}/upper/.keepApplied" ]; then
-			rm "${USER_PERSISTENT_MOUNT_DIR}/upper/.keepApplied";
-		fi
+		# marker of previous versions, which kept the home mounted until the next reboot
+		rm -f "${USER_PERSISTENT_MOUNT_DIR}/upper/.keepApplied";
 	fi
-	if [ ! -f "${USER_PERSISTENT_MOUNT_DIR}/upper/.keepApplied" ]; then
+	# the home is unmounted when the user logs out, so the marker lives in /run, which does not
+	# survive a reboot. This keeps the cleanup at once per boot, instead of once per login.
+	KEEP_APPLIED_MARKER="${KEEP_APPLIED_MARKER_DIR}/${PAM_USER}";
+	if [ ! -f "$KEEP_APPLIED_MARKER" ]; then
 		# Cleanup has not yet run on this boot, run it now
 		assertSuccessfull "/lapas/keepEngine" user "${USER_BASE_MOUNT_DIR}/.keep" "${USER_PERSISTENT_MOUNT_DIR}/upper";
-		# if cleanup ran successfully, create a temporary marker file .keepApplied that will be
-		# deleted upon next home mount (after a reboot).
-		touch "${USER_PERSISTENT_MOUNT_DIR}/upper/.keepApplied";
+		# if cleanup ran successfully, create the marker
+		assertSuccessfull mkdir -p "$KEEP_APPLIED_MARKER_DIR";
+		touch "$KEEP_APPLIED_MARKER";
 	fi
 	if [ $(mount | grep "$LOGINUSER_HOME" | wc -l) == 0 ]; then
 		assertSuccessfull mkdir -p "$LOGINUSER_HOME";
@@ -78,3 +87,9 @@ if [ "$PAM_USER" != "$BASEUSER_NAME" ] && [ "$PAM_TYPE" == "open_session" ]; the
 		assertSuccessfull mount -t overlay overlay -o lowerdir="${USER_BASE_MOUNT_DIR}",upperdir="${USER_PERSISTENT_MOUNT_DIR}/upper",workdir="${USER_PERSISTENT_MOUNT_DIR}/work" "$LOGINUSER_HOME";
 	fi
 fi
+
+if [ "$PAM_USER" != "$BASEUSER_NAME" ] && [ "$PAM_TYPE" == "close_session" ]; then
+	echo "[LOGOFF] Logout user: ${PAM_USER}";
+	# after the user's last session, the daemon unmounts the home (unmountHome.sh) before releasing its lease
+	/lapas/lapas-api-client --socket "${LAPAS_DAEMON_SOCKET}" session-end "${PAM_USER}";
+fi
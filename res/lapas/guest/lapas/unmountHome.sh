@@ -0,0 +1,22 @@
+#!/bin/bash
+
+# Unmounts the home of the given user, after their last session on this machine ended.
+# Called by the lapas api daemon, which only releases the user's exclusive home lease once
+# this succeeded, so the home image is never mounted on two machines at once.
+
+# Constants (see mountHome.sh)
+USER_MOUNT_BASE="/mnt/.mounts";
+
+USERNAME="$1";
+[ -z "$USERNAME" ] && exit 1;
+
+LOGINUSER_HOME=$(getent passwd "$USERNAME" | cut -d: -f6);
+USER_MOUNT_DIR="${USER_MOUNT_BASE}/${USERNAME}";
+
+echo "[LOGOFF] Unmounting home of: ${USERNAME}";
+# the overlay has to go first, it is stacked onto the image and the idmapped homeBase
+for MOUNTPOINT in "$LOGINUSER_HOME" "${USER_MOUNT_DIR}/overlay" "${USER_MOUNT_DIR}/base"; do
+	if [ -n "$MOUNTPOINT" ] && mountpoint -q "$MOUNTPOINT"; then
+		umount "$MOUNTPOINT" || exit $?;
+	fi
+done
@@ -0,0 +1,16 @@
+#!/bin/bash
+[ "$USER" != "root" ] && echo "ERROR: Must be root. Use 'su - root'." && exit 1
+[ -z "$BASH_VERSION" ] && echo "ERROR: Must run with bash, not sh/dash." && exit 1
+
+. "$(dirname "$0")/config" || { echo "ERROR: Failed to load config."; exit 1; }
+
+NEW_USERNAME="$1";
+[ -z "${NEW_USERNAME}" ] && echo "Usage: $0 <newUser>" && exit 1
+
+read -r -s -p "Password for ${NEW_USERNAME}: " NEW_PASSWORD; echo;
+read -r -s -p "Repeat password: " NEW_PASSWORD_REPEAT; echo;
+[ "${NEW_PASSWORD}" != "${NEW_PASSWORD_REPEAT}" ] && echo "ERROR: Passwords do not match." && exit 1
+
+# root is authenticated through the api server's local unix socket, no admin password required
+"${LAPAS_SCRIPTS_DIR}/lapas-api-client" --socket "${LAPAS_API_SOCKET}" add-user "${NEW_USERNAME}" "${NEW_PASSWORD}" \
+	|| { echo "ERROR: Failed to add user."; exit 1; }
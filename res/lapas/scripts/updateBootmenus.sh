@@ -16,6 +16,7 @@ fi
 ISCSI_NETROOT="netroot=iscsi:${LAPAS_NET_IP}::::${LAPAS_GUESTIMG_IQN}";
 GUEST_USER_OPTIONS="ip=dhcp root=UUID=${LAPAS_GUESTIMG_FSUUID} ro ${ISCSI_NETROOT} rd.retry=45 rd.timeout=45 rd.live.overlay.overlayfs=1";
 GUEST_ADMIN_OPTIONS="ip=dhcp root=UUID=${LAPAS_GUESTIMG_FSUUID} rw ${ISCSI_NETROOT} rd.retry=45 rd.timeout=45";
+GUEST_MAINTENANCE_OPTIONS="${GUEST_ADMIN_OPTIONS} systemd.unit=rescue.target";
 
 ################################################################################################
 # GRUB.CFG PREAMBLE
@@ -64,16 +65,19 @@ echo -e "\n### END /etc/grub.d/00_header ###\n\n" >> "${LAPAS_TFTP_DIR}/grub2/gr
 ################################################################################################
 
 # Call this method for every kernel to add it to the newly generated boot menu
-# Usage: addKernelToBootMenu <absolutePathToGuestKernel>
+# The entries of the newest kernel get the ids lapas-user, lapas-admin and lapas-maintenance,
+# which the per-MAC boot profiles of the lapas api server (grub2/grub.cfg-01-<mac>) boot right away.
+# Usage: addKernelToBootMenu <kernelVersion> <menuentryIdPrefix>
 function addKernelToBootMenu() {
 	kernelVersion="$1";
+	idPrefix="$2";
 	kernelBinPath="${LAPAS_TFTP_DIR}/boot/vmlinuz-${kernelVersion}";
 	kernelRamdiskPath="${LAPAS_TFTP_DIR}/boot/initrd-${kernelVersion}";
-	if [ ! -f "${kernelBinPath}" ]; then return 0; fi
+	if [ ! -f "${kernelBinPath}" ]; then return 1; fi
 	echo "Adding ${kernelVersion} to bootmenus...";
 	cat <<EOF >> "${LAPAS_TFTP_DIR}/grub2/grub.cfg"
 #############################################################################
-menuentry 'User-${kernelVersion}' {
+menuentry 'User-${kernelVersion}' --id ${idPrefix}-user {
 	insmod all_video
 	set gfxpayload=keep
 
@@ -83,13 +87,20 @@ menuentry 'User-${kernelVersion}' {
 	initrd /boot/initrd-${kernelVersion}
 	echo "Starting ..."
 }
-menuentry 'Admin-${kernelVersion}' {
+menuentry 'Admin-${kernelVersion}' --id ${idPrefix}-admin {
 	echo "Loading Kernel ${kernelVersion} [ADMIN] ..."
 	linux /boot/vmlinuz-${kernelVersion} ${GUEST_ADMIN_OPTIONS} init=/lib/systemd/systemd
 	echo "Loading Ramdisk ${kernelVersion} ..."
 	initrd /boot/initrd-${kernelVersion}
 	echo "Starting ..."
 }
+menuentry 'Maintenance-${kernelVersion}' --id ${idPrefix}-maintenance {
+	echo "Loading Kernel ${kernelVersion} [MAINTENANCE] ..."
+	linux /boot/vmlinuz-${kernelVersion} ${GUEST_MAINTENANCE_OPTIONS} init=/lib/systemd/systemd
+	echo "Loading Ramdisk ${kernelVersion} ..."
+	initrd /boot/initrd-${kernelVersion}
+	echo "Starting ..."
+}
 
 EOF
 }
@@ -102,11 +113,16 @@ cp "${LAPAS_GUESTROOT_DIR}/usr/share/efi/x86_64/shim.efi" "${LAPAS_TFTP_DIR}/shi
 cp "${LAPAS_GUESTROOT_DIR}/usr/share/efi/x86_64/grub.efi" "${LAPAS_TFTP_DIR}/grub.efi";
 cp "${LAPAS_GUESTROOT_DIR}/usr/share/efi/x86_64/MokManager.efi" "${LAPAS_TFTP_DIR}/MokManager.efi";
 
+newestKernel="yes";
 while read -r kernelConfig; do
 	kernelConfigFilename=$(basename "$kernelConfig");
 	kernelVersion="${kernelConfigFilename#config-*}";
 	echo "Found kernel: ${kernelVersion}";
-	addKernelToBootMenu "${kernelVersion}";
+	if [ "$newestKernel" == "yes" ]; then
+		addKernelToBootMenu "${kernelVersion}" "lapas" && newestKernel="no";
+	else
+		addKernelToBootMenu "${kernelVersion}" "lapas-${kernelVersion}";
+	fi
 done <<< $(find "${LAPAS_TFTP_DIR}/boot" -name "config-*" | sort --version-sort -r);
 
 chmod a+r -R "${LAPAS_TFTP_DIR}";
@@ -14,3 +14,7 @@ PROMPT_COMMAND='PS1="\[\e[1;37m\](WithGuestFs)\[\e[0m\] \u@\h:\w\$ "' bash
 umount "${LAPAS_GUESTROOT_DIR}/boot" || { echo "ERROR: Failed to unmount boot."; exit 1; }
 umount "${LAPAS_GUESTROOT_DIR}" || { echo "ERROR: Failed to unmount guest root."; exit 1; }
 rmdir "${LAPAS_GUESTROOT_DIR}" || { echo "ERROR: Failed to remove guest root dir."; exit 1; }
+
+echo "Asking guests to remount their root..."
+"${LAPAS_SCRIPTS_DIR}/lapas-api-client" --socket "${LAPAS_API_SOCKET}" root-changed \
+	|| echo "ERROR: Failed to announce the guest root change, run 'lapas-api-client root-changed' manually."
@@ -74,7 +74,8 @@ LAPAS_SCRIPTS_DIR="${LAPAS_BASE_DIR}/scripts";
 LAPAS_TFTP_DIR="${LAPAS_BASE_DIR}/tftp";
 LAPAS_GUESTROOT_DIR="${LAPAS_BASE_DIR}/guest";
 LAPAS_USERHOMES_DIR="${LAPAS_BASE_DIR}/homes";
-LAPAS_DNS_HOSTMAPPINGS_DIR="/tmp/lapas_dns_hostmappings";
+LAPAS_DNS_HOSTMAPPINGS_DIR="${LAPAS_BASE_DIR}/dns_hostmappings";
+LAPAS_API_SOCKET="/run/lapas/api-server.socket";
 LAPAS_TIMEZONE=$(getSystemTimezone);
 LAPAS_KEYMAP=$(getSystemKeymap);
 LAPAS_PASSWORD_SALT="lApAsPaSsWoRdSaLt_";
@@ -155,6 +156,8 @@ LAPAS_CONFIGURATION_OPTIONS=(
 	"LAPAS_NFS_VERSION=${LAPAS_NFS_VERSION}"
 	"LAPAS_NFS_USER_MOUNTOPTIONS=${LAPAS_NFS_USER_MOUNTOPTIONS}"
 	"LAPAS_DNS_HOSTMAPPINGS_DIR=${LAPAS_DNS_HOSTMAPPINGS_DIR}"
+	"LAPAS_API_SOCKET=${LAPAS_API_SOCKET}"
+	"LAPAS_API_LISTEN=[::]:1337%lapas unix:${LAPAS_API_SOCKET}"
 	"LAPAS_GUESTIMG_RO_MOUNTPOINT"="${LAPAS_GUESTIMG_RO_MOUNTPOINT}"
 	"LAPAS_GUESTIMG_PATH"="${LAPAS_GUESTIMG_PATH}"
 	"LAPAS_GUESTIMG_SIZE"="${LAPAS_GUESTIMG_SIZE}"
@@ -232,6 +235,7 @@ pushd "/";
 	streamBinaryPayload "${SELF_PATH}" "__PAYLOAD_SERVER_RESOURCES__" | base64 -d | gzip -d | tar -x --no-same-owner || exit 1;
 popd;
 runSilentUnfallible configureFileInplace /etc/systemd/system/lapas-api-server.service "${LAPAS_CONFIGURATION_OPTIONS[@]}";
+runSilentUnfallible configureFileInplace /etc/systemd/system/lapas-api-server.socket "${LAPAS_CONFIGURATION_OPTIONS[@]}";
 runSilentUnfallible configureFileInplace /etc/dnsmasq.conf "${LAPAS_CONFIGURATION_OPTIONS[@]}";
 runSilentUnfallible configureFileInplace /etc/exports "${LAPAS_CONFIGURATION_OPTIONS[@]}";
 runSilentUnfallible configureFileInplace /etc/systemd/network/20-upstream.network "${LAPAS_CONFIGURATION_OPTIONS[@]}";